@@ -0,0 +1,92 @@
+//! Admin commands against a running server, authenticated with its admin secret.
+
+use anyhow::{bail, Context, Result};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::auth::Authenticator;
+use crate::client::recv_version;
+use crate::shared::{
+    ClientError, ClientMessage, Delimited, ServerMessage, TunnelInfo, NETWORK_TIMEOUT,
+};
+
+/// Longest reply accepted from the server, which may list many tunnels.
+const MAX_REPLY_LENGTH: usize = 1 << 20;
+
+/// Earliest protocol version with admin commands.
+const ADMIN_VERSION: u32 = 4;
+
+/// List the tunnels open on a server, sorted by port.
+///
+/// `secret` is the client secret that the server requires of every connection, if any,
+/// and `admin_secret` is the one it was given for admins.
+pub async fn list_tunnels(
+    to: &str,
+    control_port: u16,
+    secret: Option<&str>,
+    admin_secret: &str,
+) -> Result<Vec<TunnelInfo>> {
+    let command = ClientMessage::AdminList;
+    match run(to, control_port, secret, admin_secret, command).await? {
+        ServerMessage::TunnelList(tunnels) => Ok(tunnels),
+        _ => bail!("unexpected reply to admin command"),
+    }
+}
+
+/// Close the tunnels on a public port of a server, returning whether there were any.
+///
+/// Their clients are told that the tunnels closed, and stay connected without them, so
+/// they do not reopen them until restarted. The secrets are the same as for
+/// [`list_tunnels`].
+pub async fn kill_tunnel(
+    to: &str,
+    control_port: u16,
+    secret: Option<&str>,
+    admin_secret: &str,
+    port: u16,
+) -> Result<bool> {
+    let command = ClientMessage::AdminKill(port);
+    match run(to, control_port, secret, admin_secret, command).await? {
+        ServerMessage::Killed { closed, .. } => Ok(closed),
+        _ => bail!("unexpected reply to admin command"),
+    }
+}
+
+/// Send an admin command to a server, returning its reply.
+async fn run(
+    to: &str,
+    control_port: u16,
+    secret: Option<&str>,
+    admin_secret: &str,
+    command: ClientMessage,
+) -> Result<ServerMessage> {
+    let stream = match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, control_port))).await {
+        Ok(res) => res,
+        Err(err) => Err(err.into()),
+    }
+    .map_err(ClientError::Connect)
+    .with_context(|| format!("could not connect to {to}:{control_port}"))?;
+    let mut stream = Delimited::with_max_length(stream, MAX_REPLY_LENGTH);
+    if let Some(secret) = secret {
+        Authenticator::new(secret)
+            .client_handshake(&mut stream)
+            .await?;
+    }
+
+    stream.send(ClientMessage::Version(ADMIN_VERSION)).await?;
+    match recv_version(&mut stream).await? {
+        Some(version) if version >= ADMIN_VERSION => {}
+        _ => bail!("server does not support admin commands"),
+    }
+    stream.send(command).await?;
+    Authenticator::new(admin_secret)
+        .client_handshake(&mut stream)
+        .await?;
+    match stream.recv_timeout().await? {
+        Some(ServerMessage::Error(message)) => {
+            Err(ClientError::from_server(message)).context("admin command refused")
+        }
+        Some(reply) => Ok(reply),
+        None => bail!("server closed the connection"),
+    }
+}
@@ -1,26 +1,51 @@
 //! Auth implementation for bore client and server.
 
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, Context, Result};
 use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
 use uuid::Uuid;
 
-use crate::shared::{ClientMessage, Delimited, ServerMessage};
+use crate::shared::{ClientError, ClientMessage, Delimited, ServerMessage, NETWORK_TIMEOUT};
+pub use crate::shared::{INVALID_SECRET, SECRET_REQUIRED};
 
 /// Wrapper around a MAC used for authenticating clients that have a secret.
-pub struct Authenticator(Hmac<Sha256>);
+pub struct Authenticator {
+    mac: Hmac<Sha256>,
+
+    /// Short identifier of the secret, derived from its hash.
+    key_id: String,
+}
 
 impl Authenticator {
     /// Generate an authenticator from a secret.
     pub fn new(secret: &str) -> Self {
         let hashed_secret = Sha256::new().chain_update(secret).finalize();
-        Self(Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"))
+        let fingerprint = Sha256::digest(hashed_secret);
+        Self {
+            mac: Hmac::new_from_slice(&hashed_secret).expect("HMAC can take key of any size"),
+            key_id: hex::encode(&fingerprint[..4]),
+        }
+    }
+
+    /// Identify the secret in logs and metrics, without revealing it.
+    ///
+    /// ```
+    /// use bore_cli::auth::Authenticator;
+    ///
+    /// let id = Authenticator::new("secret").key_id().to_string();
+    /// assert_eq!(id.len(), 8);
+    /// assert_eq!(id, Authenticator::new("secret").key_id());
+    /// assert_ne!(id, Authenticator::new("other").key_id());
+    /// ```
+    pub fn key_id(&self) -> &str {
+        &self.key_id
     }
 
     /// Generate a reply message for a challenge.
     pub fn answer(&self, challenge: &Uuid) -> String {
-        let mut hmac = self.0.clone();
+        let mut hmac = self.mac.clone();
         hmac.update(challenge.as_bytes());
         hex::encode(hmac.finalize().into_bytes())
     }
@@ -39,7 +64,7 @@ impl Authenticator {
     /// ```
     pub fn validate(&self, challenge: &Uuid, tag: &str) -> bool {
         if let Ok(tag) = hex::decode(tag) {
-            let mut hmac = self.0.clone();
+            let mut hmac = self.mac.clone();
             hmac.update(challenge.as_bytes());
             hmac.verify_slice(&tag).is_ok()
         } else {
@@ -52,25 +77,46 @@ impl Authenticator {
         &self,
         stream: &mut Delimited<T>,
     ) -> Result<()> {
+        Self::server_handshake_any(&[self], stream).await?;
+        Ok(())
+    }
+
+    /// As the server, authenticate a client that may hold any one of several secrets.
+    ///
+    /// Returns the index of the authenticator whose secret the client used.
+    pub async fn server_handshake_any<T: AsyncRead + AsyncWrite + Unpin>(
+        auths: &[&Self],
+        stream: &mut Delimited<T>,
+    ) -> Result<usize> {
         let challenge = Uuid::new_v4();
         stream.send(ServerMessage::Challenge(challenge)).await?;
         match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(tag)) => {
-                ensure!(self.validate(&challenge, &tag), "invalid secret");
-                Ok(())
-            }
-            _ => bail!("server requires secret, but no secret was provided"),
+            Some(ClientMessage::Authenticate(tag)) => auths
+                .iter()
+                .position(|auth| auth.validate(&challenge, &tag))
+                .context(INVALID_SECRET),
+            _ => bail!(SECRET_REQUIRED),
         }
     }
 
     /// As the client, answer a challenge to attempt to authenticate with the server.
+    ///
+    /// A server that does not require a secret sends no challenge, so this fails with
+    /// [`ClientError::AuthTimeout`] if none arrives in time, and with
+    /// [`ClientError::AuthNotRequired`] if the server sends another message instead.
     pub async fn client_handshake<T: AsyncRead + AsyncWrite + Unpin>(
         &self,
         stream: &mut Delimited<T>,
     ) -> Result<()> {
-        let challenge = match stream.recv_timeout().await? {
-            Some(ServerMessage::Challenge(challenge)) => challenge,
-            _ => bail!("expected authentication challenge, but no secret was required"),
+        let challenge = match timeout(NETWORK_TIMEOUT, stream.recv()).await {
+            Ok(Ok(Some(ServerMessage::Challenge(challenge)))) => challenge,
+            Ok(Ok(Some(ServerMessage::Error(message)))) => {
+                return Err(ClientError::from_server(message).into())
+            }
+            Ok(Ok(Some(_))) => return Err(ClientError::AuthNotRequired.into()),
+            Err(_) => return Err(ClientError::AuthTimeout.into()),
+            Ok(Ok(None)) => bail!("server closed the connection before authenticating"),
+            Ok(Err(err)) => return Err(err),
         };
         let tag = self.answer(&challenge);
         stream.send(ClientMessage::Authenticate(tag)).await?;
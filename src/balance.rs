@@ -0,0 +1,74 @@
+//! Spreading the connections on a tunnel across several local targets.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::client::Balance;
+
+/// Local targets of a tunnel, with the connections open to each of them.
+pub(crate) struct Backends {
+    targets: Vec<Backend>,
+
+    /// Position where the next round of targets starts.
+    next: AtomicUsize,
+}
+
+/// A local host and port that connections can be forwarded to.
+pub(crate) struct Backend {
+    pub host: String,
+    pub port: u16,
+
+    /// Number of connections currently forwarded to this target.
+    active: AtomicUsize,
+}
+
+/// A connection forwarded to a target, counted until it is dropped.
+pub(crate) struct Active<'a>(&'a Backend);
+
+impl Backends {
+    /// Create the targets of a tunnel, starting with its local host and port.
+    pub fn new(host: &str, port: u16) -> Self {
+        let mut backends = Backends {
+            targets: Vec::new(),
+            next: AtomicUsize::new(0),
+        };
+        backends.push(host, port);
+        backends
+    }
+
+    /// Add another target.
+    pub fn push(&mut self, host: &str, port: u16) {
+        self.targets.push(Backend {
+            host: host.to_string(),
+            port,
+            active: AtomicUsize::new(0),
+        });
+    }
+
+    /// Returns every target, in the order to try them for a new connection.
+    ///
+    /// Each call starts one target further along, so that with round-robin, connections
+    /// take turns. With least-connections, this only breaks ties between targets.
+    pub fn candidates(&self, balance: Balance) -> Vec<&Backend> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.targets.len();
+        let mut candidates: Vec<_> = (0..len).map(|i| &self.targets[(start + i) % len]).collect();
+        if balance == Balance::LeastConnections {
+            candidates.sort_by_key(|backend| backend.active.load(Ordering::Relaxed));
+        }
+        candidates
+    }
+}
+
+impl Backend {
+    /// Count a connection to this target until the returned guard is dropped.
+    pub fn start(&self) -> Active<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Active(self)
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,84 @@
+//! IP networks in CIDR notation, used to filter client addresses.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+/// A range of IP addresses sharing a prefix, such as `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Create a network from an address and a prefix length in bits.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max_len = max_prefix_len(addr);
+        if prefix_len > max_len {
+            bail!("prefix length {prefix_len} is longer than {max_len} bits");
+        }
+        Ok(IpNet { addr, prefix_len })
+    }
+
+    /// Returns whether the address is in this network.
+    ///
+    /// IPv4-mapped IPv6 addresses are compared as the IPv4 address they contain.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32);
+                let mask = mask.unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32);
+                let mask = mask.unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    fn from(addr: IpAddr) -> Self {
+        let prefix_len = max_prefix_len(addr);
+        IpNet { addr, prefix_len }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    /// Parse a network in CIDR notation, or a single address without a prefix length.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr = addr
+                    .parse()
+                    .with_context(|| format!("invalid address {addr:?}"))?;
+                let len = len
+                    .parse()
+                    .with_context(|| format!("invalid prefix length {len:?}"))?;
+                IpNet::new(addr, len)
+            }
+            None => Ok(s.parse::<IpAddr>()?.into()),
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
@@ -1,36 +1,264 @@
 //! Client implementation for the `bore` service.
 
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
-use tracing::{error, info, info_span, warn, Instrument};
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use futures_util::future::{self, BoxFuture};
+use futures_util::stream::FuturesUnordered;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{lookup_host, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{error, field, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 use crate::auth::Authenticator;
+use crate::balance::{Active, Backends};
+use crate::compress::Compressed;
+use crate::http_proxy::HttpProxy;
+use crate::memory::MemoryConnector;
 use crate::shared::{
-    proxy, ClientMessage, Delimited, ServerMessage, CONTROL_PORT, NETWORK_TIMEOUT,
+    proxy, set_stream_options, ClientMessage, Delimited, Keepalive, ServerMessage, TunnelStatus,
+    CONTROL_PORT, MAX_FRAME_LENGTH, MAX_LABEL_LENGTH, NETWORK_TIMEOUT, PROTOCOL_VERSION,
+    UDP_SESSION_TIMEOUT,
 };
+use crate::socks::{self, Socks5Proxy};
+use crate::srv;
+
+pub use crate::shared::ClientError;
+
+/// Delay between probes of a local target that is not up yet.
+const LOCAL_PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest delay between retries of a connection to a local target.
+const MAX_LOCAL_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Delay before racing a connection to the next address of the server, from RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
 /// State structure for the client.
 pub struct Client {
     /// Control connection to the server.
-    conn: Option<Delimited<TcpStream>>,
+    conn: Option<Control>,
 
     /// Destination address of the server.
     to: String,
 
+    /// Port of the control server on the destination.
+    control_port: u16,
+
+    /// Tunnels that are forwarded, starting with the one given on creation.
+    tunnels: Vec<Tunnel>,
+
+    /// Ports that are publicly available on the remote, one for each tunnel.
+    remote_ports: Vec<u16>,
+
+    /// Public hostname advertised by the server, if any.
+    public_host: Option<String>,
+
+    /// Optional secret used to authenticate clients.
+    auth: Option<Authenticator>,
+
+    /// How to reconnect when the control connection is lost, if at all.
+    reconnect: Option<ReconnectPolicy>,
+
+    /// Time without any message from the server before the connection is considered dead.
+    heartbeat_timeout: Duration,
+
+    /// Whether to ask the server for the address of each remote peer.
+    report_peers: bool,
+
+    /// Latest status of each tunnel, if the server was asked to report it.
+    status: Option<watch::Sender<Vec<TunnelStatus>>>,
+
+    /// Local address that connections to the server and local services are made from.
+    local_bind_addr: Option<SocketAddr>,
+
+    /// Whether TCP connections to the server are set to `TCP_NODELAY`.
+    nodelay: bool,
+
+    /// TCP keepalive settings of connections to the server, if enabled.
+    keepalive: Option<Keepalive>,
+
+    /// Nameserver for SRV lookups, instead of the system's first nameserver.
+    srv_nameserver: Option<SocketAddr>,
+
+    /// Proxy that connections to the server go through, if any.
+    proxy: Option<Proxy>,
+
+    /// Token asking the server to hold the TCP tunnels for a reconnect, if any.
+    reservation_token: Option<String>,
+
+    /// Human-readable name that the server logs with the tunnels, if any.
+    label: Option<String>,
+
+    /// Address of the server that the tunnels are asked to listen on, if any.
+    bind_tunnels: Option<IpAddr>,
+
+    /// Time to wait for each connection to the server to open.
+    connect_timeout: Duration,
+
+    /// How to retry the first connection while the server is unreachable, if at all.
+    startup_retry: Option<ReconnectPolicy>,
+
+    /// How long to wait for the local targets to accept connections before connecting, if at all.
+    wait_for_local: Option<Duration>,
+
+    /// Number of retries of a refused connection to the local target, and the first delay.
+    local_retry: Option<(u32, Duration)>,
+
+    /// Connector to a server in the same process, used instead of TCP if set.
+    memory_connector: Option<MemoryConnector>,
+
+    /// Progress of the only connection to forward, if the client exits after one.
+    one_shot: Option<OneShot>,
+}
+
+/// Progress of a client that forwards a single connection, then exits.
+struct OneShot {
+    /// Whether the connection arrived, so that later ones are ignored.
+    started: AtomicBool,
+
+    /// Set once the connection is closed.
+    done: watch::Sender<bool>,
+}
+
+/// A local service forwarded through a tunnel on the server.
+struct Tunnel {
     // Local host that is forwarded.
     local_host: String,
 
     /// Local port that is forwarded.
     local_port: u16,
 
-    /// Port that is publicly available on the remote.
-    remote_port: u16,
+    /// Port requested on the remote, or 0 for any available port.
+    port: u16,
 
-    /// Optional secret used to authenticate clients.
-    auth: Option<Authenticator>,
+    /// Whether the tunnel forwards UDP datagrams instead of TCP connections.
+    udp: bool,
+
+    /// Name of an HTTP tunnel on the server's shared HTTP port, requested instead of a port.
+    name: Option<String>,
+
+    /// Whether each connection is a SOCKS5 request, instead of going to the local port.
+    socks: bool,
+
+    /// Unix socket that connections are forwarded to, instead of the local port.
+    #[cfg(unix)]
+    local_socket: Option<PathBuf>,
+
+    /// Opens the stream that each connection is forwarded to, instead of the local port.
+    connector: Option<Connector>,
+
+    /// Name whose SRV records give the targets of each connection, instead of the local port.
+    srv: Option<String>,
+
+    /// Local targets that connections are spread across, if there is more than one.
+    backends: Option<Backends>,
+
+    /// How connections are spread across the local targets.
+    balance: Balance,
+
+    /// Whether connections are compressed, if the server agrees.
+    compress: bool,
+}
+
+/// A stream that connections are carried on, such as a socket or an in-memory pipe.
+trait AnyStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AnyStream for S {}
+
+/// A connection to the server, over TCP or an in-memory transport.
+type ServerConn = Box<dyn AnyStream + Sync>;
+
+/// Function opening a stream for each forwarded connection.
+type Connector = Arc<dyn Fn() -> BoxFuture<'static, io::Result<Box<dyn AnyStream>>> + Send + Sync>;
+
+/// A proxy that connections to the server go through.
+enum Proxy {
+    Http(HttpProxy),
+    Socks5(Socks5Proxy),
+}
+
+/// An established control connection.
+struct Control {
+    stream: Delimited<ServerConn>,
+
+    /// Public port of each tunnel on this connection.
+    remote_ports: Vec<u16>,
+
+    /// Messages received while the tunnels were being requested.
+    backlog: Vec<ServerMessage>,
+
+    /// Protocol version negotiated with the server.
+    version: u32,
+
+    /// Whether the server agreed to compress data connections.
+    compress: bool,
+
+    /// Public hostname advertised by the server, if any.
+    host: Option<String>,
+}
+
+/// Policy for retrying the control connection, with exponential backoff.
+///
+/// This is used both to re-establish a lost connection, and to retry the first one.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+
+    /// Upper bound on the delay, which doubles after each failed attempt.
+    pub max_delay: Duration,
+
+    /// Number of consecutive failed attempts before giving up, or `None` to retry forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+/// How connections are spread across the local targets of a tunnel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Balance {
+    /// Each connection goes to the next target in turn.
+    #[default]
+    RoundRobin,
+
+    /// Each connection goes to the target with the fewest open connections.
+    LeastConnections,
+}
+
+impl FromStr for Balance {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-connections" => Ok(Self::LeastConnections),
+            _ => bail!("unknown balance {s:?}, expected \"round-robin\" or \"least-connections\""),
+        }
+    }
 }
 
 impl Client {
@@ -42,88 +270,1298 @@ impl Client {
         port: u16,
         secret: Option<&str>,
     ) -> Result<Self> {
-        let mut stream = Delimited::new(connect_with_timeout(to, CONTROL_PORT).await?);
-        let auth = secret.map(Authenticator::new);
-        if let Some(auth) = &auth {
-            auth.client_handshake(&mut stream).await?;
-        }
+        let mut client = Self::configure(local_host, local_port, to, port, secret);
+        client.connect().await?;
+        Ok(client)
+    }
 
-        stream.send(ClientMessage::Hello(port)).await?;
-        let remote_port = match stream.recv_timeout().await? {
-            Some(ServerMessage::Hello(remote_port)) => remote_port,
-            Some(ServerMessage::Error(message)) => bail!("server error: {message}"),
-            Some(ServerMessage::Challenge(_)) => {
-                bail!("server requires authentication, but no client secret was provided");
-            }
-            Some(_) => bail!("unexpected initial non-hello message"),
-            None => bail!("unexpected EOF"),
-        };
-        info!(remote_port, "connected to server");
-        info!("listening at {to}:{remote_port}");
+    /// Create a new client that forwards UDP datagrams to the local port.
+    pub async fn new_udp(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        port: u16,
+        secret: Option<&str>,
+    ) -> Result<Self> {
+        let mut client = Self::configure(local_host, local_port, to, port, secret);
+        client.set_udp(true);
+        client.connect().await?;
+        Ok(client)
+    }
 
-        Ok(Client {
-            conn: Some(stream),
+    /// Create a new client without connecting to the server yet.
+    ///
+    /// Use [`Client::connect`] to request the tunnel and learn the assigned
+    /// port, or call [`Client::listen`] directly to do both at once.
+    pub fn configure(
+        local_host: &str,
+        local_port: u16,
+        to: &str,
+        port: u16,
+        secret: Option<&str>,
+    ) -> Self {
+        let tunnel = Tunnel {
+            local_host: local_host.to_string(),
+            local_port,
+            port,
+            udp: false,
+            name: None,
+            socks: false,
+            #[cfg(unix)]
+            local_socket: None,
+            connector: None,
+            srv: None,
+            backends: None,
+            balance: Balance::default(),
+            compress: false,
+        };
+        Client {
+            conn: None,
             to: to.to_string(),
+            control_port: CONTROL_PORT,
+            tunnels: vec![tunnel],
+            remote_ports: Vec::new(),
+            public_host: None,
+            auth: secret.map(Authenticator::new),
+            reconnect: None,
+            heartbeat_timeout: Duration::from_secs(30),
+            report_peers: false,
+            status: None,
+            local_bind_addr: None,
+            nodelay: false,
+            keepalive: None,
+            srv_nameserver: None,
+            proxy: HttpProxy::from_env(to).map(Proxy::Http),
+            reservation_token: None,
+            label: None,
+            bind_tunnels: None,
+            connect_timeout: NETWORK_TIMEOUT,
+            startup_retry: None,
+            wait_for_local: None,
+            local_retry: None,
+            memory_connector: None,
+            one_shot: None,
+        }
+    }
+
+    /// Set whether the tunnel forwards UDP datagrams instead of TCP connections.
+    pub fn set_udp(&mut self, udp: bool) {
+        self.tunnels[0].udp = udp;
+    }
+
+    /// Request an HTTP tunnel by name, instead of by port.
+    ///
+    /// The server must route HTTP by name on a shared port, which is returned as the
+    /// remote port. Requests for `name.<server domain>` are then forwarded here.
+    pub fn set_name(&mut self, name: &str) {
+        self.tunnels[0].name = Some(name.to_string());
+    }
+
+    /// Handle each connection on the tunnel as a SOCKS5 request, instead of
+    /// forwarding it to the local host and port.
+    ///
+    /// Remote peers can then reach any destination that this machine can, so this
+    /// should only be used on a server that requires a secret and on a trusted network.
+    pub fn set_socks(&mut self, socks: bool) {
+        self.tunnels[0].socks = socks;
+    }
+
+    /// Forward connections to a Unix socket at this path, instead of the local host and port.
+    ///
+    /// The public side of the tunnel is still a TCP port on the server. This only
+    /// applies to TCP tunnels.
+    #[cfg(unix)]
+    pub fn set_local_socket(&mut self, path: impl Into<PathBuf>) {
+        self.tunnels[0].local_socket = Some(path.into());
+    }
+
+    /// Forward connections to the targets in the SRV records of `name`, such as
+    /// `_http._tcp.example.com`, instead of the local host and port.
+    ///
+    /// The records are looked up again for each connection, so targets that move are
+    /// followed. Targets are tried in the order given by their priority and weight,
+    /// until one accepts the connection. This only applies to TCP tunnels.
+    pub fn set_local_srv(&mut self, name: &str) {
+        self.tunnels[0].srv = Some(name.to_string());
+    }
+
+    /// Send SRV lookups to this nameserver, instead of the first one in `/etc/resolv.conf`.
+    pub fn set_srv_nameserver(&mut self, addr: SocketAddr) {
+        self.srv_nameserver = Some(addr);
+    }
+
+    /// Forward each connection to a stream opened by `connect`, instead of the local port.
+    ///
+    /// This lets the tunnel reach a service in the same process, or anything else that
+    /// is not a socket, like an in-memory pipe in tests. `connect` is called once for each
+    /// connection, possibly concurrently, and a connection is closed if it fails. This
+    /// only applies to TCP tunnels.
+    pub fn forward_stream<F, Fut, S>(&mut self, connect: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.tunnels[0].connector = Some(Arc::new(move || {
+            let stream = connect();
+            Box::pin(async move { Ok(Box::new(stream.await?) as Box<dyn AnyStream>) })
+        }));
+    }
+
+    /// Forward connections to another local host and port as well, spreading them across
+    /// all of the targets.
+    ///
+    /// Connections go to the original local host and port and each added target, as chosen
+    /// by [`Client::set_balance`]. If a target cannot be reached, the next one is tried
+    /// before the connection is closed. This only applies to TCP tunnels that forward to
+    /// a local port.
+    pub fn add_local_target(&mut self, local_host: &str, local_port: u16) {
+        let tunnel = &mut self.tunnels[0];
+        tunnel
+            .backends
+            .get_or_insert_with(|| Backends::new(&tunnel.local_host, tunnel.local_port))
+            .push(local_host, local_port);
+    }
+
+    /// Set how connections are spread across the local targets, which is round-robin by default.
+    pub fn set_balance(&mut self, balance: Balance) {
+        self.tunnels[0].balance = balance;
+    }
+
+    /// Set whether the data of the tunnel's connections is compressed, which is off by default.
+    ///
+    /// Connections are then sent to the server as a deflate stream, which helps with
+    /// text-heavy traffic over slow links. This only applies to TCP tunnels, and only
+    /// if the server allows compression; otherwise connections are sent as they are.
+    /// Once the first bytes of a connection turn out not to shrink, as with TLS, the
+    /// rest is sent without compressing it. Tunnels added afterwards start with the
+    /// same setting.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.tunnels[0].compress = compress;
+    }
+
+    /// Forward another local port over the same control connection.
+    ///
+    /// The server allocates a separate public port for each tunnel, and the client
+    /// authenticates only once for all of them. This must be called before connecting.
+    pub fn add_tunnel(&mut self, local_host: &str, local_port: u16, port: u16) {
+        self.tunnels.push(Tunnel {
             local_host: local_host.to_string(),
             local_port,
-            remote_port,
-            auth,
+            port,
+            udp: false,
+            name: None,
+            socks: false,
+            #[cfg(unix)]
+            local_socket: None,
+            connector: None,
+            srv: None,
+            backends: None,
+            balance: Balance::default(),
+            compress: self.tunnels[0].compress,
+        });
+    }
+
+    /// Forward another local UDP port over the same control connection.
+    pub fn add_udp_tunnel(&mut self, local_host: &str, local_port: u16, port: u16) {
+        self.add_tunnel(local_host, local_port, port);
+        self.tunnels.last_mut().unwrap().udp = true;
+    }
+
+    /// Connect to the server and request a tunnel, returning the assigned remote port.
+    ///
+    /// The port is known once this returns, before any connection is forwarded. This
+    /// is useful when requesting port 0, which lets the server choose a port.
+    pub async fn connect(&mut self) -> Result<u16> {
+        if let Some(limit) = self.wait_for_local {
+            self.wait_for_local_targets(limit).await?;
+        }
+        let control = match &self.startup_retry {
+            Some(policy) => self.connect_with_retries(policy).await?,
+            None => self.handshake().await?,
+        };
+        self.remote_ports = control.remote_ports.clone();
+        self.public_host = control.host.clone();
+        self.conn = Some(control);
+        Ok(self.remote_port())
+    }
+
+    /// Close every tunnel on the server, waiting until their ports are free again.
+    ///
+    /// This is faster than dropping the client, which leaves the server to notice that
+    /// the control connection is gone. Does nothing if the client is not connected.
+    pub async fn release(mut self) -> Result<()> {
+        let Some(mut conn) = self.conn.take() else {
+            return Ok(());
+        };
+        for &port in &conn.remote_ports {
+            conn.stream.send(ClientMessage::Release(port)).await?;
+        }
+        let mut remaining = conn.remote_ports.len();
+        while remaining > 0 {
+            match conn.stream.recv_timeout().await? {
+                Some(ServerMessage::Released(_)) => remaining -= 1,
+                Some(_) => continue,
+                // Once the last tunnel is gone, the server closes the connection.
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Probe the local target of every tunnel until it answers, or give up after `limit`.
+    async fn wait_for_local_targets(&self, limit: Duration) -> Result<()> {
+        let deadline = Instant::now() + limit;
+        for tunnel in &self.tunnels {
+            loop {
+                let err = match self.probe_local(tunnel).await {
+                    Ok(()) => break,
+                    Err(err) => err,
+                };
+                if Instant::now() + LOCAL_PROBE_INTERVAL > deadline {
+                    let secs = limit.as_secs_f64();
+                    return Err(err.context(format!("local target did not come up within {secs}s")));
+                }
+                info!(%err, "waiting for local target");
+                sleep(LOCAL_PROBE_INTERVAL).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Open and close a connection to the local target of a tunnel, if it can be probed.
+    async fn probe_local(&self, tunnel: &Tunnel) -> Result<()> {
+        if tunnel.udp || tunnel.socks || tunnel.connector.is_some() {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        if let Some(path) = &tunnel.local_socket {
+            timeout(NETWORK_TIMEOUT, UnixStream::connect(path))
+                .await
+                .map_err(io::Error::from)
+                .and_then(|connected| connected)
+                .with_context(|| format!("could not connect to {}", path.display()))?;
+            return Ok(());
+        }
+        if let Some(name) = &tunnel.srv {
+            self.connect_srv(name).await?;
+        } else if let Some(backends) = &tunnel.backends {
+            self.connect_backend(backends, tunnel.balance).await?;
+        } else {
+            let (host, port) = (&tunnel.local_host, tunnel.local_port);
+            connect_with_timeout(host, port, self.local_bind_addr, NETWORK_TIMEOUT).await?;
+        }
+        Ok(())
+    }
+
+    /// Open a control connection and request every tunnel.
+    async fn handshake(&self) -> Result<Control> {
+        let to = &self.to;
+        let mut stream = self.open_control().await?;
+        stream
+            .send(ClientMessage::Version(PROTOCOL_VERSION))
+            .await?;
+        let reply = timeout(NETWORK_TIMEOUT, recv_version(&mut stream))
+            .await
+            .context("timed out waiting for version")??;
+        let version = match reply {
+            Some(version) => version,
+            None => {
+                // Servers before version 1 close the connection on unknown messages.
+                info!("server does not negotiate versions, retrying with version 0");
+                stream = self.open_control().await?;
+                0
+            }
+        };
+        if self.report_peers {
+            if version >= 1 {
+                stream.send(ClientMessage::ReportPeers).await?;
+            } else {
+                warn!(version, "server does not report peer addresses");
+            }
+        }
+        if let Some(token) = &self.reservation_token {
+            if version >= 3 {
+                stream.send(ClientMessage::Reserve(token.clone())).await?;
+            } else {
+                warn!(version, "server does not reserve ports");
+            }
+        }
+        if let Some(label) = &self.label {
+            if version >= 6 {
+                stream.send(ClientMessage::Label(label.clone())).await?;
+            } else {
+                warn!(version, "server does not log client labels");
+            }
+        }
+        if self.status.is_some() {
+            if version >= 7 {
+                stream.send(ClientMessage::ReportStatus).await?;
+            } else {
+                warn!(version, "server does not report tunnel status");
+            }
+        }
+        if let Some(addr) = self.bind_tunnels {
+            if version >= 8 {
+                stream.send(ClientMessage::BindTunnels(addr)).await?;
+            } else {
+                warn!(version, "server does not bind tunnels to client addresses");
+            }
+        }
+        if self.tunnels.iter().any(|tunnel| tunnel.compress) {
+            if version >= 9 {
+                stream.send(ClientMessage::Compress).await?;
+            } else {
+                warn!(version, "server does not compress connections");
+            }
+        }
+
+        let mut remote_ports = Vec::new();
+        let mut backlog = Vec::new();
+        let mut host = None;
+        let mut compress = false;
+        for (index, tunnel) in self.tunnels.iter().enumerate() {
+            if let Some(name) = &tunnel.name {
+                ensure!(version >= 1, "server does not support named tunnels");
+                stream.send(ClientMessage::HelloNamed(name.clone())).await?;
+            } else if tunnel.udp {
+                stream.send(ClientMessage::HelloUdp(tunnel.port)).await?;
+            } else {
+                // Reserved ports are requested again, to take back the held tunnels.
+                let port = match (&self.reservation_token, self.remote_ports.get(index)) {
+                    (Some(_), Some(&remote_port)) => remote_port,
+                    _ => tunnel.port,
+                };
+                stream.send(ClientMessage::Hello(port)).await?;
+            }
+            // Earlier tunnels are already live, so their messages may come first.
+            let live = !remote_ports.is_empty();
+            let reply = recv_hello(&mut stream, &mut backlog, &mut host, &mut compress, live);
+            let remote_port = match timeout(NETWORK_TIMEOUT, reply)
+                .await
+                .context("timed out waiting for hello")?
+            {
+                Ok(remote_port) => remote_port,
+                // The server keeps the earlier tunnels open when a later one is refused.
+                Err(err) if live && err.is::<ClientError>() => {
+                    warn!(%err, "server refused tunnel");
+                    remote_ports.push(0);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            if remote_ports.is_empty() {
+                info!(remote_port, "connected to server");
+            }
+            let public_host = host.as_deref().unwrap_or(to);
+            info!("listening at {public_host}:{remote_port}");
+            remote_ports.push(remote_port);
+        }
+        Ok(Control {
+            stream,
+            remote_ports,
+            backlog,
+            version,
+            compress,
+            host,
         })
     }
 
+    /// Open a control connection to the server and authenticate on it.
+    async fn open_control(&self) -> Result<Delimited<ServerConn>> {
+        let mut stream = Delimited::new(self.connect_server().await?);
+        if let Some(auth) = &self.auth {
+            auth.client_handshake(&mut stream).await?;
+        }
+        Ok(stream)
+    }
+
     /// Returns the port publicly available on the remote.
+    ///
+    /// This is 0 until the client has connected to the server.
     pub fn remote_port(&self) -> u16 {
-        self.remote_port
+        self.remote_ports.first().copied().unwrap_or(0)
+    }
+
+    /// Returns the host to share with the remote port, as advertised by the server.
+    ///
+    /// This is the server address the client was given, if the server advertises no
+    /// hostname or the client has not connected yet.
+    pub fn public_host(&self) -> &str {
+        self.public_host.as_deref().unwrap_or(&self.to)
+    }
+
+    /// Returns the public port of every tunnel, in the order they were added.
+    ///
+    /// This is empty until the client has connected to the server. A tunnel after the
+    /// first that the server refused has port 0, while the others stay open.
+    pub fn remote_ports(&self) -> &[u16] {
+        &self.remote_ports
+    }
+
+    /// Set the port of the control server, if it does not use the default [`CONTROL_PORT`].
+    pub fn set_control_port(&mut self, control_port: u16) {
+        self.control_port = control_port;
+    }
+
+    /// Reconnect with this policy whenever the control connection is lost.
+    ///
+    /// Each reconnection requests the same remote port as the original connection,
+    /// so a randomly assigned port may change. Connections that are already being
+    /// forwarded are not interrupted. By default, the client does not reconnect.
+    pub fn set_reconnect(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = Some(policy);
+    }
+
+    /// Set how long to wait for a heartbeat before considering the server unreachable.
+    ///
+    /// When this elapses, the control connection is closed, and the client either
+    /// exits or reconnects. The default is 30 seconds.
+    pub fn set_heartbeat_timeout(&mut self, heartbeat_timeout: Duration) {
+        self.heartbeat_timeout = heartbeat_timeout;
+    }
+
+    /// Ask the server for the address of the remote peer of each connection.
+    ///
+    /// The address is then recorded as the `peer` field of each connection's span.
+    /// Servers that predate this feature reject the request, so it is off by default.
+    pub fn set_report_peers(&mut self, report_peers: bool) {
+        self.report_peers = report_peers;
+    }
+
+    /// Ask the server for the status of each tunnel with every heartbeat, and watch it.
+    ///
+    /// The receiver holds the latest [`TunnelStatus`] of each tunnel, such as to show
+    /// a live count of connections and bytes, and is updated until the client is
+    /// dropped. Servers before protocol version 7 send no status, so it stays empty.
+    pub fn watch_status(&mut self) -> watch::Receiver<Vec<TunnelStatus>> {
+        self.status
+            .get_or_insert_with(|| watch::channel(Vec::new()).0)
+            .subscribe()
+    }
+
+    /// Make connections to the server and to local services from this address.
+    ///
+    /// This pins traffic to the network interface that owns the address. Destinations
+    /// of the other address family are skipped. A nonzero port is only suitable when
+    /// there is at most one connection to each destination at a time.
+    pub fn set_local_bind_addr(&mut self, addr: SocketAddr) {
+        self.local_bind_addr = Some(addr);
+    }
+
+    /// Set whether TCP connections to the server are set to `TCP_NODELAY`, which is off
+    /// by default.
+    ///
+    /// This applies to the control connection and to those that carry forwarded streams,
+    /// the client's side of [`Server::set_nodelay`](crate::server::Server::set_nodelay).
+    /// Set it before [`Client::connect`] for it to cover the control connection.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Enable TCP keepalive on connections to the server, with these settings.
+    ///
+    /// The kernel then probes connections that have been idle for a while, and drops
+    /// them if the server stopped answering. This applies to the same connections as
+    /// [`Client::set_nodelay`]. Keepalive is off by default.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = Some(keepalive);
+    }
+
+    /// Connect to the server through an HTTP proxy, given as `http://[user:password@]host[:port]`.
+    ///
+    /// The proxy is asked to reach the server with a `CONNECT` request, using basic
+    /// authentication if the URL has credentials. By default, the proxy is taken
+    /// from the `HTTP_PROXY` or `ALL_PROXY` environment variables, unless the server
+    /// is listed in `NO_PROXY`.
+    pub fn set_http_proxy(&mut self, url: &str) -> Result<()> {
+        self.proxy = Some(Proxy::Http(HttpProxy::new(url)?));
+        Ok(())
+    }
+
+    /// Connect to the server through a SOCKS5 proxy at `host:port`, instead of an HTTP proxy.
+    ///
+    /// If the proxy requires it, the client authenticates with the user and password
+    /// in `credentials`. The proxy resolves the server's name, so this works for Tor
+    /// hidden services. The connect timeout covers the connection to the proxy, and
+    /// reconnections go through the proxy again.
+    pub fn set_socks5_proxy(
+        &mut self,
+        addr: &str,
+        credentials: Option<(&str, &str)>,
+    ) -> Result<()> {
+        self.proxy = Some(Proxy::Socks5(Socks5Proxy::new(addr, credentials)?));
+        Ok(())
+    }
+
+    /// Ask the server to hold the TCP tunnels for a while when the control connection is lost.
+    ///
+    /// Reconnections send the token again and request the ports assigned on the first
+    /// connection, so they get the same ports back even if those were chosen at random.
+    /// The token lets anyone reclaim the ports, so it should be hard to guess. Servers
+    /// that do not hold tunnels release the ports as usual.
+    pub fn set_reservation_token(&mut self, token: &str) {
+        self.reservation_token = Some(token.into());
+    }
+
+    /// Give this client a label, such as `ci-runner-7`, that the server logs with its tunnels.
+    ///
+    /// This tells clients apart on a shared server, and is shown to administrators who
+    /// list the tunnels. Control characters are dropped, and the label is cut to
+    /// [`MAX_LABEL_LENGTH`] characters, or fewer if they would not fit in one frame.
+    /// Servers before protocol version 6 ignore the label.
+    pub fn set_label(&mut self, label: &str) {
+        let mut label: String = label
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_LABEL_LENGTH)
+            .collect();
+        while !label.is_empty() && label_frame_len(&label) > MAX_FRAME_LENGTH {
+            label.pop();
+        }
+        self.label = Some(label);
+    }
+
+    /// Ask the server to bind the tunnels to this one of its IP addresses.
+    ///
+    /// The server only does so for addresses it allows clients to choose, and binds the
+    /// tunnels to its usual addresses otherwise. Named tunnels are not affected, and
+    /// servers before protocol version 8 ignore the address.
+    pub fn set_bind_tunnels(&mut self, addr: IpAddr) {
+        self.bind_tunnels = Some(addr);
+    }
+
+    /// Set how long to wait for each connection to the server to open.
+    ///
+    /// This covers the TCP connection to the server or its proxy, not the handshake
+    /// that follows. The default is 3 seconds.
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    /// Retry the first connection with this policy while the server is unreachable.
+    ///
+    /// This helps when the client may start before the server, or before its name
+    /// resolves. Only failures to open the connection are retried, and other errors,
+    /// such as a rejected secret, are returned right away. Once connected, lost
+    /// connections are handled by [`Client::set_reconnect`] instead. By default, the
+    /// first failure is returned.
+    pub fn set_startup_retry(&mut self, policy: ReconnectPolicy) {
+        self.startup_retry = Some(policy);
+    }
+
+    /// Wait up to this long for the local targets to accept connections before connecting.
+    ///
+    /// Each TCP target and Unix socket is probed with a connection that is closed right
+    /// away, and only once all of them answer is the tunnel requested, so it is never
+    /// advertised while every connection would fail. Tunnels to several targets need
+    /// only one of them up. UDP, SOCKS5 and custom targets are not probed. By default,
+    /// the client connects without checking.
+    pub fn set_wait_for_local(&mut self, timeout: Duration) {
+        self.wait_for_local = Some(timeout);
+    }
+
+    /// Retry connections to the local target up to this many times, when they fail.
+    ///
+    /// Each forwarded connection that cannot reach its local target, such as while the
+    /// local service restarts, is retried after `delay`, which doubles after each
+    /// retry up to 2 seconds, before the connection is given up. This applies to TCP
+    /// and Unix socket targets, SRV and custom targets, but not to the destinations of
+    /// SOCKS5 requests. By default, a connection is given up on the first failure.
+    pub fn set_local_retry(&mut self, retries: u32, delay: Duration) {
+        self.local_retry = Some((retries, delay));
+    }
+
+    /// Connect to a server in the same process through an in-memory transport, instead of TCP.
+    ///
+    /// The server must accept connections from the other end of [`memory::channel`],
+    /// which is mostly useful in tests. The server address, control port and proxy are
+    /// then ignored.
+    ///
+    /// [`memory::channel`]: crate::memory::channel
+    pub fn set_memory_connector(&mut self, connector: MemoryConnector) {
+        self.memory_connector = Some(connector);
+    }
+
+    /// Set whether to exit after forwarding a single connection.
+    ///
+    /// Once the first connection on any tunnel is closed, the control connection is
+    /// closed and [`Client::listen`] returns, without reconnecting. Connections that
+    /// arrive while the first one is open are ignored, so the server drops them.
+    pub fn set_one_shot(&mut self, one_shot: bool) {
+        self.one_shot = one_shot.then(|| OneShot {
+            started: AtomicBool::new(false),
+            done: watch::channel(false).0,
+        });
     }
 
     /// Start the client, listening for new connections.
+    ///
+    /// Each connection is forwarded on its own, so one that fails, such as when the
+    /// local service is down, is closed without affecting the tunnel.
     pub async fn listen(mut self) -> Result<()> {
+        if self.conn.is_none() {
+            self.connect().await?;
+        }
         let mut conn = self.conn.take().unwrap();
         let this = Arc::new(self);
         loop {
-            match conn.recv().await? {
-                Some(ServerMessage::Hello(_)) => warn!("unexpected hello"),
-                Some(ServerMessage::Challenge(_)) => warn!("unexpected challenge"),
-                Some(ServerMessage::Heartbeat) => (),
-                Some(ServerMessage::Connection(id)) => {
-                    let this = Arc::clone(&this);
-                    tokio::spawn(
-                        async move {
-                            info!("new connection");
-                            match this.handle_connection(id).await {
-                                Ok(_) => info!("connection exited"),
-                                Err(err) => warn!(%err, "connection exited with error"),
-                            }
-                        }
-                        .instrument(info_span!("proxy", %id)),
-                    );
+            let result = this.forward_connections(&mut conn).await;
+            if this
+                .one_shot
+                .as_ref()
+                .is_some_and(|one_shot| *one_shot.done.borrow())
+            {
+                info!("forwarded the one connection, exiting");
+                return result;
+            }
+            let Some(policy) = &this.reconnect else {
+                return result;
+            };
+            match result {
+                Ok(()) => warn!("control connection closed"),
+                Err(err) => warn!(%err, "control connection lost"),
+            }
+            conn = this.reconnect(policy).await?;
+            if let Some(tx) = &this.status {
+                // Tunnels may get other ports, and they start counting again.
+                tx.send_modify(Vec::clear);
+            }
+        }
+    }
+
+    /// Handle messages on the control connection until it closes.
+    async fn forward_connections(self: &Arc<Self>, conn: &mut Control) -> Result<()> {
+        for msg in conn.backlog.drain(..) {
+            self.handle_message(msg, &conn.remote_ports, conn.version, conn.compress);
+        }
+        let mut done = self
+            .one_shot
+            .as_ref()
+            .map(|one_shot| one_shot.done.subscribe());
+        loop {
+            let recv = timeout(self.heartbeat_timeout, conn.stream.recv());
+            let msg = tokio::select! {
+                msg = recv => msg,
+                _ = one_shot_done(&mut done) => return Ok(()),
+            };
+            let Ok(msg) = msg else {
+                bail!("no heartbeat from server in {:?}", self.heartbeat_timeout);
+            };
+            match msg? {
+                Some(msg) => {
+                    self.handle_message(msg, &conn.remote_ports, conn.version, conn.compress)
                 }
-                Some(ServerMessage::Error(err)) => error!(%err, "server error"),
                 None => return Ok(()),
             }
         }
     }
 
-    async fn handle_connection(&self, id: Uuid) -> Result<()> {
-        let mut remote_conn =
-            Delimited::new(connect_with_timeout(&self.to[..], CONTROL_PORT).await?);
+    /// Handle a message from the server after the tunnels are set up.
+    fn handle_message(
+        self: &Arc<Self>,
+        msg: ServerMessage,
+        remote_ports: &[u16],
+        version: u32,
+        compress: bool,
+    ) {
+        let (index, id, peer) = match msg {
+            ServerMessage::Hello(_) => {
+                warn!("unexpected hello");
+                return;
+            }
+            ServerMessage::Version(_) => {
+                warn!("unexpected version");
+                return;
+            }
+            ServerMessage::Challenge(_) => {
+                warn!("unexpected challenge");
+                return;
+            }
+            ServerMessage::Host(_) => {
+                warn!("unexpected host");
+                return;
+            }
+            ServerMessage::Compress => {
+                warn!("unexpected compress");
+                return;
+            }
+            ServerMessage::Released(port) => {
+                warn!(port, "tunnel closed by the server");
+                return;
+            }
+            ServerMessage::Accepted(_) => {
+                warn!("unexpected accept");
+                return;
+            }
+            ServerMessage::TunnelList(_) | ServerMessage::Killed { .. } => {
+                warn!("unexpected reply to an admin command");
+                return;
+            }
+            ServerMessage::Heartbeat => return,
+            ServerMessage::Status(status) => {
+                if let Some(tx) = &self.status {
+                    tx.send_modify(|tunnels| {
+                        match tunnels.iter_mut().find(|tunnel| tunnel.port == status.port) {
+                            Some(tunnel) => *tunnel = status,
+                            None => tunnels.push(status),
+                        }
+                    });
+                }
+                return;
+            }
+            ServerMessage::Connection(id) => (0, id, None),
+            ServerMessage::TunnelConnection(port, id) => {
+                let Some(index) = remote_ports.iter().position(|&p| p == port) else {
+                    warn!(port, "connection for unknown tunnel");
+                    return;
+                };
+                (index, id, None)
+            }
+            ServerMessage::PeerConnection { port, id, remote } => {
+                let Some(index) = remote_ports.iter().position(|&p| p == port) else {
+                    warn!(port, "connection for unknown tunnel");
+                    return;
+                };
+                (index, id, Some(remote))
+            }
+            ServerMessage::Error(err) => {
+                error!(%err, "server error");
+                return;
+            }
+        };
+        if let Some(one_shot) = &self.one_shot {
+            if one_shot.started.swap(true, Ordering::Relaxed) {
+                warn!(%id, "already forwarding the one connection, ignoring");
+                return;
+            }
+        }
+        let span = info_span!("proxy", %id, peer = field::Empty);
+        if let Some(peer) = peer {
+            span.record("peer", field::display(peer));
+        }
+        let this = Arc::clone(self);
+        // Servers that reply to accepts report connections that expired in the meantime.
+        let reply = version >= 2;
+        let tunnel = &self.tunnels[index];
+        let compress = compress && tunnel.compress && !tunnel.udp;
+        tokio::spawn(
+            async move {
+                info!("new connection");
+                match this
+                    .handle_connection(id, &this.tunnels[index], reply, compress)
+                    .await
+                {
+                    Ok(_) => info!("connection exited"),
+                    Err(err) => warn!(%err, "connection exited with error"),
+                }
+                if let Some(one_shot) = &this.one_shot {
+                    one_shot.done.send_replace(true);
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Open the first control connection, retrying while the server is unreachable.
+    ///
+    /// Other errors, such as a rejected secret, are returned right away.
+    async fn connect_with_retries(&self, policy: &ReconnectPolicy) -> Result<Control> {
+        with_backoff(
+            policy,
+            || self.handshake(),
+            |err, attempt, delay| {
+                let unreachable = matches!(err.downcast_ref(), Some(ClientError::Connect(_)));
+                if unreachable {
+                    warn!(%err, attempt, ?delay, "server unreachable, retrying");
+                }
+                unreachable
+            },
+        )
+        .await
+    }
+
+    /// Re-establish the control connection, backing off between failed attempts.
+    async fn reconnect(&self, policy: &ReconnectPolicy) -> Result<Control> {
+        info!(delay = ?policy.initial_delay, "reconnecting to server");
+        sleep(policy.initial_delay).await;
+        // The first attempt waited already, so the backoff starts at the next delay.
+        let policy = ReconnectPolicy {
+            initial_delay: (policy.initial_delay * 2).min(policy.max_delay),
+            ..policy.clone()
+        };
+        with_backoff(
+            &policy,
+            || self.handshake(),
+            |err, attempt, delay| {
+                warn!(%err, attempt, ?delay, "failed to reconnect, retrying");
+                true
+            },
+        )
+        .await
+        .context("giving up on reconnecting")
+    }
+
+    /// Open a connection to the control port of the server, through the proxy if set.
+    async fn connect_server(&self) -> Result<ServerConn> {
+        if let Some(connector) = &self.memory_connector {
+            let stream = connector
+                .connect()
+                .map_err(ClientError::Connect)
+                .context("could not connect to the server in memory")?;
+            return Ok(Box::new(stream));
+        }
+        let (to, port) = (&self.to, self.control_port);
+        let Some(proxy) = &self.proxy else {
+            let stream =
+                connect_with_timeout(to, port, self.local_bind_addr, self.connect_timeout).await?;
+            self.configure_stream(&stream);
+            return Ok(Box::new(stream));
+        };
+        let (proxy_host, proxy_port) = match proxy {
+            Proxy::Http(proxy) => (&proxy.host, proxy.port),
+            Proxy::Socks5(proxy) => (&proxy.host, proxy.port),
+        };
+        let bind_addr = self.local_bind_addr;
+        let mut stream =
+            connect_with_timeout(proxy_host, proxy_port, bind_addr, self.connect_timeout)
+                .await
+                .context("could not connect to proxy")?;
+        self.configure_stream(&stream);
+        let handshake = async {
+            match proxy {
+                Proxy::Http(proxy) => proxy.connect(&mut stream, to, port).await,
+                Proxy::Socks5(proxy) => proxy.connect(&mut stream, to, port).await,
+            }
+        };
+        timeout(NETWORK_TIMEOUT, handshake)
+            .await
+            .context("timed out waiting for proxy")?
+            .with_context(|| format!("could not connect to {to}:{port} through proxy"))?;
+        Ok(Box::new(stream))
+    }
+
+    /// Apply the client's socket options to a TCP connection to the server or its proxy.
+    fn configure_stream(&self, stream: &TcpStream) {
+        if let Err(err) = set_stream_options(stream, self.nodelay, self.keepalive) {
+            warn!(%err, "could not set socket options");
+        }
+    }
+
+    async fn handle_connection(
+        &self,
+        id: Uuid,
+        tunnel: &Tunnel,
+        reply: bool,
+        compress: bool,
+    ) -> Result<()> {
+        let mut remote_conn = Delimited::new(self.connect_server().await?);
         if let Some(auth) = &self.auth {
             auth.client_handshake(&mut remote_conn).await?;
         }
-        remote_conn.send(ClientMessage::Accept(id)).await?;
-        let mut local_conn = connect_with_timeout(&self.local_host, self.local_port).await?;
+        if compress {
+            remote_conn.send(ClientMessage::Compress).await?;
+        }
+        if reply {
+            remote_conn.send(ClientMessage::AcceptWithReply(id)).await?;
+            match remote_conn.recv_timeout().await? {
+                Some(ServerMessage::Accepted(_)) => {}
+                Some(ServerMessage::Error(message)) => {
+                    return Err(ClientError::from_server(message).into())
+                }
+                Some(_) => bail!("unexpected reply to accept"),
+                None => bail!("server closed the connection before accepting"),
+            }
+        } else {
+            remote_conn.send(ClientMessage::Accept(id)).await?;
+        }
+        if tunnel.udp {
+            return Self::forward_datagrams(remote_conn, tunnel, self.local_bind_addr).await;
+        }
         let parts = remote_conn.into_parts();
         debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-        local_conn.write_all(&parts.read_buf).await?; // mostly of the cases, this will be empty
-        proxy(local_conn, parts.io).await?;
+        let (mut remote, read_buf): (ServerConn, _) = if compress {
+            // Bytes the server sent after its reply are part of the compressed stream.
+            (
+                Box::new(Compressed::new(parts.io, &parts.read_buf)),
+                &[][..],
+            )
+        } else {
+            (parts.io, &parts.read_buf[..])
+        };
+        if let Some(connect) = &tunnel.connector {
+            let mut local_conn = self
+                .retry_local(|| {
+                    let local_conn = connect();
+                    async {
+                        local_conn
+                            .await
+                            .map_err(ClientError::Connect)
+                            .context("could not open local stream")
+                    }
+                })
+                .await?;
+            local_conn.write_all(read_buf).await?;
+            proxy(local_conn, remote).await?;
+            return Ok(());
+        }
+        #[cfg(unix)]
+        if let Some(path) = &tunnel.local_socket {
+            let mut local_conn = self
+                .retry_local(move || async move {
+                    let connect = timeout(NETWORK_TIMEOUT, UnixStream::connect(path)).await;
+                    match connect {
+                        Ok(res) => res,
+                        Err(err) => Err(err.into()),
+                    }
+                    .map_err(ClientError::Connect)
+                    .with_context(|| format!("could not connect to {}", path.display()))
+                })
+                .await?;
+            local_conn.write_all(read_buf).await?;
+            proxy(local_conn, remote).await?;
+            return Ok(());
+        }
+        let (mut local_conn, buffered, _active) = if tunnel.socks {
+            // Part of the request may be buffered already, after the reply to the accept.
+            let (read, mut write) = tokio::io::split(&mut remote);
+            let mut read = read_buf.chain(read);
+            let local_conn = socks::accept(&mut read, &mut write).await?;
+            (local_conn, read.into_inner().0, None)
+        } else if let Some(name) = &tunnel.srv {
+            let local_conn = self.retry_local(|| self.connect_srv(name)).await?;
+            (local_conn, read_buf, None)
+        } else if let Some(backends) = &tunnel.backends {
+            let (local_conn, active) = self
+                .retry_local(|| self.connect_backend(backends, tunnel.balance))
+                .await?;
+            (local_conn, read_buf, Some(active))
+        } else {
+            let (host, port) = (&tunnel.local_host, tunnel.local_port);
+            let bind_addr = self.local_bind_addr;
+            let local_conn = self
+                .retry_local(|| connect_with_timeout(host, port, bind_addr, NETWORK_TIMEOUT))
+                .await?;
+            (local_conn, read_buf, None)
+        };
+        local_conn.write_all(buffered).await?; // mostly of the cases, this will be empty
+        proxy(local_conn, remote).await?;
         Ok(())
     }
+
+    /// Open a connection to a local target, retrying it with backoff as configured.
+    ///
+    /// Only failures to connect are retried, so that errors such as a failed SRV lookup
+    /// are returned right away.
+    async fn retry_local<T, F, Fut>(&self, connect: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let (retries, delay) = self.local_retry.unwrap_or_default();
+        let policy = ReconnectPolicy {
+            initial_delay: delay.min(MAX_LOCAL_RETRY_DELAY),
+            max_delay: MAX_LOCAL_RETRY_DELAY,
+            max_attempts: Some(retries.saturating_add(1)),
+        };
+        with_backoff(&policy, connect, |err, retry, delay| {
+            let unreachable = matches!(err.downcast_ref(), Some(ClientError::Connect(_)));
+            if unreachable {
+                warn!(%err, retry, ?delay, "local target unreachable, retrying");
+            }
+            unreachable
+        })
+        .await
+    }
+
+    /// Connect to the first local target that can be reached, in the order chosen by `balance`.
+    ///
+    /// The connection is counted against its target for as long as the guard is held.
+    async fn connect_backend<'a>(
+        &self,
+        backends: &'a Backends,
+        balance: Balance,
+    ) -> Result<(TcpStream, Active<'a>)> {
+        let mut last_err = None;
+        for backend in backends.candidates(balance) {
+            let (host, port) = (&backend.host, backend.port);
+            match connect_with_timeout(host, port, self.local_bind_addr, NETWORK_TIMEOUT).await {
+                Ok(local_conn) => return Ok((local_conn, backend.start())),
+                Err(err) => {
+                    warn!(%err, "local target unreachable, trying the next one");
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("tunnel has at least one local target"))
+    }
+
+    /// Connect to the first target in the SRV records of `name` that can be reached.
+    async fn connect_srv(&self, name: &str) -> Result<TcpStream> {
+        let targets = srv::lookup(name, self.srv_nameserver)
+            .await
+            .with_context(|| format!("could not look up {name}"))?;
+        let mut last_err = None;
+        for (host, port) in targets {
+            match connect_with_timeout(&host, port, self.local_bind_addr, NETWORK_TIMEOUT).await {
+                Ok(local_conn) => return Ok(local_conn),
+                Err(err) => {
+                    warn!(%err, "srv target unreachable, trying the next one");
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("srv lookup returns at least one target"))
+    }
+
+    async fn forward_datagrams(
+        remote_conn: Delimited<ServerConn>,
+        tunnel: &Tunnel,
+        local_bind_addr: Option<SocketAddr>,
+    ) -> Result<()> {
+        let local_addr = lookup_host((&tunnel.local_host[..], tunnel.local_port))
+            .await?
+            .next()
+            .with_context(|| format!("could not resolve {}", tunnel.local_host))?;
+        let bind_addr: SocketAddr = match local_bind_addr {
+            Some(addr) if addr.is_ipv4() == local_addr.is_ipv4() => addr,
+            _ if local_addr.is_ipv4() => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            _ => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(local_addr).await?;
+
+        let mut remote_conn = remote_conn.into_datagrams();
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            tokio::select! {
+                frame = remote_conn.next() => match frame {
+                    Some(frame) => {
+                        socket.send(&frame?).await?;
+                    }
+                    None => return Ok(()),
+                },
+                len = socket.recv(&mut buf) => {
+                    remote_conn.send(Bytes::copy_from_slice(&buf[..len?])).await?;
+                }
+                _ = sleep(UDP_SESSION_TIMEOUT) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Run `attempt` until it succeeds, with the backoff of `policy` between failures.
+///
+/// Each error is passed to `retry`, with the number of the failed attempt and the delay
+/// before the next one, to decide whether to try again. It is not asked once the
+/// policy's attempts are used up, and the last error is returned.
+async fn with_backoff<T, F, Fut>(
+    policy: &ReconnectPolicy,
+    mut attempt: F,
+    mut retry: impl FnMut(&anyhow::Error, u32, Duration) -> bool,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = policy.initial_delay;
+    let mut number = 1;
+    loop {
+        match attempt().await {
+            Err(err)
+                if policy.max_attempts.is_none_or(|max| number < max)
+                    && retry(&err, number, delay) => {}
+            result => return result,
+        }
+        sleep(delay).await;
+        delay = (delay * 2).min(policy.max_delay);
+        number += 1;
+    }
 }
 
-async fn connect_with_timeout(to: &str, port: u16) -> Result<TcpStream> {
-    match timeout(NETWORK_TIMEOUT, TcpStream::connect((to, port))).await {
+/// Wait for the server to reply to a hello, returning the public port of the tunnel.
+///
+/// When other tunnels are already live, connections announced in the meantime are
+/// saved to the backlog. A hostname advertised by the server is saved to `host`.
+async fn recv_hello(
+    stream: &mut Delimited<ServerConn>,
+    backlog: &mut Vec<ServerMessage>,
+    host: &mut Option<String>,
+    compress: &mut bool,
+    live: bool,
+) -> Result<u16> {
+    loop {
+        match stream.recv().await? {
+            Some(ServerMessage::Hello(remote_port)) => return Ok(remote_port),
+            Some(ServerMessage::Host(name)) => *host = Some(name),
+            Some(ServerMessage::Compress) => *compress = true,
+            Some(ServerMessage::Error(message)) => {
+                return Err(ClientError::from_server(message).into())
+            }
+            Some(ServerMessage::Challenge(_)) => return Err(ClientError::AuthRequired.into()),
+            Some(ServerMessage::Heartbeat) if live => (),
+            Some(
+                msg @ (ServerMessage::Status(_)
+                | ServerMessage::Connection(_)
+                | ServerMessage::TunnelConnection(..)
+                | ServerMessage::PeerConnection { .. }),
+            ) if live => backlog.push(msg),
+            Some(_) => bail!("unexpected initial non-hello message"),
+            None => bail!("unexpected EOF"),
+        }
+    }
+}
+
+/// Length of the frame that sends a label to the server.
+fn label_frame_len(label: &str) -> usize {
+    let msg = ClientMessage::Label(label.into());
+    serde_json::to_vec(&msg).map_or(usize::MAX, |frame| frame.len())
+}
+
+/// Wait until the one connection of a one-shot client is closed, or forever for other clients.
+async fn one_shot_done(done: &mut Option<watch::Receiver<bool>>) {
+    match done {
+        Some(done) => _ = done.wait_for(|&done| done).await,
+        None => future::pending().await,
+    }
+}
+
+/// Wait for the server's reply to a version message, or `None` if it hung up.
+pub(crate) async fn recv_version<U>(stream: &mut Delimited<U>) -> Result<Option<u32>>
+where
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    match stream.recv().await? {
+        Some(ServerMessage::Version(version)) => Ok(Some(version)),
+        Some(ServerMessage::Error(message)) => Err(ClientError::from_server(message).into()),
+        Some(ServerMessage::Challenge(_)) => Err(ClientError::AuthRequired.into()),
+        Some(_) => bail!("unexpected reply to version message"),
+        None => Ok(None),
+    }
+}
+
+async fn connect_with_timeout(
+    to: &str,
+    port: u16,
+    bind_addr: Option<SocketAddr>,
+    connect_timeout: Duration,
+) -> Result<TcpStream> {
+    let connect = async {
+        match bind_addr {
+            Some(bind_addr) => connect_from(bind_addr, to, port).await,
+            None => connect_any(to, port).await,
+        }
+    };
+    match timeout(connect_timeout, connect).await {
         Ok(res) => res,
         Err(err) => Err(err.into()),
     }
+    .map_err(ClientError::Connect)
     .with_context(|| format!("could not connect to {to}:{port}"))
 }
+
+/// Connect to the first address of a host that answers, racing them as in RFC 8305.
+///
+/// Addresses are tried in turn, alternating between IPv6 and IPv4, and each gets a head
+/// start before the next is tried alongside it. A family that cannot be reached then
+/// costs a short delay rather than a timeout.
+async fn connect_any(to: &str, port: u16) -> io::Result<TcpStream> {
+    let mut addrs = interleave_families(lookup_host((to, port)).await?.collect()).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    attempts.extend(addrs.next().map(TcpStream::connect));
+    let mut last_err = None;
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                // A failure starts the next attempt without waiting for its turn.
+                Err(err) => {
+                    last_err = Some(err);
+                    attempts.extend(addrs.next().map(TcpStream::connect));
+                }
+            },
+            () = sleep(CONNECTION_ATTEMPT_DELAY), if !addrs.as_slice().is_empty() => {
+                attempts.extend(addrs.next().map(TcpStream::connect));
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        let message = format!("{to} has no addresses");
+        io::Error::new(io::ErrorKind::NotFound, message)
+    }))
+}
+
+/// Order addresses to alternate between families, starting with the family of the first.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(first.len() + other.len());
+    let (mut first, mut other) = (first.into_iter(), other.into_iter());
+    loop {
+        match (first.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect from a local address to the first reachable address of the same family.
+async fn connect_from(bind_addr: SocketAddr, to: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in lookup_host((to, port)).await? {
+        if addr.is_ipv4() != bind_addr.is_ipv4() {
+            continue;
+        }
+        let socket = match bind_addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if bind_addr.port() != 0 {
+            // Allow connections to different destinations from the same port.
+            socket.set_reuseaddr(true)?;
+        }
+        socket.bind(bind_addr)?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        let message = format!("{to} has no address of the same family as {bind_addr}");
+        io::Error::new(io::ErrorKind::AddrNotAvailable, message)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a list of socket addresses.
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleave_mixed_families() {
+        let mixed = addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]);
+        let expected = addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]);
+        assert_eq!(interleave_families(mixed), expected);
+
+        let mixed = addrs(&["10.0.0.1:1", "10.0.0.2:1", "[::1]:1"]);
+        let expected = addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"]);
+        assert_eq!(interleave_families(mixed), expected);
+    }
+
+    #[test]
+    fn interleave_single_family() {
+        let single = addrs(&["10.0.0.2:1", "10.0.0.1:1", "10.0.0.3:1"]);
+        assert_eq!(interleave_families(single.clone()), single);
+    }
+
+    #[test]
+    fn interleave_empty() {
+        assert!(interleave_families(Vec::new()).is_empty());
+    }
+}
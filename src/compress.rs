@@ -0,0 +1,311 @@
+//! Compression of the data forwarded on a tunnel, as a raw deflate stream each way.
+//!
+//! Each side compresses what it writes and decompresses what it reads, so the
+//! targets see the original bytes. Data that barely shrinks, such as TLS or media
+//! that is already compressed, is sent in stored blocks once this is noticed.
+
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use miniz_oxide::deflate::core::CompressorOxide;
+use miniz_oxide::deflate::{stream::deflate, CompressionLevel};
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+
+/// Size of the buffers of compressed data in each direction.
+const BUFFER_LEN: usize = 16 * 1024;
+
+/// Bytes compressed before deciding whether the data is worth compressing.
+const SAMPLE_LEN: u64 = 64 * 1024;
+
+/// A stream that compresses what is written to it, and decompresses what is read.
+///
+/// Written data is buffered in the compressor until the stream is flushed.
+pub(crate) struct Compressed<S> {
+    inner: S,
+    encoder: Box<CompressorOxide>,
+
+    /// Compressed bytes to write to the inner stream, of which `written` are done.
+    output: Vec<u8>,
+    written: usize,
+
+    /// Whether data was compressed since the last flush.
+    dirty: bool,
+
+    /// Whether the end of the compressed stream was written.
+    finished: bool,
+
+    /// Bytes before and after compression, until the data is found to shrink or not.
+    sample: Option<(u64, u64)>,
+
+    decoder: Box<InflateState>,
+
+    /// Compressed bytes read from the inner stream, from `consumed` up to `filled`.
+    input: Vec<u8>,
+    consumed: usize,
+    filled: usize,
+
+    /// Whether the peer's compressed stream ended.
+    ended: bool,
+}
+
+impl<S> Compressed<S> {
+    /// Wrap a stream, given the compressed bytes that were already read from it.
+    pub fn new(inner: S, buffered: &[u8]) -> Self {
+        let mut encoder = Box::<CompressorOxide>::default();
+        encoder.set_format_and_level(DataFormat::Raw, CompressionLevel::DefaultLevel as u8);
+        let mut input = vec![0; BUFFER_LEN.max(buffered.len())];
+        input[..buffered.len()].copy_from_slice(buffered);
+        Compressed {
+            inner,
+            encoder,
+            output: Vec::with_capacity(BUFFER_LEN),
+            written: 0,
+            dirty: false,
+            finished: false,
+            sample: Some((0, 0)),
+            decoder: InflateState::new_boxed(DataFormat::Raw),
+            input,
+            consumed: 0,
+            filled: buffered.len(),
+            ended: false,
+        }
+    }
+
+    /// Compress data into the output buffer, which must be empty, returning the bytes
+    /// consumed and whether the compressed stream is done.
+    fn compress(&mut self, data: &[u8], flush: MZFlush) -> io::Result<(usize, bool)> {
+        self.output.resize(BUFFER_LEN, 0);
+        let result = deflate(&mut self.encoder, data, &mut self.output, flush);
+        self.output.truncate(result.bytes_written);
+        if let Some((plain, compressed)) = &mut self.sample {
+            *plain += result.bytes_consumed as u64;
+            *compressed += result.bytes_written as u64;
+        }
+        match result.status {
+            Ok(status) => Ok((result.bytes_consumed, status == MZStatus::StreamEnd)),
+            // Nothing was left to compress.
+            Err(MZError::Buf) => Ok((result.bytes_consumed, false)),
+            Err(err) => Err(io::Error::other(format!("compression failed: {err:?}"))),
+        }
+    }
+
+    /// Stop compressing once enough data has been seen, if it did not shrink by a tenth.
+    fn check_sample(&mut self) {
+        let Some((plain, compressed)) = self.sample else {
+            return;
+        };
+        if plain < SAMPLE_LEN {
+            return;
+        }
+        self.sample = None;
+        if compressed * 10 > plain * 9 {
+            info!(
+                plain,
+                compressed, "data does not compress, sending it as is"
+            );
+            self.encoder
+                .set_compression_level(CompressionLevel::NoCompression);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Compressed<S> {
+    /// Write out the compressed bytes that are waiting.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.output.len() {
+            let pending = &self.output[self.written..];
+            let bytes = ready!(Pin::new(&mut self.inner).poll_write(cx, pending))?;
+            if bytes == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += bytes;
+        }
+        self.output.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Compressed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.ended || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let data = &this.input[this.consumed..this.filled];
+            let result = inflate(
+                &mut this.decoder,
+                data,
+                buf.initialize_unfilled(),
+                MZFlush::None,
+            );
+            this.consumed += result.bytes_consumed;
+            buf.advance(result.bytes_written);
+            match result.status {
+                Ok(MZStatus::StreamEnd) => this.ended = true,
+                // More input is needed to make progress.
+                Ok(_) | Err(MZError::Buf) => (),
+                Err(err) => {
+                    let message = format!("invalid compressed data: {err:?}");
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, message)));
+                }
+            }
+            if result.bytes_written > 0 || this.ended {
+                return Poll::Ready(Ok(()));
+            }
+            if result.bytes_consumed > 0 {
+                continue;
+            }
+
+            this.input.copy_within(this.consumed..this.filled, 0);
+            this.filled -= this.consumed;
+            this.consumed = 0;
+            if this.filled == this.input.len() {
+                let message = "compressed data makes no progress";
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, message)));
+            }
+            let mut read_buf = ReadBuf::new(&mut this.input[this.filled..]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            let bytes = read_buf.filled().len();
+            if bytes == 0 {
+                // A peer that closes without ending the stream is taken as a plain EOF.
+                this.ended = true;
+                return Poll::Ready(Ok(()));
+            }
+            this.filled += bytes;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Compressed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            ready!(this.poll_drain(cx))?;
+            let (consumed, _) = this.compress(buf, MZFlush::None)?;
+            if consumed > 0 {
+                this.dirty = true;
+                return Poll::Ready(Ok(consumed));
+            }
+            if this.output.is_empty() {
+                let message = "compressor makes no progress";
+                return Poll::Ready(Err(io::Error::other(message)));
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.dirty {
+            ready!(this.poll_drain(cx))?;
+            this.compress(&[], MZFlush::Sync)?;
+            // The flush is complete once its output fits in the buffer.
+            if this.output.len() < BUFFER_LEN {
+                this.dirty = false;
+                this.check_sample();
+            }
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while !this.finished {
+            ready!(this.poll_drain(cx))?;
+            (_, this.finished) = this.compress(&[], MZFlush::Finish)?;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::core::deflate_flags;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() -> io::Result<()> {
+        let (client, server) = io::duplex(1024);
+        let (mut client, mut server) = (Compressed::new(client, &[]), Compressed::new(server, &[]));
+        let text = b"hello, hello, hello, hello!".repeat(1000);
+
+        let write = async {
+            client.write_all(&text).await?;
+            client.flush().await?;
+            client.write_all(b"bye").await?;
+            client.shutdown().await
+        };
+        let mut received = Vec::new();
+        let (written, read) = tokio::join!(write, server.read_to_end(&mut received));
+        written?;
+        read?;
+        assert_eq!(received, [&text[..], b"bye"].concat());
+
+        // The other direction still works after the first one ended.
+        server.write_all(b"reply").await?;
+        server.shutdown().await?;
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await?;
+        assert_eq!(reply, b"reply");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_sends_data() -> io::Result<()> {
+        let (client, mut raw) = io::duplex(1024);
+        let mut client = Compressed::new(client, &[]);
+        client.write_all(&[b'a'; 4096]).await?;
+        client.flush().await?;
+
+        // The repeated bytes shrink, and can be decompressed before the stream ends.
+        let mut compressed = [0; 1024];
+        let len = raw.read(&mut compressed).await?;
+        assert!(len < 100, "{len}");
+        let mut server = Compressed::new(raw, &compressed[..len]);
+        let mut buf = [0; 4096];
+        server.read_exact(&mut buf).await?;
+        assert_eq!(buf, [b'a'; 4096]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incompressible_data_is_stored() -> io::Result<()> {
+        let (client, server) = io::duplex(64 * 1024);
+        let mut client = Compressed::new(client, &[]);
+        let noise: Vec<u8> = (0..4 * SAMPLE_LEN).map(|_| fastrand::u8(..)).collect();
+        let read = tokio::spawn(async move {
+            let mut server = Compressed::new(server, &[]);
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.map(|_| received)
+        });
+        for chunk in noise.chunks(8192) {
+            client.write_all(chunk).await?;
+            client.flush().await?;
+        }
+        assert!(client.sample.is_none());
+        let raw = deflate_flags::TDEFL_FORCE_ALL_RAW_BLOCKS as i32;
+        assert_ne!(client.encoder.flags() & raw, 0);
+        client.shutdown().await?;
+        assert_eq!(read.await.unwrap()?, noise);
+        Ok(())
+    }
+}
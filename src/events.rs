@@ -0,0 +1,163 @@
+//! Structured events emitted by the server, for machine consumption.
+//!
+//! Unlike log messages, events have a stable schema. Each event serializes to a
+//! single JSON object with a `timestamp` in milliseconds since the Unix epoch, an
+//! `event` name, and the fields of that event:
+//!
+//! | `event`                    | Fields                                   |
+//! | -------------------------- | ---------------------------------------- |
+//! | `listener_created`         | `port`, `remote_addr`, `udp`             |
+//! | `listener_aborted`         | `port`, `remote_addr`                    |
+//! | `connection_accepted`      | `port`, `peer_addr`, `id`                |
+//! | `connection_forwarded`     | `id`                                     |
+//! | `connection_closed`        | `id`, `bytes_to_peer`, `bytes_from_peer` |
+//! | `stale_connection_removed` | `id`                                     |
+//! | `auth_failed`              | `remote_addr`, `reason`                  |
+//!
+//! Here `remote_addr` is the address of the client's control connection, which is
+//! `null` for clients on a Unix socket, and `peer_addr` is the address of the
+//! remote peer that connected to a tunnel.
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+/// An event that happened on the server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    /// Time of the event, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// What happened.
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// The kind of an [`Event`], with its fields.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum EventKind {
+    /// A tunnel started listening on a public port.
+    ListenerCreated {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Address of the client that requested the tunnel.
+        remote_addr: Option<SocketAddr>,
+        /// Whether the tunnel forwards UDP instead of TCP.
+        udp: bool,
+    },
+
+    /// A tunnel stopped listening before its client disconnected.
+    ///
+    /// This happens when the client reconnects and requests the same port again,
+    /// or when the tunnel was idle for too long.
+    ListenerAborted {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Address of the client that requested the tunnel.
+        remote_addr: Option<SocketAddr>,
+    },
+
+    /// A remote peer connected to a tunnel, and the client was asked to accept it.
+    ConnectionAccepted {
+        /// Public port of the tunnel.
+        port: u16,
+        /// Address of the remote peer.
+        peer_addr: SocketAddr,
+        /// ID of the pending connection.
+        id: Uuid,
+    },
+
+    /// The client accepted a connection, which is now being forwarded.
+    ConnectionForwarded {
+        /// ID of the connection.
+        id: Uuid,
+    },
+
+    /// A forwarded connection or UDP session was closed, normally or after an error.
+    ConnectionClosed {
+        /// ID of the connection.
+        id: Uuid,
+        /// Bytes sent to the remote peer.
+        bytes_to_peer: u64,
+        /// Bytes received from the remote peer.
+        bytes_from_peer: u64,
+    },
+
+    /// A connection was closed because the client did not accept it in time.
+    StaleConnectionRemoved {
+        /// ID of the connection.
+        id: Uuid,
+    },
+
+    /// A client failed the authentication handshake.
+    AuthFailed {
+        /// Address of the client.
+        remote_addr: Option<SocketAddr>,
+        /// Why authentication failed.
+        reason: String,
+    },
+}
+
+impl Event {
+    /// Create an event that happened now.
+    pub fn now(kind: EventKind) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        Event { timestamp, kind }
+    }
+}
+
+/// Number of events a subscriber can fall behind by before it misses some.
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Destinations of the events emitted by a server.
+#[derive(Clone)]
+pub(crate) struct EventSinks {
+    /// Channel that receives every event, if any.
+    pub sink: Option<mpsc::UnboundedSender<Event>>,
+
+    /// Channel of subscribers that may lag behind and miss events.
+    pub subscribers: broadcast::Sender<Event>,
+}
+
+impl EventSinks {
+    /// Create sinks with no channel and no subscribers.
+    pub fn new() -> Self {
+        EventSinks {
+            sink: None,
+            subscribers: broadcast::channel(SUBSCRIBER_CAPACITY).0,
+        }
+    }
+
+    /// Send an event to the channel and to every subscriber.
+    pub fn send(&self, event: Event) {
+        if self.subscribers.receiver_count() > 0 {
+            let _ = self.subscribers.send(event.clone());
+        }
+        if let Some(sink) = &self.sink {
+            let _ = sink.send(event);
+        }
+    }
+}
+
+/// Write events from a channel as JSON lines, until every sender is dropped.
+///
+/// This blocks the current thread, so it should be run on a dedicated thread.
+pub fn write_json_lines(
+    mut events: mpsc::UnboundedReceiver<Event>,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    while let Some(event) = events.blocking_recv() {
+        serde_json::to_writer(&mut writer, &event)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+    Ok(())
+}
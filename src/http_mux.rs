@@ -0,0 +1,54 @@
+//! Routing of HTTP connections on a shared port to named tunnels.
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Maximum byte length of an HTTP request head that is read to find the host.
+const MAX_HEAD_LENGTH: usize = 8192;
+
+/// Returns whether a name can be requested for a tunnel, as a single DNS label.
+pub(crate) fn valid_name(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// Read the head of an HTTP request, returning every byte read so far.
+///
+/// The caller must replay these bytes to the tunnel, as they may also include the
+/// start of the request body.
+pub(crate) async fn read_head(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LENGTH {
+            bail!("request head too long");
+        }
+        if stream.read_buf(&mut head).await? == 0 {
+            bail!("incomplete request");
+        }
+    }
+    Ok(head)
+}
+
+/// Extract the tunnel name from the `Host` header, which is its first label.
+///
+/// For example, `myapp.bore.example.com:8080` is routed to the tunnel `myapp`.
+pub(crate) fn host_name(head: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(head);
+    let host = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("host").then(|| value.trim())
+    })?;
+    let label = host.split(['.', ':']).next()?;
+    Some(label.to_ascii_lowercase())
+}
+
+/// Reply with an error status and close the connection.
+pub(crate) async fn respond_error(stream: &mut TcpStream, status: &str) {
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    let _ = stream.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,184 @@
+//! Connections to the server through an HTTP proxy, using the `CONNECT` method.
+
+use std::env;
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+/// Longest response head accepted from the proxy.
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+/// An HTTP proxy that tunnels TCP connections with `CONNECT` requests.
+#[derive(Clone, Debug)]
+pub(crate) struct HttpProxy {
+    /// Host name or IP address of the proxy.
+    pub host: String,
+
+    /// Port of the proxy.
+    pub port: u16,
+
+    /// Base64 of `user:password` for basic authentication, if given in the URL.
+    credentials: Option<String>,
+}
+
+impl HttpProxy {
+    /// Parse a proxy from a URL of the form `http://[user[:password]@]host[:port][/]`.
+    ///
+    /// The port defaults to 80, and the user and password may be percent-encoded.
+    pub fn new(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("proxy URL {url:?} must start with http://");
+        };
+        let authority = rest.strip_suffix('/').unwrap_or(rest);
+        ensure!(
+            !authority.contains('/'),
+            "proxy URL {url:?} must not have a path"
+        );
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(userinfo), host_port),
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("proxy URL {url:?} has an invalid port"))?;
+                (host, port)
+            }
+            _ => (host_port, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        ensure!(!host.is_empty(), "proxy URL {url:?} has no host");
+        let credentials = match userinfo {
+            Some(userinfo) => {
+                let decoded = percent_decode(userinfo)
+                    .with_context(|| format!("proxy URL {url:?} has invalid credentials"))?;
+                Some(base64(&decoded))
+            }
+            None => None,
+        };
+        Ok(HttpProxy {
+            host: host.into(),
+            port,
+            credentials,
+        })
+    }
+
+    /// Find the proxy to reach a host from the environment, if there is one.
+    ///
+    /// This reads `HTTP_PROXY`, then `ALL_PROXY`, in either case, skipping hosts
+    /// listed in `NO_PROXY`. Proxies that are not valid HTTP proxy URLs are ignored
+    /// with a warning, as `ALL_PROXY` often names a SOCKS proxy.
+    pub fn from_env(host: &str) -> Option<Self> {
+        let var = |name: &str| {
+            env::var(name)
+                .or_else(|_| env::var(name.to_lowercase()))
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        if var("NO_PROXY").is_some_and(|no_proxy| bypass(&no_proxy, host)) {
+            return None;
+        }
+        let url = var("HTTP_PROXY").or_else(|| var("ALL_PROXY"))?;
+        match HttpProxy::new(&url) {
+            Ok(proxy) => Some(proxy),
+            Err(err) => {
+                warn!(%err, "ignoring proxy from environment");
+                None
+            }
+        }
+    }
+
+    /// Ask the proxy to open a tunnel to a destination, on a stream connected to it.
+    ///
+    /// Once this returns, the stream carries data to and from the destination.
+    pub async fn connect<S>(&self, stream: &mut S, to: &str, port: u16) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let target = if to.contains(':') {
+            format!("[{to}]:{port}")
+        } else {
+            format!("{to}:{port}")
+        };
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(credentials) = &self.credentials {
+            request += &format!("Proxy-Authorization: Basic {credentials}\r\n");
+        }
+        request += "\r\n";
+        stream.write_all(request.as_bytes()).await?;
+
+        let head = read_head(stream).await?;
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some("407") => bail!("proxy requires authentication"),
+            Some(_) => bail!("proxy refused to connect to {target}: {status_line}"),
+            None => bail!("invalid response from proxy"),
+        }
+    }
+}
+
+/// Read the head of the proxy's response, without reading past it.
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        ensure!(head.len() < MAX_RESPONSE_HEAD, "proxy response too long");
+        let byte = stream
+            .read_u8()
+            .await
+            .context("proxy closed the connection")?;
+        head.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Whether a host is excluded from proxying by a `NO_PROXY` list.
+///
+/// Entries match the host itself and its subdomains, and `*` matches every host.
+fn bypass(no_proxy: &str, host: &str) -> bool {
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        let entry = entry.trim_start_matches('.');
+        entry == "*"
+            || (!entry.is_empty()
+                && (host.eq_ignore_ascii_case(entry)
+                    || host
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", entry.to_ascii_lowercase()))))
+    })
+}
+
+/// Decode `%XX` escapes in a URL component.
+fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            output.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            output.push(byte);
+        }
+    }
+    Some(output)
+}
+
+/// Encode bytes as standard base64, with padding.
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
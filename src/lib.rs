@@ -15,7 +15,23 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod admin;
 pub mod auth;
+mod balance;
+pub mod cidr;
 pub mod client;
+mod compress;
+pub mod events;
+mod http_mux;
+mod http_proxy;
+pub mod memory;
+mod metrics;
+pub mod proxy_protocol;
+mod rate_limit;
+mod secrets;
 pub mod server;
 pub mod shared;
+mod socks;
+mod srv;
+mod throttle;
+mod webhook;
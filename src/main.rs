@@ -1,8 +1,25 @@
-use std::net::IpAddr;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::{env, process};
 
-use anyhow::Result;
-use bore_cli::{client::Client, server::Server};
+use anyhow::{ensure, Context, Result};
+use bore_cli::admin;
+use bore_cli::cidr::IpNet;
+use bore_cli::client::{Balance, Client, ReconnectPolicy};
+use bore_cli::events;
+use bore_cli::proxy_protocol::ProxyProtocol;
+use bore_cli::server::{AllocationStrategy, Keepalive, PortTakeover, Server};
+use bore_cli::shared::CONTROL_PORT;
 use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use tokio::sync::mpsc;
+use tracing::{error, info};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -12,12 +29,20 @@ struct Args {
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)] // parsed once at startup
 enum Command {
     /// Starts a local proxy to the remote server.
     Local {
         /// The local port to expose.
-        #[clap(env = "BORE_LOCAL_PORT")]
-        local_port: u16,
+        #[cfg_attr(
+            unix,
+            clap(env = "BORE_LOCAL_PORT", required_unless_present_any = ["socks", "local_socket", "local_srv"])
+        )]
+        #[cfg_attr(
+            not(unix),
+            clap(env = "BORE_LOCAL_PORT", required_unless_present_any = ["socks", "local_srv"])
+        )]
+        local_port: Option<u16>,
 
         /// The local host to expose.
         #[clap(short, long, value_name = "HOST", default_value = "localhost")]
@@ -34,6 +59,142 @@ enum Command {
         /// Optional secret for authentication.
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
         secret: Option<String>,
+
+        /// Port of the control server, which the other side must use too.
+        #[clap(long, value_name = "PORT", default_value_t = CONTROL_PORT)]
+        control_port: u16,
+
+        /// Forward UDP datagrams instead of TCP connections.
+        #[clap(long)]
+        udp: bool,
+
+        /// Request an HTTP tunnel by this name, routed by the `Host` header on the server.
+        #[clap(long, conflicts_with_all = ["port", "udp"])]
+        name: Option<String>,
+
+        /// Serve SOCKS5 requests on the tunnel, connecting each to the destination it asks for.
+        #[clap(long, conflicts_with_all = ["local_port", "udp", "name", "forward"])]
+        socks: bool,
+
+        /// Path of a local Unix socket to expose, instead of a local port.
+        #[cfg(unix)]
+        #[clap(long, value_name = "PATH", conflicts_with_all = ["local_port", "udp", "socks", "forward"])]
+        local_socket: Option<PathBuf>,
+
+        /// DNS name whose SRV records give the local target, looked up for each connection.
+        #[clap(long, value_name = "NAME", conflicts_with_all = ["local_port", "udp", "socks", "local_socket", "forward", "local_target", "balance"])]
+        local_srv: Option<String>,
+
+        /// Nameserver for --local-srv lookups, defaults to the first in /etc/resolv.conf.
+        #[clap(long, value_name = "ADDR", requires = "local_srv")]
+        srv_nameserver: Option<SocketAddr>,
+
+        /// Another local port to expose over the same connection, with an optional remote port.
+        /// Can be repeated.
+        #[clap(long, value_name = "LOCAL_PORT[:PORT]", value_parser = parse_forward)]
+        forward: Vec<(u16, u16)>,
+
+        /// Another local host and port to spread connections across, as `HOST:PORT`.
+        /// Can be repeated.
+        #[clap(long, value_name = "HOST:PORT", value_parser = parse_target, conflicts_with_all = ["udp", "socks", "local_socket"])]
+        local_target: Vec<(String, u16)>,
+
+        /// How to spread connections across local targets, `round-robin` or `least-connections`.
+        #[clap(
+            long,
+            value_name = "BALANCE",
+            default_value = "round-robin",
+            requires = "local_target"
+        )]
+        balance: Balance,
+
+        /// Compress the tunnel's connections, if the server allows it with --compression.
+        #[clap(long, conflicts_with = "udp")]
+        compress: bool,
+
+        /// Reconnect with exponential backoff when the connection to the server is lost.
+        #[clap(long)]
+        reconnect: bool,
+
+        /// Retry with exponential backoff while the server is unreachable at startup.
+        #[clap(long)]
+        retry_startup: bool,
+
+        /// Seconds to wait for the local target to accept connections before connecting.
+        #[clap(long, value_name = "SECS")]
+        wait_for_local: Option<u64>,
+
+        /// Retry each connection to the local target this many times when it fails.
+        #[clap(long, value_name = "N")]
+        local_retries: Option<u32>,
+
+        /// Milliseconds before the first retry of a local connection, doubling after each up to 2s.
+        #[clap(
+            long,
+            value_name = "MS",
+            default_value_t = 100,
+            requires = "local_retries"
+        )]
+        local_retry_delay: u64,
+
+        /// Exit after the first forwarded connection closes.
+        #[clap(long)]
+        one_shot: bool,
+
+        /// Seconds to wait for each connection to the server to open.
+        #[clap(long, value_name = "SECS", default_value_t = 3)]
+        connect_timeout: u64,
+
+        /// Seconds without a heartbeat before the connection to the server is considered lost.
+        #[clap(long, value_name = "SECS", default_value_t = 30)]
+        heartbeat_timeout: u64,
+
+        /// Log the address of the remote peer of each connection, if the server supports it.
+        #[clap(long)]
+        log_peers: bool,
+
+        /// Log the connections and bytes of each tunnel when they change, if the server reports them.
+        #[clap(long)]
+        show_status: bool,
+
+        /// Local address to connect to the server and local services from, such as `10.0.0.2:0`.
+        #[clap(long, value_name = "ADDR")]
+        local_bind_addr: Option<SocketAddr>,
+
+        /// HTTP proxy to reach the server through, defaults to HTTP_PROXY or ALL_PROXY.
+        #[clap(long, value_name = "URL")]
+        http_proxy: Option<String>,
+
+        /// SOCKS5 proxy to reach the server through, as HOST:PORT.
+        #[clap(long, value_name = "ADDR", conflicts_with = "http_proxy")]
+        socks5_proxy: Option<String>,
+
+        /// User and password for the SOCKS5 proxy, as USER:PASSWORD.
+        #[clap(
+            long,
+            value_name = "CREDENTIALS",
+            env = "BORE_SOCKS5_AUTH",
+            hide_env_values = true,
+            requires = "socks5_proxy"
+        )]
+        socks5_auth: Option<String>,
+
+        /// Ask the server to hold TCP ports for a reconnect with this token, keep it secret.
+        #[clap(
+            long,
+            value_name = "TOKEN",
+            env = "BORE_RESERVATION_TOKEN",
+            hide_env_values = true
+        )]
+        reservation_token: Option<String>,
+
+        /// Label that the server logs with this client's tunnels, such as `ci-runner-7`.
+        #[clap(long, env = "BORE_LABEL")]
+        label: Option<String>,
+
+        /// IP address of the server to bind the tunnels to, if the server allows it.
+        #[clap(long, value_name = "IP")]
+        bind_tunnels: Option<IpAddr>,
     },
 
     /// Runs the remote proxy server.
@@ -50,13 +211,281 @@ enum Command {
         #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
         secret: Option<String>,
 
+        /// File of additional secrets, each limited to a port range, as `MIN-MAX SECRET` lines.
+        /// Reloaded when it changes.
+        #[clap(long, value_name = "PATH")]
+        secrets_file: Option<PathBuf>,
+
         /// IP address to bind to, clients must reach this.
         #[clap(long, default_value = "0.0.0.0")]
         bind_addr: IpAddr,
 
-        /// IP address where tunnels will listen on, defaults to --bind-addr.
+        /// Bind the control port to loopback and tunnels to all interfaces, and require a secret.
+        #[clap(long, conflicts_with_all = ["bind_addr", "bind_tunnels"])]
+        secure_defaults: bool,
+
+        /// Port of the control server, which the other side must use too.
+        #[clap(long, value_name = "PORT", default_value_t = CONTROL_PORT)]
+        control_port: u16,
+
+        /// Public hostname of the server, which clients print with their port.
+        #[clap(long, value_name = "HOST")]
+        advertised_host: Option<String>,
+
+        /// IP address where tunnels will listen on, defaults to --bind-addr. Can be repeated.
         #[clap(long)]
-        bind_tunnels: Option<IpAddr>,
+        bind_tunnels: Vec<IpAddr>,
+
+        /// IP address that clients may ask their tunnels to listen on instead. Can be repeated.
+        #[clap(long, value_name = "IP")]
+        client_bind_tunnels: Vec<IpAddr>,
+
+        /// Maximum number of outstanding connections on each tunnel.
+        #[clap(long, value_name = "N")]
+        max_conns_per_port: Option<usize>,
+
+        /// Queue this many connections on each tunnel until the client accepts them.
+        #[clap(long, value_name = "N")]
+        pending_queue_depth: Option<usize>,
+
+        /// Maximum number of connections waiting for a client, across all tunnels.
+        #[clap(long, value_name = "N")]
+        max_pending_connections: Option<usize>,
+
+        /// Maximum number of tunnels open at once, defaults to the size of the port range.
+        #[clap(long, value_name = "N")]
+        max_tunnels: Option<usize>,
+
+        /// Warn when tunnels take more than this percentage of the port range.
+        #[clap(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+        warn_port_utilization: Option<u8>,
+
+        /// Seconds an incoming connection waits for the client to accept it, or 0 to wait
+        /// until the server shuts down.
+        #[clap(long, value_name = "SECS", default_value_t = 10)]
+        accept_timeout: u64,
+
+        /// File with a message to write to connections that no client accepts in time.
+        #[clap(long, value_name = "PATH")]
+        unavailable_banner: Option<PathBuf>,
+
+        /// Milliseconds between heartbeats sent to each client.
+        #[clap(
+            long,
+            value_name = "MILLIS",
+            default_value_t = 500,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        heartbeat_interval: u64,
+
+        /// Seconds a tunnel may go without connections before its port is reclaimed.
+        #[clap(long, value_name = "SECS")]
+        idle_tunnel_timeout: Option<u64>,
+
+        /// Seconds after which a tunnel is closed, so that its client reconnects.
+        #[clap(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        max_lifetime: Option<u64>,
+
+        /// Seconds without any tunnels after which the server exits.
+        #[clap(long, value_name = "SECS")]
+        exit_when_idle: Option<u64>,
+
+        /// Seconds the server runs before it may exit for being idle.
+        #[clap(long, value_name = "SECS", requires = "exit_when_idle")]
+        min_uptime: Option<u64>,
+
+        /// Seconds to hold the TCP tunnels of clients with a reservation token after they disconnect.
+        #[clap(long, value_name = "SECS")]
+        reservation_grace: Option<u64>,
+
+        /// Seconds a forwarded connection may go without transferring data before it is closed.
+        #[clap(long, value_name = "SECS")]
+        connection_idle_timeout: Option<u64>,
+
+        /// Number of random ports to try when a client requests any available port.
+        #[clap(long, value_name = "N", default_value_t = 150)]
+        port_probe_attempts: usize,
+
+        /// How to choose a port when the client requests any port: "random" or "sequential".
+        #[clap(long, value_name = "STRATEGY", default_value = "random")]
+        allocation_strategy: AllocationStrategy,
+
+        /// Who may take over a requested port that is in use: "reject", "takeover-same-addr"
+        /// or "takeover-any".
+        #[clap(long, value_name = "POLICY", default_value = "takeover-same-addr")]
+        port_takeover: PortTakeover,
+
+        /// Range of ports to assign first when the client requests any port, as `MIN-MAX`.
+        #[clap(long, value_name = "MIN-MAX", value_parser = parse_port_range)]
+        preferred_ports: Option<RangeInclusive<u16>>,
+
+        /// Only accept clients from this network, in CIDR notation. Can be repeated.
+        #[clap(long, value_name = "CIDR")]
+        allow_ip: Vec<IpNet>,
+
+        /// Refuse clients from this network, in CIDR notation. Can be repeated.
+        #[clap(long, value_name = "CIDR")]
+        deny_ip: Vec<IpNet>,
+
+        /// Maximum sustained rate of new control connections from each IP address.
+        #[clap(long, value_name = "PER_SEC")]
+        control_rate_limit: Option<u32>,
+
+        /// Number of control connections an IP address can open at once, defaults to the rate.
+        #[clap(long, value_name = "N", requires = "control_rate_limit")]
+        control_rate_burst: Option<u32>,
+
+        /// Maximum sustained rate of new connections on each tunnel.
+        #[clap(long, value_name = "PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+        tunnel_rate_limit: Option<u32>,
+
+        /// Number of connections a tunnel can accept at once, defaults to the rate.
+        #[clap(long, value_name = "N", requires = "tunnel_rate_limit", value_parser = clap::value_parser!(u32).range(1..))]
+        tunnel_rate_burst: Option<u32>,
+
+        /// Maximum bandwidth of each TCP tunnel, in bytes per second.
+        #[clap(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u64).range(1..))]
+        bandwidth_limit: Option<u64>,
+
+        /// Number of bytes a tunnel can transfer at once, defaults to the bandwidth limit.
+        #[clap(long, value_name = "BYTES", requires = "bandwidth_limit")]
+        bandwidth_burst: Option<u64>,
+
+        /// Send a PROXY protocol header of this version ahead of each connection: "v1" or "v2".
+        #[clap(long, value_name = "VERSION")]
+        proxy_protocol: Option<ProxyProtocol>,
+
+        /// File to append structured JSON events to, one per line, or `-` for stdout.
+        #[clap(long, value_name = "PATH")]
+        event_log: Option<PathBuf>,
+
+        /// Port to route HTTP connections on to named tunnels, by their `Host` header.
+        #[clap(long, value_name = "PORT")]
+        http_port: Option<u16>,
+
+        /// HTTP endpoint to post tunnel events to, as JSON.
+        #[clap(long, value_name = "URL")]
+        webhook: Option<String>,
+
+        /// Address to serve Prometheus metrics on, at the `/metrics` path.
+        #[clap(long, value_name = "ADDR")]
+        metrics_addr: Option<SocketAddr>,
+
+        /// Address to serve a health check for load balancers on, at the `/health` path.
+        #[clap(long, value_name = "ADDR")]
+        health_addr: Option<SocketAddr>,
+
+        /// Path of a Unix socket to accept control connections on, instead of TCP.
+        #[cfg(unix)]
+        #[clap(long, value_name = "PATH")]
+        control_socket: Option<PathBuf>,
+
+        /// Network interface to restrict tunnels to, such as `eth0`.
+        #[cfg(target_os = "linux")]
+        #[clap(long, value_name = "NAME")]
+        bind_interface: Option<String>,
+
+        /// Bind the control port with SO_REUSEPORT, so that several servers can share it.
+        #[cfg(unix)]
+        #[clap(long)]
+        reuse_port: bool,
+
+        /// Enable admin commands, such as `bore admin list`, for clients with this secret.
+        #[clap(
+            long,
+            value_name = "SECRET",
+            env = "BORE_ADMIN_SECRET",
+            hide_env_values = true
+        )]
+        admin_secret: Option<String>,
+
+        /// Set TCP_NODELAY on accepted connections, sending small writes right away.
+        #[clap(long)]
+        nodelay: bool,
+
+        /// Seconds a connection is idle before TCP keepalive probes are sent.
+        #[clap(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        keepalive: Option<u64>,
+
+        /// Seconds between TCP keepalive probes.
+        #[clap(long, value_name = "SECS", requires = "keepalive", value_parser = clap::value_parser!(u64).range(1..))]
+        keepalive_interval: Option<u64>,
+
+        /// Number of unanswered TCP keepalive probes before a connection is dropped.
+        #[clap(long, value_name = "COUNT", requires = "keepalive", value_parser = clap::value_parser!(u32).range(1..))]
+        keepalive_count: Option<u32>,
+
+        /// Let clients compress the data of their connections with --compress.
+        #[clap(long)]
+        compression: bool,
+
+        /// Seconds to let forwarded connections finish on SIGTERM or SIGINT, before closing them.
+        #[clap(long, value_name = "SECS", default_value_t = 10)]
+        drain_timeout: u64,
+
+        /// Check the configuration and exit, without starting the server.
+        #[clap(long)]
+        check: bool,
+    },
+
+    /// Runs admin commands against a server started with an admin secret.
+    Admin {
+        #[clap(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Lists the tunnels open on the server.
+    List {
+        /// Address of the server.
+        #[clap(short, long, env = "BORE_SERVER")]
+        to: String,
+
+        /// Port of the control server.
+        #[clap(long, value_name = "PORT", default_value_t = CONTROL_PORT)]
+        control_port: u16,
+
+        /// Secret for authentication, if the server requires one of every client.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// Admin secret of the server.
+        #[clap(
+            long,
+            value_name = "SECRET",
+            env = "BORE_ADMIN_SECRET",
+            hide_env_values = true
+        )]
+        admin_secret: String,
+    },
+
+    /// Closes the tunnels on a public port of the server.
+    Kill {
+        /// Public port of the tunnels to close.
+        port: u16,
+
+        /// Address of the server.
+        #[clap(short, long, env = "BORE_SERVER")]
+        to: String,
+
+        /// Port of the control server.
+        #[clap(long, value_name = "PORT", default_value_t = CONTROL_PORT)]
+        control_port: u16,
+
+        /// Secret for authentication, if the server requires one of every client.
+        #[clap(short, long, env = "BORE_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// Admin secret of the server.
+        #[clap(
+            long,
+            value_name = "SECRET",
+            env = "BORE_ADMIN_SECRET",
+            hide_env_values = true
+        )]
+        admin_secret: String,
     },
 }
 
@@ -69,33 +498,515 @@ async fn run(command: Command) -> Result<()> {
             to,
             port,
             secret,
+            control_port,
+            udp,
+            name,
+            socks,
+            #[cfg(unix)]
+            local_socket,
+            local_srv,
+            srv_nameserver,
+            forward,
+            local_target,
+            balance,
+            compress,
+            reconnect,
+            retry_startup,
+            wait_for_local,
+            local_retries,
+            local_retry_delay,
+            one_shot,
+            connect_timeout,
+            heartbeat_timeout,
+            log_peers,
+            show_status,
+            local_bind_addr,
+            http_proxy,
+            socks5_proxy,
+            socks5_auth,
+            reservation_token,
+            label,
+            bind_tunnels,
         } => {
-            let client = Client::new(&local_host, local_port, &to, port, secret.as_deref()).await?;
+            let local_port = local_port.unwrap_or_default(); // unused with --socks, --local-socket or --local-srv
+            let mut client =
+                Client::configure(&local_host, local_port, &to, port, secret.as_deref());
+            client.set_control_port(control_port);
+            client.set_udp(udp);
+            client.set_socks(socks);
+            #[cfg(unix)]
+            if let Some(path) = local_socket {
+                client.set_local_socket(path);
+            }
+            if let Some(name) = local_srv {
+                client.set_local_srv(&name);
+            }
+            if let Some(addr) = srv_nameserver {
+                client.set_srv_nameserver(addr);
+            }
+            if let Some(name) = name {
+                client.set_name(&name);
+            }
+            for (host, port) in local_target {
+                client.add_local_target(&host, port);
+            }
+            client.set_balance(balance);
+            client.set_compression(compress);
+            for (local_port, port) in forward {
+                if udp {
+                    client.add_udp_tunnel(&local_host, local_port, port);
+                } else {
+                    client.add_tunnel(&local_host, local_port, port);
+                }
+            }
+            if reconnect {
+                client.set_reconnect(ReconnectPolicy::default());
+            }
+            if retry_startup {
+                client.set_startup_retry(ReconnectPolicy::default());
+            }
+            if let Some(secs) = wait_for_local {
+                client.set_wait_for_local(Duration::from_secs(secs));
+            }
+            if let Some(retries) = local_retries {
+                client.set_local_retry(retries, Duration::from_millis(local_retry_delay));
+            }
+            client.set_one_shot(one_shot);
+            client.set_connect_timeout(Duration::from_secs(connect_timeout));
+            client.set_heartbeat_timeout(Duration::from_secs(heartbeat_timeout));
+            client.set_report_peers(log_peers);
+            if let Some(addr) = local_bind_addr {
+                client.set_local_bind_addr(addr);
+            }
+            if let Some(url) = http_proxy {
+                client.set_http_proxy(&url)?;
+            }
+            if let Some(addr) = socks5_proxy {
+                let credentials = match &socks5_auth {
+                    Some(auth) => match auth.split_once(':') {
+                        Some(credentials) => Some(credentials),
+                        None => Args::command()
+                            .error(ErrorKind::InvalidValue, "socks5 auth must be USER:PASSWORD")
+                            .exit(),
+                    },
+                    None => None,
+                };
+                client.set_socks5_proxy(&addr, credentials)?;
+            }
+            if let Some(token) = reservation_token {
+                client.set_reservation_token(&token);
+            }
+            if let Some(label) = label {
+                client.set_label(&label);
+            }
+            if let Some(addr) = bind_tunnels {
+                client.set_bind_tunnels(addr);
+            }
+            if show_status {
+                let mut status = client.watch_status();
+                tokio::spawn(async move {
+                    let mut shown = Vec::new();
+                    while status.changed().await.is_ok() {
+                        let tunnels = status.borrow_and_update().clone();
+                        for tunnel in tunnels.iter().filter(|tunnel| !shown.contains(*tunnel)) {
+                            let bytes = format_bytes(tunnel.bytes);
+                            let connections = tunnel.connections;
+                            info!(port = tunnel.port, "{connections} connections, {bytes}");
+                        }
+                        shown = tunnels;
+                    }
+                });
+            }
             client.listen().await?;
         }
         Command::Server {
             min_port,
             max_port,
             secret,
+            secrets_file,
             bind_addr,
+            secure_defaults,
+            control_port,
+            advertised_host,
             bind_tunnels,
+            client_bind_tunnels,
+            max_conns_per_port,
+            pending_queue_depth,
+            max_pending_connections,
+            max_tunnels,
+            warn_port_utilization,
+            accept_timeout,
+            unavailable_banner,
+            heartbeat_interval,
+            idle_tunnel_timeout,
+            max_lifetime,
+            exit_when_idle,
+            min_uptime,
+            reservation_grace,
+            connection_idle_timeout,
+            port_probe_attempts,
+            allocation_strategy,
+            port_takeover,
+            preferred_ports,
+            allow_ip,
+            deny_ip,
+            control_rate_limit,
+            control_rate_burst,
+            tunnel_rate_limit,
+            tunnel_rate_burst,
+            bandwidth_limit,
+            bandwidth_burst,
+            proxy_protocol,
+            event_log,
+            http_port,
+            webhook,
+            metrics_addr,
+            health_addr,
+            #[cfg(unix)]
+            control_socket,
+            #[cfg(target_os = "linux")]
+            bind_interface,
+            #[cfg(unix)]
+            reuse_port,
+            admin_secret,
+            nodelay,
+            keepalive,
+            keepalive_interval,
+            keepalive_count,
+            compression,
+            drain_timeout,
+            check,
         } => {
-            let port_range = min_port..=max_port;
-            if port_range.is_empty() {
+            let Ok(mut server) = Server::try_new(min_port..=max_port, secret.as_deref()) else {
                 Args::command()
                     .error(ErrorKind::InvalidValue, "port range is empty")
                     .exit();
+            };
+            if let Some(path) = secrets_file {
+                server.set_secrets_file(path)?;
+            }
+            if secure_defaults {
+                server.secure_defaults();
+            } else {
+                server.set_bind_addr(bind_addr);
+            }
+            server.set_control_port(control_port);
+            if let Some(host) = advertised_host {
+                server.set_advertised_host(host);
+            }
+            if bind_tunnels.is_empty() && !secure_defaults {
+                server.set_bind_tunnels(bind_addr);
+            }
+            for addr in bind_tunnels {
+                server.add_bind_tunnel(addr);
+            }
+            server.set_client_bind_tunnels(client_bind_tunnels);
+            if let Some(max_conns) = max_conns_per_port {
+                server.set_max_conns_per_port(max_conns);
+            }
+            if let Some(depth) = pending_queue_depth {
+                server.set_pending_queue_depth(depth);
+            }
+            if let Some(max_pending) = max_pending_connections {
+                server.set_max_pending_connections(max_pending);
+            }
+            if let Some(max_tunnels) = max_tunnels {
+                server.set_max_tunnels(max_tunnels);
+            }
+            if let Some(percent) = warn_port_utilization {
+                server.set_port_utilization_warning(f64::from(percent) / 100.0);
+            }
+            server.set_accept_timeout(
+                (accept_timeout > 0).then(|| Duration::from_secs(accept_timeout)),
+            );
+            if let Some(path) = unavailable_banner {
+                let banner = std::fs::read(&path)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                server.set_unavailable_banner(banner);
+            }
+            server.set_heartbeat_interval(Duration::from_millis(heartbeat_interval));
+            if let Some(secs) = idle_tunnel_timeout {
+                server.set_idle_tunnel_timeout(Duration::from_secs(secs));
+            }
+            if let Some(secs) = max_lifetime {
+                server.set_max_tunnel_lifetime(Duration::from_secs(secs));
+            }
+            if let Some(secs) = exit_when_idle {
+                server.set_exit_when_idle(Duration::from_secs(secs));
+            }
+            if let Some(secs) = min_uptime {
+                server.set_min_uptime(Duration::from_secs(secs));
+            }
+            if let Some(secs) = reservation_grace {
+                server.set_reservation_grace(Duration::from_secs(secs));
+            }
+            if let Some(secs) = connection_idle_timeout {
+                server.set_connection_idle_timeout(Duration::from_secs(secs));
+            }
+            server.set_port_probe_attempts(port_probe_attempts);
+            server.set_allocation_strategy(allocation_strategy);
+            server.set_port_takeover(port_takeover);
+            if let Some(port_range) = preferred_ports {
+                server.set_preferred_port_range(port_range);
+            }
+            server.set_ip_allowlist(allow_ip);
+            server.set_ip_denylist(deny_ip);
+            if let Some(per_sec) = control_rate_limit {
+                server.set_control_rate_limit(per_sec, control_rate_burst.unwrap_or(per_sec));
+            }
+            if let Some(per_sec) = tunnel_rate_limit {
+                server.set_tunnel_rate_limit(per_sec, tunnel_rate_burst.unwrap_or(per_sec));
+            }
+            if let Some(per_sec) = bandwidth_limit {
+                server.set_bandwidth_limit(per_sec, bandwidth_burst.unwrap_or(per_sec));
+            }
+            if let Some(version) = proxy_protocol {
+                server.set_proxy_protocol(version);
+            }
+            if let Some(path) = event_log {
+                let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+                    Box::new(io::stdout())
+                } else {
+                    let file = OpenOptions::new().create(true).append(true).open(&path);
+                    Box::new(file.with_context(|| format!("could not open {}", path.display()))?)
+                };
+                let (tx, rx) = mpsc::unbounded_channel();
+                thread::spawn(move || {
+                    if let Err(err) = events::write_json_lines(rx, writer) {
+                        error!(%err, "failed to write event log");
+                    }
+                });
+                server.set_event_sink(tx);
+            }
+            if let Some(port) = http_port {
+                server.set_http_port(port);
+            }
+            if let Some(url) = webhook {
+                server.set_webhook(&url)?;
+            }
+            if let Some(addr) = metrics_addr {
+                server.set_metrics_addr(addr);
+            }
+            if let Some(addr) = health_addr {
+                server.set_health_addr(addr);
+            }
+            #[cfg(unix)]
+            if let Some(path) = control_socket {
+                server.set_control_socket(path);
+            }
+            #[cfg(unix)]
+            if let Some(listener) = activated_listener()? {
+                info!("using control socket from systemd");
+                server.set_control_listener(listener);
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(name) = bind_interface {
+                server.set_bind_interface(name);
+            }
+            #[cfg(unix)]
+            server.set_reuse_port(reuse_port);
+            if let Some(secret) = admin_secret {
+                server.set_admin_secret(&secret);
+            }
+            server.set_nodelay(nodelay);
+            if let Some(idle) = keepalive {
+                server.set_keepalive(Keepalive {
+                    idle: Duration::from_secs(idle),
+                    interval: keepalive_interval.map(Duration::from_secs),
+                    count: keepalive_count,
+                });
+            }
+            server.set_compression(compression);
+            server.set_drain_timeout(Duration::from_secs(drain_timeout));
+            if check {
+                server.validate()?;
+                info!("configuration is valid");
+                return Ok(());
+            }
+            #[cfg(unix)]
+            let shutdown = shutdown_signal()?;
+            #[cfg(not(unix))]
+            let shutdown = std::future::pending();
+            server.listen_with_shutdown(shutdown).await?;
+        }
+        Command::Admin {
+            command:
+                AdminCommand::List {
+                    to,
+                    control_port,
+                    secret,
+                    admin_secret,
+                },
+        } => {
+            let tunnels =
+                admin::list_tunnels(&to, control_port, secret.as_deref(), &admin_secret).await?;
+            println!("{:<7} {:<47} {:<9} LABEL", "PORT", "CLIENT", "UPTIME");
+            for tunnel in tunnels {
+                let client = tunnel.client.map_or("unix".into(), |addr| addr.to_string());
+                let uptime = format!("{}s", tunnel.uptime.as_secs());
+                let label = tunnel.label.as_deref().unwrap_or("-");
+                println!("{:<7} {client:<47} {uptime:<9} {label}", tunnel.port);
+            }
+        }
+        Command::Admin {
+            command:
+                AdminCommand::Kill {
+                    port,
+                    to,
+                    control_port,
+                    secret,
+                    admin_secret,
+                },
+        } => {
+            let secret = secret.as_deref();
+            if admin::kill_tunnel(&to, control_port, secret, &admin_secret, port).await? {
+                println!("closed the tunnel on port {port}");
+            } else {
+                println!("no tunnel is open on port {port}");
             }
-            let mut server = Server::new(port_range, secret.as_deref());
-            server.set_bind_addr(bind_addr);
-            server.set_bind_tunnels(bind_tunnels.unwrap_or(bind_addr));
-            server.listen().await?;
         }
     }
 
     Ok(())
 }
 
+/// Take the control socket passed by systemd socket activation, if there is one.
+///
+/// Activation is detected with the `LISTEN_PID` and `LISTEN_FDS` variables, as
+/// described in `sd_listen_fds(3)`. Exactly one TCP listener must be passed.
+#[cfg(unix)]
+fn activated_listener() -> Result<Option<std::net::TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    use socket2::{Domain, SockRef, Type};
+
+    /// File descriptor of the first socket passed by systemd.
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    if pid != Some(process::id()) {
+        return Ok(None);
+    }
+    let fds: u32 = env::var("LISTEN_FDS")
+        .context("LISTEN_PID is set without LISTEN_FDS")?
+        .parse()
+        .context("invalid LISTEN_FDS")?;
+    ensure!(fds == 1, "expected one socket from systemd, got {fds}");
+    // SAFETY: systemd passes the socket as this file descriptor, which nothing else in
+    // the process owns, and this function is only called once.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    let socket = SockRef::from(&listener);
+    ensure!(
+        matches!(socket.domain()?, Domain::IPV4 | Domain::IPV6) && socket.r#type()? == Type::STREAM,
+        "socket from systemd is not a TCP socket"
+    );
+    Ok(Some(listener))
+}
+
+/// Write end of the pipe that wakes up [`shutdown_signal`], once it is installed.
+#[cfg(unix)]
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Handle SIGTERM and SIGINT, returning a future that completes on the first of them.
+///
+/// The handler only writes to a pipe, which is all that is safe to do in it, and puts
+/// back the default action, so a second signal stops the server right away. System
+/// calls that the signal interrupts are restarted rather than failing with `EINTR`.
+#[cfg(unix)]
+fn shutdown_signal() -> Result<impl std::future::Future<Output = ()>> {
+    use std::os::unix::{io::IntoRawFd, net::UnixStream};
+
+    use tokio::io::AsyncReadExt;
+
+    extern "C" fn on_signal(_signal: libc::c_int) {
+        let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+        // SAFETY: write is async-signal-safe, the pipe is never closed, and errno is put
+        // back so that the interrupted code does not see the result of the write.
+        unsafe {
+            let errno = *errno_location();
+            libc::write(fd, [0u8].as_ptr().cast(), 1);
+            *errno_location() = errno;
+        }
+    }
+
+    let (reader, writer) = UnixStream::pair()?;
+    reader.set_nonblocking(true)?;
+    writer.set_nonblocking(true)?;
+    SIGNAL_PIPE.store(writer.into_raw_fd(), Ordering::Relaxed);
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: an all-zero sigaction is valid, with an empty mask, and the handler only
+        // calls async-signal-safe functions.
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        ensure!(
+            result == 0,
+            "could not handle signal {signal}: {}",
+            io::Error::last_os_error()
+        );
+    }
+    let mut reader = tokio::net::UnixStream::from_std(reader)?;
+    Ok(async move {
+        let _ = reader.read_u8().await;
+        info!("received shutdown signal, draining connections");
+    })
+}
+
+/// Address of the calling thread's `errno`.
+#[cfg(unix)]
+fn errno_location() -> *mut libc::c_int {
+    // SAFETY: these only return the address of the thread's errno.
+    unsafe {
+        #[cfg(any(target_os = "linux", target_os = "dragonfly", target_os = "emscripten"))]
+        return libc::__errno_location();
+        #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+        return libc::__error();
+        #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+        return libc::__errno();
+    }
+}
+
+/// Format a number of bytes with a decimal unit, such as `1.2 MB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["kB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Parse a local port, optionally followed by a colon and the remote port to select.
+fn parse_forward(s: &str) -> Result<(u16, u16)> {
+    let (local_port, port) = s.split_once(':').unwrap_or((s, "0"));
+    Ok((local_port.parse()?, port.parse()?))
+}
+
+/// Parse a host and port written as `HOST:PORT`, with IPv6 addresses in brackets.
+fn parse_target(s: &str) -> Result<(String, u16)> {
+    let (host, port) = s.rsplit_once(':').context("expected `HOST:PORT`")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ensure!(!host.is_empty(), "host must not be empty");
+    Ok((host.into(), port.parse()?))
+}
+
+/// Parse a range of ports written as `MIN-MAX`.
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let (min, max) = s.split_once('-').context("expected `MIN-MAX`")?;
+    let port_range = min.parse()?..=max.parse()?;
+    ensure!(!port_range.is_empty(), "port range must not be empty");
+    Ok(port_range)
+}
+
 fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     run(Args::parse().command)
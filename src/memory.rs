@@ -0,0 +1,67 @@
+//! An in-memory transport for control connections between a client and a server.
+//!
+//! This runs the handshake, the control messages, and the connections that accept
+//! forwarded streams over [`tokio::io::duplex`] pipes instead of sockets, so a client
+//! and server in the same process can be tested without binding a control port.
+//! Tunnels still listen on TCP ports on the server, as they are reached from outside.
+//!
+//! ```no_run
+//! use bore_cli::{client::Client, memory, server::Server};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let (connector, listener) = memory::channel();
+//! let mut server = Server::new(1024..=65535, None);
+//! server.set_memory_listener(listener);
+//! tokio::spawn(server.listen());
+//!
+//! let mut client = Client::configure("localhost", 8000, "memory", 0, None);
+//! client.set_memory_connector(connector);
+//! let port = client.connect().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+
+use tokio::io::{duplex, DuplexStream};
+use tokio::sync::mpsc;
+
+/// Size of the buffer in each direction of an in-memory connection.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Create a connector and the listener that accepts its connections.
+pub fn channel() -> (MemoryConnector, MemoryListener) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (MemoryConnector { tx }, MemoryListener { rx })
+}
+
+/// Opens in-memory connections to a [`MemoryListener`], like a client connecting to a port.
+#[derive(Clone, Debug)]
+pub struct MemoryConnector {
+    tx: mpsc::UnboundedSender<DuplexStream>,
+}
+
+/// Accepts in-memory connections from its [`MemoryConnector`]s, like a listening socket.
+#[derive(Debug)]
+pub struct MemoryListener {
+    rx: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl MemoryConnector {
+    /// Open a connection, failing with [`io::ErrorKind::ConnectionRefused`] if the
+    /// listener was dropped.
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (stream, peer) = duplex(BUFFER_SIZE);
+        self.tx.send(peer).map_err(|_| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "memory listener closed")
+        })?;
+        Ok(stream)
+    }
+}
+
+impl MemoryListener {
+    /// Wait for the next connection, or `None` once every connector was dropped.
+    pub async fn accept(&mut self) -> Option<DuplexStream> {
+        self.rx.recv().await
+    }
+}
@@ -0,0 +1,213 @@
+//! Prometheus metrics exposed by the `bore` server.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{ensure, Context, Result};
+use dashmap::DashMap;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Maximum byte length of an HTTP request head on the metrics endpoint.
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// Counters updated by the server as it handles connections.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Control connections that are currently open.
+    pub control_connections: AtomicU64,
+
+    /// Connections accepted on all tunnels since startup.
+    pub connections_total: AtomicU64,
+
+    /// Bytes forwarded through tunnels in either direction since startup.
+    pub forwarded_bytes_total: AtomicU64,
+
+    /// Successful handshakes since startup, by the ID of the secret used.
+    authentications_total: DashMap<String, u64>,
+}
+
+/// Gauges that are read from the server state at scrape time.
+pub(crate) struct Gauges {
+    /// Number of tunnels that are listening for connections.
+    pub tunnels: usize,
+
+    /// Maximum number of tunnels that may be open at once.
+    pub max_tunnels: usize,
+
+    /// Number of incoming connections waiting to be accepted by a client.
+    pub pending_connections: usize,
+
+    /// Number of ports of the port range that tunnels listen on.
+    pub ports_used: usize,
+
+    /// Number of ports in the port range.
+    pub ports_total: usize,
+}
+
+impl Metrics {
+    /// Add to the count of bytes forwarded through tunnels.
+    pub fn add_forwarded_bytes(&self, bytes: u64) {
+        self.forwarded_bytes_total
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a successful handshake with the secret of this ID.
+    pub fn add_authentication(&self, key_id: &str) {
+        *self
+            .authentications_total
+            .entry(key_id.to_string())
+            .or_default() += 1;
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        metric(
+            "bore_control_connections",
+            "gauge",
+            "Control connections that are currently open.",
+            self.control_connections.load(Ordering::Relaxed),
+        );
+        metric(
+            "bore_tunnels",
+            "gauge",
+            "Tunnels that are listening for connections.",
+            gauges.tunnels as u64,
+        );
+        metric(
+            "bore_max_tunnels",
+            "gauge",
+            "Maximum number of tunnels that may be open at once.",
+            gauges.max_tunnels as u64,
+        );
+        metric(
+            "bore_pending_connections",
+            "gauge",
+            "Incoming connections waiting to be accepted by a client.",
+            gauges.pending_connections as u64,
+        );
+        metric(
+            "bore_ports_used",
+            "gauge",
+            "Ports of the port range that tunnels listen on.",
+            gauges.ports_used as u64,
+        );
+        metric(
+            "bore_ports_total",
+            "gauge",
+            "Ports in the port range.",
+            gauges.ports_total as u64,
+        );
+        metric(
+            "bore_connections_total",
+            "counter",
+            "Connections accepted on all tunnels.",
+            self.connections_total.load(Ordering::Relaxed),
+        );
+        metric(
+            "bore_forwarded_bytes_total",
+            "counter",
+            "Bytes forwarded through tunnels in either direction.",
+            self.forwarded_bytes_total.load(Ordering::Relaxed),
+        );
+
+        let mut authentications: Vec<_> = self
+            .authentications_total
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        authentications.sort_unstable();
+        let name = "bore_authentications_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Successful client handshakes, by the ID of the secret used."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (key_id, count) in authentications {
+            let _ = writeln!(out, "{name}{{key=\"{key_id}\"}} {count}");
+        }
+        out
+    }
+}
+
+/// Content type of the Prometheus text exposition format.
+pub(crate) const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Serve a single HTTP endpoint at `path`, rendering the body for each request.
+///
+/// This is used for `/metrics`, and for the health check endpoint.
+pub(crate) async fn serve<F>(
+    listener: TcpListener,
+    path: &'static str,
+    content_type: &'static str,
+    render: F,
+) where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    info!(addr = ?listener.local_addr().ok(), path, "http endpoint listening");
+    let render = Arc::new(render);
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!(%err, path, "failed to accept http endpoint connection");
+                continue;
+            }
+        };
+        let render = Arc::clone(&render);
+        tokio::spawn(async move {
+            if let Err(err) = respond(&mut stream, path, content_type, &*render).await {
+                warn!(%err, path, "http endpoint request failed");
+            }
+        });
+    }
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    path: &str,
+    content_type: &str,
+    render: &impl Fn() -> String,
+) -> Result<()> {
+    let request_line = timeout(NETWORK_TIMEOUT, read_request_head(stream))
+        .await
+        .context("timed out reading request")??;
+    let response = match request_line.split(' ').nth(1) {
+        Some(requested) if requested == path => {
+            let body = render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Read an HTTP request head, returning its request line.
+async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_LENGTH as u64));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut line = String::new();
+        ensure!(reader.read_line(&mut line).await? > 0, "incomplete request");
+        if line.trim_end().is_empty() {
+            return Ok(request_line);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Headers of the PROXY protocol, which tell the local service who connected.
+//!
+//! See the [specification](https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt)
+//! for details of both versions.
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+/// Signature that starts every version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version of the PROXY protocol to send ahead of forwarded connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProxyProtocol {
+    /// The human-readable text format.
+    V1,
+
+    /// The binary format.
+    V2,
+}
+
+impl ProxyProtocol {
+    /// Encode a header for a TCP connection from `source` to `destination`.
+    ///
+    /// If the addresses are of different families, the header does not carry them,
+    /// and the receiver should use the addresses of the connection itself.
+    pub fn header(self, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+        let (source, destination) = (canonical(source), canonical(destination));
+        match self {
+            ProxyProtocol::V1 => {
+                let family = match (source, destination) {
+                    (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+                    (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+                    _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+                };
+                format!(
+                    "PROXY {family} {} {} {} {}\r\n",
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port(),
+                )
+                .into_bytes()
+            }
+            ProxyProtocol::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                let mut push_addresses = |family: u8, source: &[u8], destination: &[u8]| {
+                    let len = 2 * source.len() + 4;
+                    header.push(0x21); // version 2, PROXY command
+                    header.push(family);
+                    header.extend_from_slice(&(len as u16).to_be_bytes());
+                    header.extend_from_slice(source);
+                    header.extend_from_slice(destination);
+                };
+                match (source, destination) {
+                    (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                        push_addresses(0x11, &src.ip().octets(), &dst.ip().octets());
+                    }
+                    (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+                        push_addresses(0x21, &src.ip().octets(), &dst.ip().octets());
+                    }
+                    _ => {
+                        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]); // LOCAL command
+                        return header;
+                    }
+                }
+                header.extend_from_slice(&source.port().to_be_bytes());
+                header.extend_from_slice(&destination.port().to_be_bytes());
+                header
+            }
+        }
+    }
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "v1" => Ok(Self::V1),
+            "v2" => Ok(Self::V2),
+            _ => bail!("unknown PROXY protocol version {s:?}, expected \"v1\" or \"v2\""),
+        }
+    }
+}
+
+/// Convert IPv4-mapped IPv6 addresses to plain IPv4.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
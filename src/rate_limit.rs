@@ -0,0 +1,105 @@
+//! Token-bucket rate limiting of new connections.
+
+use std::net::IpAddr;
+use std::time::Instant;
+
+use dashmap::DashMap;
+
+/// Number of tracked addresses above which idle buckets are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Rate limiter allowing each IP address a sustained rate with some burst.
+pub(crate) struct RateLimiter {
+    /// Tokens added to each bucket per second.
+    per_sec: f64,
+
+    /// Maximum number of tokens in a bucket.
+    burst: f64,
+
+    /// Token buckets for addresses that have connected recently.
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+/// Tokens available to one address, as of the last update.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter refilling at `per_sec`, holding at most `burst` tokens.
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        RateLimiter {
+            per_sec: per_sec as f64,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token for this address, returning false if it is over the limit.
+    pub fn check(&self, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.buckets.len() > PRUNE_THRESHOLD {
+            // Buckets that would have refilled completely are the same as new ones.
+            self.buckets
+                .retain(|_, bucket| bucket.refill(self.per_sec, self.burst, now) < self.burst);
+        }
+        let mut bucket = self
+            .buckets
+            .entry(addr)
+            .or_insert_with(|| Bucket::full(self.burst, now));
+        bucket.take(self.per_sec, self.burst, now)
+    }
+}
+
+/// Rate limiter for the connections of a single tunnel, shared by all addresses.
+pub(crate) struct ConnectionRate {
+    per_sec: f64,
+    burst: f64,
+    bucket: Bucket,
+}
+
+impl ConnectionRate {
+    /// Create a rate limiter refilling at `per_sec`, holding at most `burst` tokens.
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        ConnectionRate {
+            per_sec: per_sec as f64,
+            burst,
+            bucket: Bucket::full(burst, Instant::now()),
+        }
+    }
+
+    /// Take a token for a new connection, returning false if it is over the limit.
+    pub fn check(&mut self) -> bool {
+        self.bucket.take(self.per_sec, self.burst, Instant::now())
+    }
+}
+
+impl Bucket {
+    /// A bucket holding as many tokens as it can.
+    fn full(burst: f64, now: Instant) -> Self {
+        Bucket {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    /// Refill the bucket up to this instant, then take a token if there is one.
+    fn take(&mut self, per_sec: f64, burst: f64, now: Instant) -> bool {
+        self.tokens = self.refill(per_sec, burst, now);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number of tokens in the bucket after refilling it up to this instant.
+    fn refill(&self, per_sec: f64, burst: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * per_sec).min(burst)
+    }
+}
@@ -0,0 +1,102 @@
+//! Secrets loaded from a file, which is reloaded whenever it changes.
+
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+use crate::auth::Authenticator;
+
+/// Interval between checks of the secrets file for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Secrets accepted by the server, each restricting its clients to a range of ports.
+pub(crate) type Secrets = Vec<(Authenticator, RangeInclusive<u16>)>;
+
+/// A file of secrets, as `MIN-MAX SECRET` lines.
+pub(crate) struct SecretsFile {
+    path: PathBuf,
+
+    /// Secrets from the last version of the file that was read successfully.
+    current: RwLock<Arc<Secrets>>,
+}
+
+impl SecretsFile {
+    /// Read the secrets in a file, failing if it cannot be read or parsed.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let secrets = read(&path)?;
+        Ok(SecretsFile {
+            path,
+            current: RwLock::new(Arc::new(secrets)),
+        })
+    }
+
+    /// Returns the secrets, which stay the same for as long as they are held.
+    pub fn current(&self) -> Arc<Secrets> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Reload the secrets whenever the file changes, keeping the old ones if it is invalid.
+    ///
+    /// Clients that already authenticated are not affected, as secrets are only
+    /// checked during the handshake.
+    pub async fn watch(self: Arc<Self>) {
+        let mut last_seen = modified(&self.path);
+        let mut poll = interval(POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            poll.tick().await;
+            let seen = modified(&self.path);
+            if seen == last_seen {
+                continue;
+            }
+            last_seen = seen;
+            match read(&self.path) {
+                Ok(secrets) => {
+                    info!(path = ?self.path, count = secrets.len(), "reloaded secrets");
+                    *self.current.write().unwrap() = Arc::new(secrets);
+                }
+                Err(err) => warn!(%err, "keeping previous secrets"),
+            }
+        }
+    }
+}
+
+/// Modification time and size of a file, used to tell when it changes.
+fn modified(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Read secrets and their port ranges from a file, skipping blank lines and comments.
+fn read(path: &Path) -> Result<Secrets> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+    let mut secrets = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse = || {
+            let (range, secret) = line.split_once(char::is_whitespace)?;
+            let (min, max) = range.split_once('-')?;
+            let port_range = min.parse().ok()?..=max.parse().ok()?;
+            (!port_range.is_empty()).then(|| (Authenticator::new(secret.trim()), port_range))
+        };
+        let entry = parse().with_context(|| {
+            format!(
+                "{}:{}: expected `MIN-MAX SECRET`",
+                path.display(),
+                number + 1
+            )
+        })?;
+        secrets.push(entry);
+    }
+    Ok(secrets)
+}
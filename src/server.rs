@@ -1,18 +1,199 @@
 //! Server implementation for the `bore` service.
 
-use std::net::{IpAddr, Ipv4Addr};
-use std::{io, ops::RangeInclusive, sync::Arc, time::Duration};
-
-use anyhow::Result;
-use dashmap::DashMap;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{sleep, timeout};
-use tracing::{info, info_span, warn, Instrument};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::{self, Future};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::{fmt, io, ops::RangeInclusive, slice, sync::Arc, time::Duration};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::future::{select_all, BoxFuture};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, oneshot, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{interval, sleep, sleep_until, timeout, Instant, MissedTickBehavior};
+use tokio_util::either::Either;
+use tokio_util::sync::CancellationToken;
+use tracing::{field, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
 use crate::auth::Authenticator;
-use crate::shared::{proxy, ClientMessage, Delimited, ServerMessage, CONTROL_PORT};
+use crate::cidr::IpNet;
+use crate::compress::Compressed;
+use crate::events::{Event, EventKind, EventSinks};
+use crate::http_mux;
+use crate::memory::MemoryListener;
+use crate::metrics::{self, Gauges, Metrics};
+use crate::proxy_protocol::ProxyProtocol;
+use crate::rate_limit::{ConnectionRate, RateLimiter};
+use crate::secrets::SecretsFile;
+use crate::shared::{
+    proxy_counted, set_stream_options, ClientMessage, Delimited, ServerMessage, TunnelInfo,
+    TunnelStatus, CONTROL_PORT, MAX_FRAME_LENGTH, MAX_LABEL_LENGTH, NETWORK_TIMEOUT,
+    PROTOCOL_VERSION, UDP_SESSION_TIMEOUT,
+};
+use crate::throttle::{Bandwidth, Throttled};
+use crate::webhook::Webhook;
+
+pub use crate::shared::Keepalive;
+
+/// Error sent to clients when their tunnel is closed by a server shutdown.
+const SHUTDOWN_MESSAGE: &str = "server shutting down";
+
+/// Error sent to clients when their tunnel reaches its maximum lifetime.
+const LIFETIME_MESSAGE: &str = "tunnel reached its maximum lifetime";
+
+/// Map of port and client IP to the tunnel listening there.
+///
+/// See [`owner_key`] for how clients are matched.
+type PortOwners = DashMap<(u16, Option<IpAddr>), TunnelEntry>;
+
+/// Map of reservation token and port to the tunnels held for clients that disconnected.
+type Reservations = DashMap<(String, u16), Held>;
+
+/// Control connection state handed to a held tunnel when its client comes back.
+///
+/// The sender is a lease, dropped when the tunnel closes to tell the new connection.
+type Adoption = (Notifier, oneshot::Sender<()>);
+
+/// Permits held by a tunnel, in the server's tunnel limit and its secret's quota.
+type TunnelPermits = (OwnedSemaphorePermit, Option<OwnedSemaphorePermit>);
+
+/// Permits held by a connection, in its tunnel's connection limit and its secret's quota.
+type ConnectionPermits = (Option<OwnedSemaphorePermit>, Option<OwnedSemaphorePermit>);
+
+/// Permits held by a connection until it is accepted, in its tunnel's queue and the
+/// server's limit on pending connections.
+type PendingPermits = (Option<OwnedSemaphorePermit>, Option<OwnedSemaphorePermit>);
+
+/// Map of tunnel name to its client, and the bandwidth that the tunnel shares.
+type NamedTunnels = DashMap<String, (Notifier, Option<Arc<Bandwidth>>)>;
+
+/// Function deciding whether to accept a connection on a tunnel, given its port and peer.
+type AcceptFilter = Arc<dyn Fn(u16, SocketAddr) -> bool + Send + Sync>;
+
+/// Function returning the ID of each new connection.
+type IdGenerator = Arc<dyn Fn() -> Uuid + Send + Sync>;
+
+/// Pause after a failure to accept a connection, so that errors like running out of
+/// file descriptors do not spin the listener.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Default interval between heartbeats sent on each control connection.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Transport protocol of a tunnel requested by a client.
+#[derive(Clone, Copy)]
+enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// A tunnel requested by a client's hello message.
+enum TunnelRequest {
+    /// A tunnel with its own public port, or 0 for any available port.
+    Port(Transport, u16),
+
+    /// An HTTP tunnel on the shared HTTP port, routed by this name.
+    Named(String),
+}
+
+/// Notifies a control connection of incoming connections on one of its tunnels.
+#[derive(Clone)]
+struct Notifier {
+    /// Channel of messages to send on the control connection.
+    tx: mpsc::Sender<ServerMessage>,
+
+    /// Public port of the tunnel.
+    port: u16,
+
+    /// Address of the client, which owns the tunnel together with the port.
+    remote_addr: Option<SocketAddr>,
+
+    /// Whether notifications are tagged with the port, for tunnels after the first.
+    tagged: bool,
+
+    /// Whether notifications carry the address of the remote peer.
+    peers: bool,
+
+    /// Slots for connections waiting to be accepted, if the tunnel has a queue.
+    queue: Option<Arc<Semaphore>>,
+
+    /// Connection slots shared by the tunnels of the client's secret, if it has a quota.
+    quota: Option<Arc<Semaphore>>,
+
+    /// Activity on the tunnel, which its client may ask to be told about.
+    activity: Arc<Activity>,
+}
+
+/// Counters of the activity on one tunnel.
+#[derive(Default)]
+struct Activity {
+    /// Connections and UDP sessions that are being forwarded.
+    connections: AtomicU64,
+
+    /// Bytes forwarded in either direction by connections that have closed.
+    bytes: AtomicU64,
+}
+
+/// Optional features that a client asked for before its first hello.
+#[derive(Clone, Copy)]
+struct Features {
+    /// Whether notifications carry the address of the remote peer.
+    peers: bool,
+
+    /// Whether heartbeats are replaced with the status of each tunnel.
+    status: bool,
+}
+
+impl Notifier {
+    /// Ask the client to accept a connection, returning false if it has left.
+    async fn notify(&self, id: Uuid, remote: SocketAddr) -> bool {
+        let msg = match (self.peers, self.tagged) {
+            (true, _) => ServerMessage::PeerConnection {
+                port: self.port,
+                id,
+                remote,
+            },
+            (false, true) => ServerMessage::TunnelConnection(self.port, id),
+            (false, false) => ServerMessage::Connection(id),
+        };
+        self.tx.send(msg).await.is_ok()
+    }
+
+    /// Tell the client why the tunnel is closing.
+    async fn close(&self, reason: &str) {
+        let _ = self.tx.send(ServerMessage::Error(reason.into())).await;
+    }
+
+    /// Take a place in the queue of pending connections, failing if it is full.
+    fn enqueue(&self) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        acquire(&self.queue)
+    }
+
+    /// Take a connection slot from the quota of the client's secret, warning the client if
+    /// none are left.
+    fn take_quota(&self) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        let result = acquire(&self.quota);
+        if result.is_err() {
+            // Only this connection is refused, so the client is not disconnected.
+            let message = ServerMessage::Error("connection quota exceeded".into());
+            let _ = self.tx.try_send(message);
+        }
+        result
+    }
+}
 
 /// State structure for the server.
 pub struct Server {
@@ -22,189 +203,3572 @@ pub struct Server {
     /// Optional secret used to authenticate clients.
     auth: Option<Authenticator>,
 
+    /// Additional secrets, each restricting its clients to a range of ports.
+    secrets: Vec<(Authenticator, RangeInclusive<u16>)>,
+
+    /// File of more secrets, which is reloaded when it changes.
+    secrets_file: Option<Arc<SecretsFile>>,
+
+    /// Quotas of secrets, by the ID of each secret.
+    quotas: HashMap<String, QuotaSlots>,
+
     /// Concurrent map of IDs to incoming connections.
-    conns: Arc<DashMap<Uuid, TcpStream>>,
+    conns: Arc<DashMap<Uuid, Pending>>,
+
+    /// Map of port and client IP to a handle that can be used to abort the listener task.
+    ///
+    /// The IP is `None` for clients connected over a Unix socket.
+    port_owners: Arc<PortOwners>,
+
+    /// TCP tunnels held for clients that disconnected with a reservation token.
+    reservations: Arc<Reservations>,
+
+    /// Time to hold the tunnels of clients with a reservation token, if held at all.
+    reservation_grace: Option<Duration>,
+
+    /// When the server started listening, once it has.
+    started: Arc<OnceLock<Instant>>,
+
+    /// Maximum number of tunnels that may be open at once, across all clients.
+    max_tunnels: usize,
+
+    /// Permits held by open tunnels, up to the maximum.
+    tunnel_slots: Arc<Semaphore>,
+
+    /// Fraction of the port range in use above which opening a tunnel logs a warning.
+    port_utilization_warning: Option<f64>,
 
-    /// Map of (port, remote_addr) to a handle that can be used to abort the listener task.
-    port_owners: Arc<DashMap<(u16, std::net::SocketAddr), tokio::task::JoinHandle<()>>>,
+    /// Held while a tunnel is added to the port owners and the ports in use are counted.
+    port_insert: Mutex<()>,
+
+    /// Permits held by pending connections across all tunnels, if limited.
+    pending_slots: Option<Arc<Semaphore>>,
 
     /// IP address where the control server will bind to.
     bind_addr: IpAddr,
 
-    /// IP address where tunnels will listen on.
-    bind_tunnels: IpAddr,
+    /// TCP port where the control server will listen on.
+    control_port: u16,
+
+    /// Public hostname that clients are told to share, if any.
+    advertised_host: Option<String>,
+
+    /// IP addresses where tunnels will listen on, or empty for all interfaces.
+    bind_tunnels: Vec<IpAddr>,
+
+    /// IP addresses that clients may ask their tunnels to listen on instead.
+    client_bind_tunnels: Vec<IpAddr>,
+
+    /// Token cancelled when the server begins shutting down.
+    shutdown: CancellationToken,
+
+    /// Time to wait for forwarded connections to finish during shutdown.
+    drain_timeout: Duration,
+
+    /// Time without any tunnels after which the server exits, if it ever does.
+    exit_when_idle: Option<Duration>,
+
+    /// Time the server runs before it may exit for being idle.
+    min_uptime: Duration,
+
+    /// Maximum number of outstanding connections on each tunnel, if limited.
+    max_conns_per_port: Option<usize>,
+
+    /// Number of connections each tunnel can queue for its client, if queued.
+    pending_queue_depth: Option<usize>,
+
+    /// Time an incoming connection waits for the client to accept it, or `None` to wait forever.
+    accept_timeout: Option<Duration>,
+
+    /// Message written to TCP connections that no client accepted in time, if any.
+    unavailable_banner: Option<Bytes>,
+
+    /// Interval between heartbeats sent on each control connection.
+    heartbeat_interval: Duration,
+
+    /// Time a forwarded connection may go without transferring data, if limited.
+    connection_idle_timeout: Option<Duration>,
+
+    /// Bandwidth of each TCP tunnel, as a rate in bytes per second and a burst in bytes.
+    bandwidth_limit: Option<(u64, u64)>,
+
+    /// Time a tunnel may go without connections before its port is reclaimed, if limited.
+    idle_tunnel_timeout: Option<Duration>,
+
+    /// Time after which a tunnel is closed however busy it is, if limited.
+    max_tunnel_lifetime: Option<Duration>,
+
+    /// Number of random ports to try when a client requests any available port.
+    port_probe_attempts: usize,
+
+    /// How ports are chosen when a client requests any available port.
+    allocation_strategy: AllocationStrategy,
+
+    /// Who may take over a specific port that another tunnel is listening on.
+    port_takeover: PortTakeover,
+
+    /// Ports to try before the rest of the range when a client requests any port, if any.
+    preferred_ports: Option<RangeInclusive<u16>>,
+
+    /// Limit on the rate of new control connections from each IP address, if any.
+    control_rate_limit: Option<RateLimiter>,
+
+    /// Rate of new connections on each tunnel, as connections per second and a burst.
+    tunnel_rate_limit: Option<(u32, u32)>,
+
+    /// Decides which connections on tunnels are accepted, if any are refused.
+    accept_filter: Option<AcceptFilter>,
+
+    /// Networks that clients must connect from, or empty to allow any address.
+    ip_allowlist: Vec<IpNet>,
+
+    /// Networks that clients must not connect from.
+    ip_denylist: Vec<IpNet>,
+
+    /// Version of the PROXY protocol header sent ahead of forwarded TCP connections, if any.
+    proxy_protocol: Option<ProxyProtocol>,
+
+    /// Channel and subscribers that receive structured events.
+    events: EventSinks,
+
+    /// Source of the IDs of incoming connections.
+    id_generator: IdGenerator,
+
+    /// Shared port where HTTP connections are routed to named tunnels, if enabled.
+    http_port: Option<u16>,
+
+    /// Named tunnels, keyed by the first label of the `Host` header they serve.
+    named_tunnels: Arc<NamedTunnels>,
+
+    /// Endpoint that tunnel events are posted to, if any.
+    webhook: Option<Arc<Webhook>>,
+
+    /// Counters exported on the metrics endpoint.
+    metrics: Arc<Metrics>,
+
+    /// Address of the Prometheus metrics endpoint, if enabled.
+    metrics_addr: Option<SocketAddr>,
+
+    /// Address of the health check endpoint, if enabled.
+    health_addr: Option<SocketAddr>,
+
+    /// Path of a Unix socket to accept control connections on, instead of TCP.
+    #[cfg(unix)]
+    control_socket: Option<PathBuf>,
+
+    /// Listener for control connections that was bound before the server started, if any.
+    control_listener: Option<std::net::TcpListener>,
+
+    /// In-memory listener for control connections, taken when the server starts.
+    memory_listener: Mutex<Option<MemoryListener>>,
+
+    /// Network interface that tunnels are restricted to, if any.
+    #[cfg(target_os = "linux")]
+    bind_interface: Option<String>,
+
+    /// Secret that administrators authenticate with, if admin commands are enabled.
+    admin_auth: Option<Authenticator>,
+
+    /// Whether the server refuses to start without a client secret.
+    require_secret: bool,
+
+    /// Whether TCP listeners are bound with `SO_REUSEADDR`.
+    reuse_address: bool,
+
+    /// Whether TCP listeners are bound with `SO_REUSEPORT`.
+    #[cfg(unix)]
+    reuse_port: bool,
+
+    /// Whether accepted TCP connections are set to `TCP_NODELAY`.
+    nodelay: bool,
+
+    /// Keepalive settings for accepted TCP connections, if enabled.
+    keepalive: Option<Keepalive>,
+
+    /// Whether clients may compress the data of their TCP connections.
+    compression: bool,
 }
 
-impl Server {
-    /// Create a new server with a specified minimum port number.
-    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
-        assert!(!port_range.is_empty(), "must provide at least one port");
-        Server {
-            port_range,
-            conns: Arc::new(DashMap::new()),
-            auth: secret.map(Authenticator::new),
-            port_owners: Arc::new(DashMap::new()),
-            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-            bind_tunnels: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+/// Policy for choosing a port when a client requests any available port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AllocationStrategy {
+    /// Try ports sampled uniformly at random from the range.
+    ///
+    /// This spreads tunnels across the range and makes assigned ports hard to guess,
+    /// which suits large shared deployments.
+    #[default]
+    Random,
+
+    /// Scan the range in order and bind to the lowest free port.
+    ///
+    /// Assigned ports are predictable, but each request may need to try every port
+    /// that is already in use.
+    Sequential,
+}
+
+impl FromStr for AllocationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(Self::Random),
+            "sequential" => Ok(Self::Sequential),
+            _ => bail!("unknown allocation strategy {s:?}, expected \"random\" or \"sequential\""),
         }
     }
+}
 
-    /// Set the IP address where tunnels will listen on.
-    pub fn set_bind_addr(&mut self, bind_addr: IpAddr) {
-        self.bind_addr = bind_addr;
-    }
+/// Policy for a client that requests a specific port which another tunnel is listening on.
+///
+/// The tunnel that is taken over is closed, and its client is told why. Requests
+/// that may not take over the port fail with "port already in use".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PortTakeover {
+    /// Never take over a port.
+    Reject,
 
-    /// Set the IP address where the control server will bind to.
-    pub fn set_bind_tunnels(&mut self, bind_tunnels: IpAddr) {
-        self.bind_tunnels = bind_tunnels;
+    /// Take over a port from a client with the same IP address.
+    ///
+    /// This lets a reconnecting client take its port back from a tunnel whose control
+    /// connection has not yet been noticed as dead. Clients on Unix sockets never take
+    /// over a port.
+    #[default]
+    SameAddr,
+
+    /// Take over a port from any client.
+    ///
+    /// Any client can then disrupt the tunnels of others, so this only suits servers
+    /// where every client is trusted.
+    Any,
+}
+
+impl FromStr for PortTakeover {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "takeover-same-addr" => Ok(Self::SameAddr),
+            "takeover-any" => Ok(Self::Any),
+            _ => bail!(
+                "unknown port takeover policy {s:?}, expected \"reject\", \"takeover-same-addr\" or \"takeover-any\""
+            ),
+        }
     }
+}
 
-    /// Start the server, listening for new connections.
-    pub async fn listen(self) -> Result<()> {
-        let this = Arc::new(self);
-        let listener = TcpListener::bind((this.bind_addr, CONTROL_PORT)).await?;
-        info!(addr = ?this.bind_addr, "server listening");
+/// Errors in the configuration of a server.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ServerError {
+    /// The range of ports that can be forwarded is empty.
+    EmptyPortRange,
+}
 
-        loop {
-            let (stream, addr) = listener.accept().await?;
-            let this = Arc::clone(&this);
-            tokio::spawn(
-                async move {
-                    info!("incoming connection");
-                    if let Err(err) = this.handle_connection(stream).await {
-                        warn!(%err, "connection exited with error");
-                    } else {
-                        info!("connection exited");
-                    }
-                }
-                .instrument(info_span!("control", ?addr)),
-            );
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyPortRange => write!(f, "must provide at least one port"),
         }
     }
+}
 
-    async fn create_listener(&self, port: u16) -> Result<TcpListener, &'static str> {
-        let try_bind = |port: u16| async move {
-            TcpListener::bind((self.bind_tunnels, port))
-                .await
-                .map_err(|err| match err.kind() {
-                    io::ErrorKind::AddrInUse => "port already in use",
-                    io::ErrorKind::PermissionDenied => "permission denied",
-                    _ => "failed to bind to port",
-                })
-        };
-        if port > 0 {
-            // Client requests a specific port number.
-            if !self.port_range.contains(&port) {
-                return Err("client port number not in allowed range");
-            }
-            try_bind(port).await
-        } else {
-            // Client requests any available port in range.
-            //
-            // In this case, we bind to 150 random port numbers. We choose this value because in
-            // order to find a free port with probability at least 1-δ, when ε proportion of the
-            // ports are currently available, it suffices to check approximately -2 ln(δ) / ε
-            // independently and uniformly chosen ports (up to a second-order term in ε).
-            //
-            // Checking 150 times gives us 99.999% success at utilizing 85% of ports under these
-            // conditions, when ε=0.15 and δ=0.00001.
-            for _ in 0..150 {
-                let port = fastrand::u16(self.port_range.clone());
-                match try_bind(port).await {
-                    Ok(listener) => return Ok(listener),
-                    Err(_) => continue,
-                }
-            }
-            Err("failed to find an available port")
+impl std::error::Error for ServerError {}
+
+/// Point-in-time snapshot of the state of a server, for monitoring.
+#[derive(Clone, Debug)]
+pub struct ServerStats {
+    /// Public port and client address of each tunnel that is listening.
+    ///
+    /// The address is `None` for clients connected over a Unix socket.
+    pub listeners: Vec<(u16, Option<SocketAddr>)>,
+
+    /// Number of incoming connections waiting to be accepted by a client.
+    pub pending_connections: usize,
+
+    /// Ports of the port range that tunnels listen on, and the ports in the range.
+    pub port_utilization: (usize, usize),
+
+    /// Time since the server started listening, or zero if it has not yet.
+    pub uptime: Duration,
+}
+
+/// Handle for reading [`ServerStats`] while the server is listening.
+#[derive(Clone)]
+pub struct StatsHandle {
+    port_range: RangeInclusive<u16>,
+    port_owners: Arc<PortOwners>,
+    conns: Arc<DashMap<Uuid, Pending>>,
+    started: Arc<OnceLock<Instant>>,
+}
+
+impl StatsHandle {
+    /// Take a snapshot of the state of the server.
+    ///
+    /// Each map is read without locking the others, so the snapshot may not be
+    /// exactly consistent while tunnels are opening or closing.
+    pub fn stats(&self) -> ServerStats {
+        let mut listeners: Vec<_> = self
+            .port_owners
+            .iter()
+            .map(|entry| (entry.key().0, entry.remote_addr))
+            .collect();
+        listeners.sort_unstable();
+        ServerStats {
+            listeners,
+            pending_connections: self.conns.len(),
+            port_utilization: self.port_utilization(),
+            uptime: self.started.get().map_or(Duration::ZERO, Instant::elapsed),
         }
     }
 
-    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        let remote_addr = stream.peer_addr().ok();
-        let port_owners = Arc::clone(&self.port_owners);
-        let conns = Arc::clone(&self.conns);
-        let mut stream = Delimited::new(stream);
-        if let Some(auth) = &self.auth {
-            if let Err(err) = auth.server_handshake(&mut stream).await {
-                warn!(%err, "server handshake failed");
-                stream.send(ServerMessage::Error(err.to_string())).await?;
-                return Ok(());
-            }
+    /// Count the ports of the port range that tunnels listen on, and the ports in the range.
+    ///
+    /// Tunnels on ports outside the range, such as those of secrets with their own
+    /// range, are not counted.
+    pub fn port_utilization(&self) -> (usize, usize) {
+        // A port may have several owners, such as TCP and UDP tunnels of different clients.
+        let used = self
+            .port_owners
+            .iter()
+            .map(|entry| entry.key().0)
+            .filter(|port| self.port_range.contains(port))
+            .collect::<HashSet<_>>()
+            .len();
+        let total = self.port_range.len();
+        (used, total)
+    }
+}
+
+/// Builder for a [`Server`], validating its configuration when it is built.
+///
+/// Each method corresponds to a setter on [`Server`]. Errors from setters that can
+/// fail are kept until [`ServerBuilder::build`], which reports the first of them.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use std::time::Duration;
+///
+/// use bore_cli::server::Server;
+///
+/// let server = Server::builder(1024..=65535, Some("secret"))
+///     .max_tunnels(100)
+///     .accept_timeout(Duration::from_secs(5))
+///     .build()?;
+/// server.listen().await
+/// # }
+/// ```
+#[must_use]
+pub struct ServerBuilder {
+    /// Server being configured, or the first error while configuring it.
+    server: Result<Server>,
+}
+
+impl ServerBuilder {
+    /// Start building a server that forwards ports in this range.
+    ///
+    /// An empty port range is reported by [`ServerBuilder::build`].
+    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
+        ServerBuilder {
+            server: Server::try_new(port_range, secret).map_err(Into::into),
         }
+    }
 
-        match stream.recv_timeout().await? {
-            Some(ClientMessage::Authenticate(_)) => {
-                warn!("unexpected authenticate");
-                Ok(())
-            }
-            Some(ClientMessage::Hello(port)) => {
-                // Before creating listener, check for an existing (port, remote_addr) owner
-                if let Some(addr) = remote_addr {
-                    if let Some((_, handle)) = port_owners.remove(&(port, addr)) {
-                        handle.abort(); // abort the old listener task
-                        info!(?port, ?addr, "aborted old listener for this port/addr");
-                    }
-                }
-                let listener = match self.create_listener(port).await {
-                    Ok(listener) => listener,
-                    Err(err) => {
-                        stream.send(ServerMessage::Error(err.into())).await?;
-                        return Ok(());
-                    }
-                };
-                let host = listener.local_addr()?.ip();
-                let port = listener.local_addr()?.port();
-                info!(?host, ?port, "new client");
-                stream.send(ServerMessage::Hello(port)).await?;
-
-                // Spawn and track the listener task for this port/addr
-                let handle = tokio::spawn({
-                    let mut stream = stream;
-                    let listener = listener;
-                    let port = port;
-                    let conns = Arc::clone(&conns);
-                    async move {
-                        loop {
-                            if stream.send(ServerMessage::Heartbeat).await.is_err() {
-                                break;
-                            }
-                            const TIMEOUT: Duration = Duration::from_millis(500);
-                            if let Ok(result) = timeout(TIMEOUT, listener.accept()).await {
-                                let (stream2, addr) = result.unwrap();
-                                info!(?addr, ?port, "new connection");
-                                let id = Uuid::new_v4();
-                                conns.insert(id, stream2);
-                                let conns2 = Arc::clone(&conns);
-                                tokio::spawn(async move {
-                                    sleep(Duration::from_secs(10)).await;
-                                    if conns2.remove(&id).is_some() {
-                                        warn!(%id, "removed stale connection");
-                                    }
-                                });
-                                let _ = stream.send(ServerMessage::Connection(id)).await;
-                            }
-                        }
-                    }
-                });
-                if let Some(addr) = remote_addr {
-                    port_owners.insert((port, addr), handle);
-                }
-                Ok(())
-            }
-            Some(ClientMessage::Accept(id)) => {
-                info!(%id, "forwarding connection");
-                match self.conns.remove(&id) {
-                    Some((_, mut stream2)) => {
-                        let parts = stream.into_parts();
-                        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
-                        stream2.write_all(&parts.read_buf).await?;
-                        proxy(parts.io, stream2).await?
-                    }
-                    None => warn!(%id, "missing connection"),
-                }
-                Ok(())
+    /// Check the configuration with [`Server::validate`], and return the server.
+    pub fn build(self) -> Result<Server> {
+        let server = self.server?;
+        server.validate()?;
+        Ok(server)
+    }
+
+    /// Apply a setter, unless an earlier one failed.
+    fn apply(self, set: impl FnOnce(&mut Server)) -> Self {
+        self.try_apply(|server| {
+            set(server);
+            Ok(())
+        })
+    }
+
+    /// Apply a setter that can fail, keeping its error.
+    fn try_apply(mut self, set: impl FnOnce(&mut Server) -> Result<()>) -> Self {
+        if let Ok(server) = &mut self.server {
+            if let Err(err) = set(server) {
+                self.server = Err(err);
             }
-            None => Ok(()),
         }
+        self
+    }
+
+    /// Accept clients with another secret, who may only forward ports in this range.
+    ///
+    /// See [`Server::add_secret`].
+    pub fn add_secret(self, secret: &str, port_range: RangeInclusive<u16>) -> Self {
+        self.apply(|server| server.add_secret(secret, port_range))
+    }
+
+    /// Enable admin commands, such as listing tunnels, for clients with this secret.
+    ///
+    /// See [`Server::set_admin_secret`].
+    pub fn admin_secret(self, secret: &str) -> Self {
+        self.apply(|server| server.set_admin_secret(secret))
+    }
+
+    /// Accept clients with the secrets in a file, each restricted to a range of ports.
+    ///
+    /// See [`Server::set_secrets_file`].
+    pub fn secrets_file(self, path: impl Into<PathBuf>) -> Self {
+        self.try_apply(|server| server.set_secrets_file(path))
+    }
+
+    /// Limit what the clients of a secret may use at once, across all of their connections.
+    ///
+    /// See [`Server::set_secret_quota`].
+    pub fn secret_quota(self, secret: &str, quota: Quota) -> Self {
+        self.apply(|server| server.set_secret_quota(secret, quota))
+    }
+
+    /// Set the IP address where the control server will bind to.
+    ///
+    /// See [`Server::set_bind_addr`].
+    pub fn bind_addr(self, bind_addr: IpAddr) -> Self {
+        self.apply(|server| server.set_bind_addr(bind_addr))
+    }
+
+    /// Refuse to start unless clients must authenticate with a secret.
+    ///
+    /// See [`Server::set_require_secret`].
+    pub fn require_secret(self, require_secret: bool) -> Self {
+        self.apply(|server| server.set_require_secret(require_secret))
+    }
+
+    /// Bind the control server to loopback and tunnels to all interfaces, and require a secret.
+    ///
+    /// See [`Server::secure_defaults`].
+    pub fn secure_defaults(self) -> Self {
+        self.apply(Server::secure_defaults)
+    }
+
+    /// Set the TCP port where the control server will listen on.
+    ///
+    /// See [`Server::set_control_port`].
+    pub fn control_port(self, control_port: u16) -> Self {
+        self.apply(|server| server.set_control_port(control_port))
+    }
+
+    /// Set the public hostname that clients are told to share with others.
+    ///
+    /// See [`Server::set_advertised_host`].
+    pub fn advertised_host(self, host: impl Into<String>) -> Self {
+        self.apply(|server| server.set_advertised_host(host))
+    }
+
+    /// Set the IP address where tunnels will listen on.
+    ///
+    /// See [`Server::set_bind_tunnels`].
+    pub fn bind_tunnels(self, bind_tunnels: IpAddr) -> Self {
+        self.apply(|server| server.set_bind_tunnels(bind_tunnels))
+    }
+
+    /// Add another IP address where tunnels will listen on.
+    ///
+    /// See [`Server::add_bind_tunnel`].
+    pub fn add_bind_tunnel(self, bind_tunnel: IpAddr) -> Self {
+        self.apply(|server| server.add_bind_tunnel(bind_tunnel))
+    }
+
+    /// Let clients ask for their tunnels to listen on one of these IP addresses.
+    ///
+    /// See [`Server::set_client_bind_tunnels`].
+    pub fn client_bind_tunnels(self, addrs: Vec<IpAddr>) -> Self {
+        self.apply(|server| server.set_client_bind_tunnels(addrs))
+    }
+
+    /// Restrict tunnels to a network interface by name, such as `eth0`.
+    ///
+    /// See [`Server::set_bind_interface`].
+    #[cfg(target_os = "linux")]
+    pub fn bind_interface(self, name: impl Into<String>) -> Self {
+        self.apply(|server| server.set_bind_interface(name))
+    }
+
+    /// Set whether TCP listeners are bound with `SO_REUSEADDR`.
+    ///
+    /// See [`Server::set_reuse_address`].
+    pub fn reuse_address(self, reuse_address: bool) -> Self {
+        self.apply(|server| server.set_reuse_address(reuse_address))
+    }
+
+    /// Set whether TCP listeners are bound with `SO_REUSEPORT`.
+    ///
+    /// See [`Server::set_reuse_port`].
+    #[cfg(unix)]
+    pub fn reuse_port(self, reuse_port: bool) -> Self {
+        self.apply(|server| server.set_reuse_port(reuse_port))
+    }
+
+    /// Set whether accepted TCP connections are set to `TCP_NODELAY`.
+    ///
+    /// See [`Server::set_nodelay`].
+    pub fn nodelay(self, nodelay: bool) -> Self {
+        self.apply(|server| server.set_nodelay(nodelay))
+    }
+
+    /// Enable TCP keepalive on accepted connections, with these settings.
+    ///
+    /// See [`Server::set_keepalive`].
+    pub fn keepalive(self, keepalive: Keepalive) -> Self {
+        self.apply(|server| server.set_keepalive(keepalive))
+    }
+
+    /// Set whether clients may compress the data of their TCP connections.
+    ///
+    /// See [`Server::set_compression`].
+    pub fn compression(self, compression: bool) -> Self {
+        self.apply(|server| server.set_compression(compression))
+    }
+
+    /// Set how long to wait for forwarded connections to finish during shutdown.
+    ///
+    /// See [`Server::set_drain_timeout`].
+    pub fn drain_timeout(self, drain_timeout: Duration) -> Self {
+        self.apply(|server| server.set_drain_timeout(drain_timeout))
+    }
+
+    /// Shut down once the server has had no tunnels for this long.
+    ///
+    /// See [`Server::set_exit_when_idle`].
+    pub fn exit_when_idle(self, idle: Duration) -> Self {
+        self.apply(|server| server.set_exit_when_idle(idle))
+    }
+
+    /// Keep the server running for at least this long, even when it is idle.
+    ///
+    /// See [`Server::set_min_uptime`].
+    pub fn min_uptime(self, min_uptime: Duration) -> Self {
+        self.apply(|server| server.set_min_uptime(min_uptime))
+    }
+
+    /// Limit the number of connections each tunnel can have pending or forwarding at once.
+    ///
+    /// See [`Server::set_max_conns_per_port`].
+    pub fn max_conns_per_port(self, max_conns: usize) -> Self {
+        self.apply(|server| server.set_max_conns_per_port(max_conns))
+    }
+
+    /// Queue up to this many connections on each tunnel while they wait for the client.
+    ///
+    /// See [`Server::set_pending_queue_depth`].
+    pub fn pending_queue_depth(self, depth: usize) -> Self {
+        self.apply(|server| server.set_pending_queue_depth(depth))
+    }
+
+    /// Limit the number of connections waiting for a client to accept them, across all tunnels.
+    ///
+    /// See [`Server::set_max_pending_connections`].
+    pub fn max_pending_connections(self, max_pending: usize) -> Self {
+        self.apply(|server| server.set_max_pending_connections(max_pending))
+    }
+
+    /// Limit the number of tunnels that may be open at once, across all clients.
+    ///
+    /// See [`Server::set_max_tunnels`].
+    pub fn max_tunnels(self, max_tunnels: usize) -> Self {
+        self.apply(|server| server.set_max_tunnels(max_tunnels))
+    }
+
+    /// Log a warning when tunnels take more than this fraction of the port range.
+    ///
+    /// See [`Server::set_port_utilization_warning`].
+    pub fn port_utilization_warning(self, fraction: f64) -> Self {
+        self.apply(|server| server.set_port_utilization_warning(fraction))
     }
+
+    /// Set how long an incoming connection waits for the client to accept it.
+    ///
+    /// See [`Server::set_accept_timeout`].
+    pub fn accept_timeout(self, accept_timeout: impl Into<Option<Duration>>) -> Self {
+        self.apply(|server| server.set_accept_timeout(accept_timeout))
+    }
+
+    /// Write a message to TCP connections that no client accepts in time, before closing them.
+    ///
+    /// See [`Server::set_unavailable_banner`].
+    pub fn unavailable_banner(self, banner: impl Into<Bytes>) -> Self {
+        self.apply(|server| server.set_unavailable_banner(banner))
+    }
+
+    /// Set how often heartbeats are sent to each client, which is 500 ms by default.
+    ///
+    /// See [`Server::set_heartbeat_interval`].
+    pub fn heartbeat_interval(self, heartbeat_interval: Duration) -> Self {
+        self.apply(|server| server.set_heartbeat_interval(heartbeat_interval))
+    }
+
+    /// Close forwarded TCP connections that transfer no data in either direction for this long.
+    ///
+    /// See [`Server::set_connection_idle_timeout`].
+    pub fn connection_idle_timeout(self, idle_timeout: Duration) -> Self {
+        self.apply(|server| server.set_connection_idle_timeout(idle_timeout))
+    }
+
+    /// Limit how fast each TCP tunnel can transfer data, in both directions combined.
+    ///
+    /// See [`Server::set_bandwidth_limit`].
+    pub fn bandwidth_limit(self, bytes_per_sec: u64, burst: u64) -> Self {
+        self.apply(|server| server.set_bandwidth_limit(bytes_per_sec, burst))
+    }
+
+    /// Close tunnels that receive no connections for this long, freeing their ports.
+    ///
+    /// See [`Server::set_idle_tunnel_timeout`].
+    pub fn idle_tunnel_timeout(self, idle_timeout: Duration) -> Self {
+        self.apply(|server| server.set_idle_tunnel_timeout(idle_timeout))
+    }
+
+    /// Close tunnels once they have been open for this long, so that clients reconnect.
+    ///
+    /// See [`Server::set_max_tunnel_lifetime`].
+    pub fn max_tunnel_lifetime(self, lifetime: Duration) -> Self {
+        self.apply(|server| server.set_max_tunnel_lifetime(lifetime))
+    }
+
+    /// Set how many random ports to try when a client requests any available port.
+    ///
+    /// See [`Server::set_port_probe_attempts`].
+    pub fn port_probe_attempts(self, attempts: usize) -> Self {
+        self.apply(|server| server.set_port_probe_attempts(attempts))
+    }
+
+    /// Set how ports are chosen when a client requests any available port.
+    ///
+    /// See [`Server::set_allocation_strategy`].
+    pub fn allocation_strategy(self, strategy: AllocationStrategy) -> Self {
+        self.apply(|server| server.set_allocation_strategy(strategy))
+    }
+
+    /// Set who may take over a specific port that another tunnel is listening on.
+    ///
+    /// See [`Server::set_port_takeover`].
+    pub fn port_takeover(self, policy: PortTakeover) -> Self {
+        self.apply(|server| server.set_port_takeover(policy))
+    }
+
+    /// Assign ports from this range first when a client requests any available port.
+    ///
+    /// See [`Server::set_preferred_port_range`].
+    pub fn preferred_port_range(self, port_range: RangeInclusive<u16>) -> Self {
+        self.apply(|server| server.set_preferred_port_range(port_range))
+    }
+
+    /// Limit how quickly each IP address can open control connections.
+    ///
+    /// See [`Server::set_control_rate_limit`].
+    pub fn control_rate_limit(self, per_sec: u32, burst: u32) -> Self {
+        self.apply(|server| server.set_control_rate_limit(per_sec, burst))
+    }
+
+    /// Hold the TCP tunnels of clients with a reservation token after they disconnect.
+    ///
+    /// See [`Server::set_reservation_grace`].
+    pub fn reservation_grace(self, grace: Duration) -> Self {
+        self.apply(|server| server.set_reservation_grace(grace))
+    }
+
+    /// Limit how quickly each tunnel accepts new connections, from all addresses combined.
+    ///
+    /// See [`Server::set_tunnel_rate_limit`].
+    pub fn tunnel_rate_limit(self, per_sec: u32, burst: u32) -> Self {
+        self.apply(|server| server.set_tunnel_rate_limit(per_sec, burst))
+    }
+
+    /// Decide with this function which connections on tunnels are accepted.
+    ///
+    /// See [`Server::set_accept_filter`].
+    pub fn accept_filter(
+        self,
+        filter: impl Fn(u16, SocketAddr) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.apply(|server| server.set_accept_filter(filter))
+    }
+
+    /// Only accept control connections from addresses in these networks.
+    ///
+    /// See [`Server::set_ip_allowlist`].
+    pub fn ip_allowlist(self, allowlist: Vec<IpNet>) -> Self {
+        self.apply(|server| server.set_ip_allowlist(allowlist))
+    }
+
+    /// Refuse control connections from addresses in these networks.
+    ///
+    /// See [`Server::set_ip_denylist`].
+    pub fn ip_denylist(self, denylist: Vec<IpNet>) -> Self {
+        self.apply(|server| server.set_ip_denylist(denylist))
+    }
+
+    /// Send a PROXY protocol header to the client ahead of each forwarded TCP connection.
+    ///
+    /// See [`Server::set_proxy_protocol`].
+    pub fn proxy_protocol(self, version: ProxyProtocol) -> Self {
+        self.apply(|server| server.set_proxy_protocol(version))
+    }
+
+    /// Generate the IDs of incoming connections with this function.
+    ///
+    /// See [`Server::set_id_generator`].
+    pub fn id_generator(self, generator: impl Fn() -> Uuid + Send + Sync + 'static) -> Self {
+        self.apply(|server| server.set_id_generator(generator))
+    }
+
+    /// Send structured [`Event`]s about tunnels and connections to this channel.
+    ///
+    /// See [`Server::set_event_sink`].
+    pub fn event_sink(self, sink: mpsc::UnboundedSender<Event>) -> Self {
+        self.apply(|server| server.set_event_sink(sink))
+    }
+
+    /// Route HTTP connections on this port to named tunnels, by their `Host` header.
+    ///
+    /// See [`Server::set_http_port`].
+    pub fn http_port(self, port: u16) -> Self {
+        self.apply(|server| server.set_http_port(port))
+    }
+
+    /// Post tunnel events as JSON to an HTTP endpoint, such as `http://localhost:8080/hook`.
+    ///
+    /// See [`Server::set_webhook`].
+    pub fn webhook(self, url: &str) -> Self {
+        self.try_apply(|server| server.set_webhook(url))
+    }
+
+    /// Serve Prometheus metrics over HTTP at `/metrics` on this address.
+    ///
+    /// See [`Server::set_metrics_addr`].
+    pub fn metrics_addr(self, metrics_addr: SocketAddr) -> Self {
+        self.apply(|server| server.set_metrics_addr(metrics_addr))
+    }
+
+    /// Serve a health check for load balancers over HTTP at `/health` on this address.
+    ///
+    /// See [`Server::set_health_addr`].
+    pub fn health_addr(self, health_addr: SocketAddr) -> Self {
+        self.apply(|server| server.set_health_addr(health_addr))
+    }
+
+    /// Accept control connections on a Unix socket at this path, instead of on TCP.
+    ///
+    /// See [`Server::set_control_socket`].
+    #[cfg(unix)]
+    pub fn control_socket(self, path: impl Into<PathBuf>) -> Self {
+        self.apply(|server| server.set_control_socket(path))
+    }
+
+    /// Accept control connections on a TCP listener that is already bound, instead of binding one.
+    ///
+    /// See [`Server::set_control_listener`].
+    pub fn control_listener(self, listener: std::net::TcpListener) -> Self {
+        self.apply(|server| server.set_control_listener(listener))
+    }
+
+    /// Accept control connections from an in-memory transport, instead of binding a port.
+    ///
+    /// See [`Server::set_memory_listener`].
+    pub fn memory_listener(self, listener: MemoryListener) -> Self {
+        self.apply(|server| server.set_memory_listener(listener))
+    }
+}
+
+/// Listener accepting control connections from clients.
+enum ControlListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Memory(MemoryListener),
+}
+
+/// Control connection accepted from a [`ControlListener`].
+enum ControlStream {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Memory(DuplexStream),
+}
+
+impl ControlListener {
+    async fn accept(&mut self) -> io::Result<ControlStream> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok(ControlStream::Tcp(stream, addr))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => Ok(ControlStream::Unix(listener.accept().await?.0)),
+            Self::Memory(listener) => match listener.accept().await {
+                Some(stream) => Ok(ControlStream::Memory(stream)),
+                None => future::pending().await,
+            },
+        }
+    }
+}
+
+/// Limits on what the clients of one secret may use at once.
+///
+/// See [`Server::set_secret_quota`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Quota {
+    /// Maximum number of tunnels open at once, or `None` for no limit.
+    pub max_tunnels: Option<usize>,
+
+    /// Maximum number of connections pending or forwarding at once, or `None` for no limit.
+    pub max_connections: Option<usize>,
+}
+
+/// Slots of a [`Quota`], shared by every client of its secret.
+#[derive(Clone)]
+struct QuotaSlots {
+    tunnels: Option<Arc<Semaphore>>,
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl QuotaSlots {
+    fn new(quota: Quota) -> Self {
+        let slots = |max: Option<usize>| max.map(|max| Arc::new(Semaphore::new(max)));
+        QuotaSlots {
+            tunnels: slots(quota.max_tunnels),
+            connections: slots(quota.max_connections),
+        }
+    }
+}
+
+/// What an authenticated client is allowed to use, and how it is known.
+struct Grant {
+    /// Ports that the client may forward.
+    port_range: RangeInclusive<u16>,
+
+    /// Quota of the client's secret, if it has one.
+    quota: Option<QuotaSlots>,
+
+    /// Label that the client gave itself, once sanitized, if any.
+    label: Option<String>,
+
+    /// Address that the client's tunnels listen on instead of the tunnel addresses, if any.
+    bind_addr: Option<IpAddr>,
+}
+
+/// An incoming connection waiting to be accepted by the client.
+struct Pending {
+    /// The connection or session itself.
+    incoming: Incoming,
+
+    /// Address of the remote peer that connected to the tunnel.
+    peer_addr: SocketAddr,
+
+    /// Held until the connection closes when the tunnel has a connection limit or quota.
+    _permit: ConnectionPermits,
+
+    /// Place in the tunnel's queue, held until the client accepts the connection.
+    queued: Option<OwnedSemaphorePermit>,
+
+    /// Place in the server's limit on pending connections, released with `queued`.
+    pending_slot: Option<OwnedSemaphorePermit>,
+
+    /// Bandwidth budget of the tunnel, if limited.
+    bandwidth: Option<Arc<Bandwidth>>,
+
+    /// Activity on the tunnel, which counts the connection once it is forwarded.
+    activity: Arc<Activity>,
+}
+
+/// Transport-specific state of an incoming connection.
+enum Incoming {
+    /// A TCP connection accepted on a tunnel listener.
+    Tcp(TcpStream),
+
+    /// A connection on the shared HTTP port, with the request head already read.
+    Http(TcpStream, Vec<u8>),
+
+    /// A UDP session opened by the first datagram from a new remote address.
+    Udp(UdpSession),
+}
+
+/// Datagrams exchanged with a single remote address on a UDP tunnel.
+struct UdpSession {
+    /// Socket bound on the public port, shared by all sessions of the tunnel.
+    socket: Arc<UdpSocket>,
+
+    /// Address of the remote peer.
+    peer: SocketAddr,
+
+    /// Datagrams received from the peer that are yet to be forwarded.
+    datagrams: mpsc::Receiver<Bytes>,
+}
+
+impl Server {
+    /// Create a new server with a specified minimum port number.
+    ///
+    /// An empty port range is reported by [`Server::validate`], and so when the server
+    /// starts listening. Use [`Server::try_new`] to handle it right away instead.
+    pub fn new(port_range: RangeInclusive<u16>, secret: Option<&str>) -> Self {
+        let max_tunnels = port_range.len();
+        Server {
+            port_range,
+            conns: Arc::new(DashMap::new()),
+            auth: secret.map(Authenticator::new),
+            secrets: Vec::new(),
+            quotas: HashMap::new(),
+            secrets_file: None,
+            port_owners: Arc::new(DashMap::new()),
+            reservations: Arc::new(DashMap::new()),
+            reservation_grace: None,
+            started: Arc::new(OnceLock::new()),
+            max_tunnels,
+            tunnel_slots: Arc::new(Semaphore::new(max_tunnels)),
+            port_utilization_warning: None,
+            port_insert: Mutex::new(()),
+            pending_slots: None,
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            control_port: CONTROL_PORT,
+            advertised_host: None,
+            bind_tunnels: Vec::new(),
+            client_bind_tunnels: Vec::new(),
+            shutdown: CancellationToken::new(),
+            drain_timeout: Duration::from_secs(10),
+            exit_when_idle: None,
+            min_uptime: Duration::ZERO,
+            max_conns_per_port: None,
+            pending_queue_depth: None,
+            accept_timeout: Some(Duration::from_secs(10)),
+            unavailable_banner: None,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            connection_idle_timeout: None,
+            bandwidth_limit: None,
+            idle_tunnel_timeout: None,
+            max_tunnel_lifetime: None,
+            port_probe_attempts: 150,
+            preferred_ports: None,
+            allocation_strategy: AllocationStrategy::default(),
+            port_takeover: PortTakeover::default(),
+            control_rate_limit: None,
+            tunnel_rate_limit: None,
+            accept_filter: None,
+            ip_allowlist: Vec::new(),
+            ip_denylist: Vec::new(),
+            proxy_protocol: None,
+            events: EventSinks::new(),
+            id_generator: Arc::new(Uuid::new_v4),
+            http_port: None,
+            named_tunnels: Arc::new(DashMap::new()),
+            webhook: None,
+            metrics: Arc::new(Metrics::default()),
+            metrics_addr: None,
+            health_addr: None,
+            #[cfg(unix)]
+            control_socket: None,
+            control_listener: None,
+            memory_listener: Mutex::new(None),
+            #[cfg(target_os = "linux")]
+            bind_interface: None,
+            admin_auth: None,
+            require_secret: false,
+            reuse_address: cfg!(unix), // as in `TcpListener::bind`
+            #[cfg(unix)]
+            reuse_port: false,
+            nodelay: false,
+            keepalive: None,
+            compression: false,
+        }
+    }
+
+    /// Start building a server, as an alternative to calling setters on [`Server::new`].
+    pub fn builder(port_range: RangeInclusive<u16>, secret: Option<&str>) -> ServerBuilder {
+        ServerBuilder::new(port_range, secret)
+    }
+
+    /// Create a new server, failing if the port range is empty.
+    pub fn try_new(
+        port_range: RangeInclusive<u16>,
+        secret: Option<&str>,
+    ) -> Result<Self, ServerError> {
+        if port_range.is_empty() {
+            return Err(ServerError::EmptyPortRange);
+        }
+        Ok(Self::new(port_range, secret))
+    }
+
+    /// Accept clients with another secret, who may only forward ports in this range.
+    ///
+    /// Once any secret is configured, clients must authenticate with one of them.
+    /// The main secret passed to [`Server::new`] keeps the default port range. An
+    /// empty range is reported by [`Server::validate`].
+    pub fn add_secret(&mut self, secret: &str, port_range: RangeInclusive<u16>) {
+        self.secrets.push((Authenticator::new(secret), port_range));
+    }
+
+    /// Enable admin commands, such as listing tunnels, for clients with this secret.
+    ///
+    /// Admins authenticate with this secret after the usual handshake, so if the server
+    /// requires a client secret, they need one too. Admin commands are disabled by default.
+    pub fn set_admin_secret(&mut self, secret: &str) {
+        self.admin_auth = Some(Authenticator::new(secret));
+    }
+
+    /// Accept clients with the secrets in a file, each restricted to a range of ports.
+    ///
+    /// Each line of the file has the form `MIN-MAX SECRET`, and blank lines and lines
+    /// starting with `#` are ignored. While listening, the file is checked for changes
+    /// every second and reloaded, so secrets can be rotated without a restart. Clients
+    /// that are already connected stay connected. If the file becomes invalid, the
+    /// previous secrets are kept. Like [`Server::add_secret`], this requires every
+    /// client to authenticate.
+    pub fn set_secrets_file(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        self.secrets_file = Some(Arc::new(SecretsFile::load(path.into())?));
+        Ok(())
+    }
+
+    /// Limit what the clients of a secret may use at once, across all of their connections.
+    ///
+    /// The secret may be the main one, one added with [`Server::add_secret`], or one in
+    /// the secrets file. Clients are told when they exceed the quota: a tunnel
+    /// beyond it is refused, and so is a connection beyond it, which is closed.
+    pub fn set_secret_quota(&mut self, secret: &str, quota: Quota) {
+        let key_id = Authenticator::new(secret).key_id().to_string();
+        self.quotas.insert(key_id, QuotaSlots::new(quota));
+    }
+
+    /// Set the IP address where the control server will bind to.
+    ///
+    /// If this address is reachable from other hosts and no secret is configured,
+    /// the server logs a warning on startup, as anyone can then open tunnels.
+    pub fn set_bind_addr(&mut self, bind_addr: IpAddr) {
+        self.bind_addr = bind_addr;
+    }
+
+    /// Refuse to start unless clients must authenticate with a secret.
+    ///
+    /// Any secret counts: the main one, one added with [`Server::add_secret`], or a
+    /// secrets file. This catches a deployment that lost its secret, which would
+    /// otherwise open the server to anyone. Off by default.
+    pub fn set_require_secret(&mut self, require_secret: bool) {
+        self.require_secret = require_secret;
+    }
+
+    /// Bind the control server to loopback and tunnels to all interfaces, and require a secret.
+    ///
+    /// This is the safest layout for a server on a public host: only clients on the
+    /// same host, or those that reach it through an SSH tunnel or a reverse proxy, can
+    /// open tunnels, while the tunnels themselves stay public. Settings made afterwards,
+    /// such as [`Server::set_bind_addr`], take precedence.
+    pub fn secure_defaults(&mut self) {
+        self.set_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        self.set_bind_tunnels(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        self.set_require_secret(true);
+    }
+
+    /// Set the TCP port where the control server will listen on.
+    ///
+    /// Clients must be configured with the same port. The default is [`CONTROL_PORT`].
+    pub fn set_control_port(&mut self, control_port: u16) {
+        self.control_port = control_port;
+    }
+
+    /// Set the public hostname that clients are told to share with others.
+    ///
+    /// Clients print this with the port of their tunnel, instead of the address they
+    /// used to reach the server, which may be internal or a bare IP address. Clients
+    /// before protocol version 5 are not told. The hostname is sent in one control
+    /// message, so [`Server::validate`] rejects one that does not fit, of more than
+    /// about 240 bytes. By default, no hostname is advertised.
+    pub fn set_advertised_host(&mut self, host: impl Into<String>) {
+        self.advertised_host = Some(host.into());
+    }
+
+    /// Set the IP address where tunnels will listen on.
+    pub fn set_bind_tunnels(&mut self, bind_tunnels: IpAddr) {
+        self.bind_tunnels = vec![bind_tunnels];
+    }
+
+    /// Let clients ask for their tunnels to listen on one of these IP addresses.
+    ///
+    /// A client that asks for an address in this list gets its TCP and UDP tunnels
+    /// bound to that address alone, instead of every tunnel address, which lets clients
+    /// of a multi-homed server pick the network they are reachable on. Other addresses
+    /// fall back to the tunnel addresses, with a warning. Named tunnels share the HTTP
+    /// port, so they are not affected. By default, the list is empty, and clients
+    /// cannot choose.
+    pub fn set_client_bind_tunnels(&mut self, addrs: Vec<IpAddr>) {
+        self.client_bind_tunnels = addrs;
+    }
+
+    /// Add another IP address where tunnels will listen on.
+    ///
+    /// Each tunnel is bound to the same port on every address, and connections
+    /// from all of them are forwarded to the client.
+    pub fn add_bind_tunnel(&mut self, bind_tunnel: IpAddr) {
+        self.bind_tunnels.push(bind_tunnel);
+    }
+
+    /// Restrict tunnels to a network interface by name, such as `eth0`.
+    ///
+    /// Tunnel sockets are bound to the interface with `SO_BINDTODEVICE`, so they
+    /// follow its addresses as they change. This applies on top of the addresses
+    /// where tunnels listen on, which accept any address of the interface by default.
+    #[cfg(target_os = "linux")]
+    pub fn set_bind_interface(&mut self, name: impl Into<String>) {
+        self.bind_interface = Some(name.into());
+    }
+
+    /// Set whether TCP listeners are bound with `SO_REUSEADDR`.
+    ///
+    /// This applies to the control port and tunnels. It is on by default on Unix, so
+    /// a restarted server can bind ports that still have connections closing, and off
+    /// on Windows, where it lets other sockets take over a bound port.
+    pub fn set_reuse_address(&mut self, reuse_address: bool) {
+        self.reuse_address = reuse_address;
+    }
+
+    /// Set whether TCP listeners are bound with `SO_REUSEPORT`, which is off by default.
+    ///
+    /// This lets several server processes on the same host share the control port, with
+    /// the kernel spreading new connections across them, and likewise the HTTP port.
+    /// Tunnel ports are never shared, so give each process its own port range. Each
+    /// process manages its own tunnels, so a client that reconnects may reach one that
+    /// does not hold its old port.
+    #[cfg(unix)]
+    pub fn set_reuse_port(&mut self, reuse_port: bool) {
+        self.reuse_port = reuse_port;
+    }
+
+    /// Set whether accepted TCP connections are set to `TCP_NODELAY`, which is off by default.
+    ///
+    /// This sends small writes right away instead of batching them, for latency-sensitive
+    /// traffic. It applies to connections on tunnels and the shared HTTP port, and to
+    /// control connections, including those that carry forwarded streams.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Enable TCP keepalive on accepted connections, with these settings.
+    ///
+    /// The kernel then probes connections that have been idle for a while, and drops
+    /// them if the peer stopped answering. This applies to the same connections as
+    /// [`Server::set_nodelay`]. Keepalive is off by default.
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = Some(keepalive);
+    }
+
+    /// Set whether clients may compress the data of their TCP connections, which is off
+    /// by default.
+    ///
+    /// Clients that ask for it send each forwarded connection as a deflate stream, which
+    /// the server decompresses before it reaches the remote peer. This saves bandwidth on
+    /// text-heavy traffic at the cost of CPU time. Clients need protocol version 9.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// Set how long to wait for forwarded connections to finish during shutdown.
+    pub fn set_drain_timeout(&mut self, drain_timeout: Duration) {
+        self.drain_timeout = drain_timeout;
+    }
+
+    /// Shut down once the server has had no tunnels for this long.
+    ///
+    /// The server then returns from [`Server::listen`] as on a graceful shutdown, which
+    /// suits deployments that start it on demand, such as with socket activation. Held
+    /// tunnels count as open, and the idle period starts over whenever a tunnel opens.
+    /// By default, the server runs until it is stopped.
+    pub fn set_exit_when_idle(&mut self, idle: Duration) {
+        self.exit_when_idle = Some(idle);
+    }
+
+    /// Keep the server running for at least this long, even when it is idle.
+    ///
+    /// This keeps a server that exits when idle from being restarted over and over
+    /// when clients come and go. The default is no minimum.
+    pub fn set_min_uptime(&mut self, min_uptime: Duration) {
+        self.min_uptime = min_uptime;
+    }
+
+    /// Limit the number of connections each tunnel can have pending or forwarding at once.
+    ///
+    /// Connections beyond the limit are closed immediately. By default there is no limit.
+    pub fn set_max_conns_per_port(&mut self, max_conns: usize) {
+        self.max_conns_per_port = Some(max_conns);
+    }
+
+    /// Queue up to this many connections on each tunnel while they wait for the client.
+    ///
+    /// Queued connections are announced to the client in the order they arrive,
+    /// and wait until it accepts them, the accept timeout passes, or it disconnects.
+    /// Connections beyond the depth are closed immediately, rather than waiting for
+    /// a place. By default there is no queue.
+    pub fn set_pending_queue_depth(&mut self, depth: usize) {
+        self.pending_queue_depth = Some(depth);
+    }
+
+    /// Limit the number of connections waiting for a client to accept them, across all tunnels.
+    ///
+    /// Each pending connection holds an open socket on the server, so this bounds the
+    /// memory that a burst of connections can take, whatever the limits of each tunnel.
+    /// Connections beyond the limit are closed immediately, and forwarded connections
+    /// no longer count. By default there is no limit.
+    pub fn set_max_pending_connections(&mut self, max_pending: usize) {
+        self.pending_slots = Some(Arc::new(Semaphore::new(max_pending)));
+    }
+
+    /// Limit the number of tunnels that may be open at once, across all clients.
+    ///
+    /// Clients that request a tunnel beyond the limit are told that the server is at
+    /// capacity, so they can retry later. The default is the size of the port range.
+    pub fn set_max_tunnels(&mut self, max_tunnels: usize) {
+        self.max_tunnels = max_tunnels;
+        self.tunnel_slots = Arc::new(Semaphore::new(max_tunnels));
+    }
+
+    /// Log a warning when tunnels take more than this fraction of the port range.
+    ///
+    /// The warning is logged by the tunnel that crosses the threshold, as given by
+    /// [`Server::port_utilization`], so operators hear of it before clients asking for
+    /// any port start failing to find a free one. The fraction must be above 0 and at
+    /// most 1. By default, no warning is logged.
+    pub fn set_port_utilization_warning(&mut self, fraction: f64) {
+        self.port_utilization_warning = Some(fraction);
+    }
+
+    /// Set how long an incoming connection waits for the client to accept it.
+    ///
+    /// Connections that are not accepted in time are closed. The default is 10 seconds.
+    ///
+    /// With `None`, the server spawns no task per connection to expire it, which saves
+    /// work when clients reliably accept every connection they are told about. The
+    /// risk is that a connection that is never accepted, for example because its client
+    /// crashed, stays open until the server shuts down. Queued connections are still
+    /// closed when their client disconnects.
+    pub fn set_accept_timeout(&mut self, accept_timeout: impl Into<Option<Duration>>) {
+        self.accept_timeout = accept_timeout.into();
+    }
+
+    /// Write a message to TCP connections that no client accepts in time, before closing them.
+    ///
+    /// This tells users why a tunnel is not answering, such as when its client lost its
+    /// control connection, instead of closing the connection without a word. It applies
+    /// to connections dropped after the accept timeout, and to queued connections whose
+    /// client disconnected first. The banner is written as is, so it can be a complete response
+    /// in the tunnel's protocol. By default, connections are closed without one.
+    pub fn set_unavailable_banner(&mut self, banner: impl Into<Bytes>) {
+        self.unavailable_banner = Some(banner.into());
+    }
+
+    /// Set how often heartbeats are sent to each client, which is 500 ms by default.
+    ///
+    /// Heartbeats keep a steady cadence, regardless of connection activity. Clients
+    /// consider the server lost after their heartbeat timeout, so it should be well
+    /// above this interval. A zero interval is reported by [`Server::validate`].
+    pub fn set_heartbeat_interval(&mut self, heartbeat_interval: Duration) {
+        self.heartbeat_interval = heartbeat_interval;
+    }
+
+    /// Close forwarded TCP connections that transfer no data in either direction for this long.
+    ///
+    /// This protects against half-open connections, whose peer vanished without closing
+    /// them. By default there is no limit. UDP sessions always time out after 60 seconds.
+    pub fn set_connection_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.connection_idle_timeout = Some(idle_timeout);
+    }
+
+    /// Limit how fast each TCP tunnel can transfer data, in both directions combined.
+    ///
+    /// All connections on a tunnel share one budget, which refills at `bytes_per_sec`
+    /// and can save up to `burst` bytes while the tunnel is quiet. By default there is
+    /// no limit. UDP tunnels are not limited. A rate of 0 is reported by
+    /// [`Server::validate`].
+    pub fn set_bandwidth_limit(&mut self, bytes_per_sec: u64, burst: u64) {
+        self.bandwidth_limit = Some((bytes_per_sec, burst));
+    }
+
+    /// Close tunnels that receive no connections for this long, freeing their ports.
+    ///
+    /// On UDP tunnels, any datagram counts as activity. Connections that are already
+    /// being forwarded are not interrupted, and the client is sent an error so it
+    /// can reconnect if it still needs the tunnel. By default, tunnels never expire.
+    pub fn set_idle_tunnel_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_tunnel_timeout = Some(idle_timeout);
+    }
+
+    /// Close tunnels once they have been open for this long, so that clients reconnect.
+    ///
+    /// The client is sent an error, and a client that reconnects gets a fresh port
+    /// unless it asked for a specific one, which rotates random ports. Connections that
+    /// are already being forwarded finish undisturbed. A reserved tunnel keeps the
+    /// lifetime it started with when it is handed to a new control connection. By
+    /// default, tunnels live as long as their control connection.
+    pub fn set_max_tunnel_lifetime(&mut self, lifetime: Duration) {
+        self.max_tunnel_lifetime = Some(lifetime);
+    }
+
+    /// Set how many random ports to try when a client requests any available port.
+    ///
+    /// The default of 150 finds a free port with high probability while at least
+    /// 15% of the range is unused. Nearly full ranges may need more attempts.
+    pub fn set_port_probe_attempts(&mut self, attempts: usize) {
+        self.port_probe_attempts = attempts;
+    }
+
+    /// Set how ports are chosen when a client requests any available port.
+    pub fn set_allocation_strategy(&mut self, strategy: AllocationStrategy) {
+        self.allocation_strategy = strategy;
+    }
+
+    /// Set who may take over a specific port that another tunnel is listening on.
+    ///
+    /// By default, only a client from the same IP address takes the port back.
+    pub fn set_port_takeover(&mut self, policy: PortTakeover) {
+        self.port_takeover = policy;
+    }
+
+    /// Assign ports from this range first when a client requests any available port.
+    ///
+    /// The allocation strategy is applied to the part of the client's port range that
+    /// overlaps this one, and then to the whole range if none of those ports are free.
+    /// This suits ranges where only some ports are reachable through a firewall. An
+    /// empty range is reported by [`Server::validate`].
+    pub fn set_preferred_port_range(&mut self, port_range: RangeInclusive<u16>) {
+        self.preferred_ports = Some(port_range);
+    }
+
+    /// Limit how quickly each IP address can open control connections.
+    ///
+    /// Every address may open `burst` connections at once, refilled at `per_sec`
+    /// connections per second. Connections over the limit are closed before the
+    /// authentication handshake. Clients open a control connection for every
+    /// forwarded connection too, so the limit must allow for a tunnel's traffic.
+    pub fn set_control_rate_limit(&mut self, per_sec: u32, burst: u32) {
+        self.control_rate_limit = Some(RateLimiter::new(per_sec, burst));
+    }
+
+    /// Hold TCP tunnels for this long after their control connection closes, if the
+    /// client sent a reservation token.
+    ///
+    /// A client that reconnects in time with the same token, and asks for the same
+    /// port, takes over the tunnel without its listener ever closing. Connections
+    /// that arrive in the meantime wait to be accepted. Anyone with the token can
+    /// reclaim the ports, within the port range of their own secret. By default,
+    /// tunnels close as soon as their client disconnects.
+    pub fn set_reservation_grace(&mut self, grace: Duration) {
+        self.reservation_grace = Some(grace);
+    }
+
+    /// Limit how quickly each tunnel accepts new connections, from all addresses combined.
+    ///
+    /// Every tunnel may accept `burst` connections at once, refilled at `per_sec`
+    /// connections per second. Connections over the limit are closed immediately,
+    /// before the client is told about them, and new UDP sessions are dropped.
+    /// Named HTTP tunnels are not limited. By default there is no limit, and a rate or
+    /// burst of 0 is rejected by [`Server::validate`].
+    pub fn set_tunnel_rate_limit(&mut self, per_sec: u32, burst: u32) {
+        self.tunnel_rate_limit = Some((per_sec, burst));
+    }
+
+    /// Decide with this function which connections on tunnels are accepted.
+    ///
+    /// The function is called with the public port of the tunnel and the address of
+    /// the remote peer, as soon as a connection arrives. Connections it returns false
+    /// for are closed immediately, before the client is told about them, and a UDP
+    /// session it refuses drops its first datagram. It runs on the accept loop of the
+    /// tunnel, so it should return quickly. By default, every connection is accepted.
+    pub fn set_accept_filter(
+        &mut self,
+        filter: impl Fn(u16, SocketAddr) -> bool + Send + Sync + 'static,
+    ) {
+        self.accept_filter = Some(Arc::new(filter));
+    }
+
+    /// Only accept control connections from addresses in these networks.
+    ///
+    /// An empty list, the default, allows every address. This does not apply to
+    /// connections on a Unix control socket.
+    pub fn set_ip_allowlist(&mut self, allowlist: Vec<IpNet>) {
+        self.ip_allowlist = allowlist;
+    }
+
+    /// Refuse control connections from addresses in these networks.
+    ///
+    /// The denylist takes precedence over the allowlist.
+    pub fn set_ip_denylist(&mut self, denylist: Vec<IpNet>) {
+        self.ip_denylist = denylist;
+    }
+
+    /// Send a PROXY protocol header to the client ahead of each forwarded TCP connection.
+    ///
+    /// The header carries the address of the remote peer, so that a local service
+    /// that understands the protocol, like nginx or HAProxy, can see who connected.
+    pub fn set_proxy_protocol(&mut self, version: ProxyProtocol) {
+        self.proxy_protocol = Some(version);
+    }
+
+    /// Generate the IDs of incoming connections with this function.
+    ///
+    /// IDs must be unique among the connections waiting to be accepted, and hard to
+    /// guess if untrusted clients share the server, as an ID is all it takes to accept
+    /// a connection. This is meant for tests that need predictable IDs, such as
+    /// sequential ones. The default is [`Uuid::new_v4`].
+    pub fn set_id_generator(&mut self, generator: impl Fn() -> Uuid + Send + Sync + 'static) {
+        self.id_generator = Arc::new(generator);
+    }
+
+    /// Send structured [`Event`]s about tunnels and connections to this channel.
+    ///
+    /// Events are never dropped, so the receiver should keep up with them. See
+    /// [`write_json_lines`](crate::events::write_json_lines) for writing them out as JSON lines.
+    pub fn set_event_sink(&mut self, sink: mpsc::UnboundedSender<Event>) {
+        self.events.sink = Some(sink);
+    }
+
+    /// Subscribe to the structured [`Event`]s about tunnels and connections.
+    ///
+    /// Unlike the event sink, subscribers never slow the server down. One that
+    /// falls more than 1024 events behind misses the oldest of them, and its next
+    /// receive reports how many were lost. Subscribe before listening, since that
+    /// consumes the server.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribers.subscribe()
+    }
+
+    /// Route HTTP connections on this port to named tunnels, by their `Host` header.
+    ///
+    /// Clients then request a tunnel by name instead of by port, and a request for
+    /// `name.example.com` is forwarded to the tunnel called `name`. Tunnels with their
+    /// own ports keep working alongside.
+    pub fn set_http_port(&mut self, port: u16) {
+        self.http_port = Some(port);
+    }
+
+    /// Post tunnel events as JSON to an HTTP endpoint, such as `http://localhost:8080/hook`.
+    ///
+    /// Events are posted when a listener is created or aborted and when a connection
+    /// is forwarded or closed, with the same body as in [`Server::set_event_sink`]. Requests are
+    /// sent in the background with a short timeout, and failures are only logged.
+    pub fn set_webhook(&mut self, url: &str) -> Result<()> {
+        self.webhook = Some(Arc::new(Webhook::new(url)?));
+        Ok(())
+    }
+
+    /// Serve Prometheus metrics over HTTP at `/metrics` on this address.
+    pub fn set_metrics_addr(&mut self, metrics_addr: SocketAddr) {
+        self.metrics_addr = Some(metrics_addr);
+    }
+
+    /// Serve a health check for load balancers over HTTP at `/health` on this address.
+    ///
+    /// While the server is accepting connections, this responds with 200 and a JSON
+    /// body such as `{"status":"ok","uptime_secs":60,"tunnels":2}`. It stops
+    /// listening as soon as the server begins shutting down.
+    pub fn set_health_addr(&mut self, health_addr: SocketAddr) {
+        self.health_addr = Some(health_addr);
+    }
+
+    /// Accept control connections on a Unix socket at this path, instead of on TCP.
+    ///
+    /// Tunnels are still bound on TCP or UDP ports. The socket file is removed when
+    /// the server shuts down gracefully.
+    #[cfg(unix)]
+    pub fn set_control_socket(&mut self, path: impl Into<PathBuf>) {
+        self.control_socket = Some(path.into());
+    }
+
+    /// Accept control connections on a TCP listener that is already bound, instead of binding one.
+    ///
+    /// This supports socket activation, where a service manager such as systemd binds
+    /// the control port and passes the listener to the server, allowing restarts that
+    /// never refuse a connection. The bind address and control port are then ignored.
+    pub fn set_control_listener(&mut self, listener: std::net::TcpListener) {
+        self.control_listener = Some(listener);
+    }
+
+    /// Accept control connections from an in-memory transport, instead of binding a port.
+    ///
+    /// Clients reach the server with the other end of [`memory::channel`], which is
+    /// mostly useful in tests. The bind address and control port are then ignored, but
+    /// tunnels still listen on TCP ports.
+    ///
+    /// [`memory::channel`]: crate::memory::channel
+    pub fn set_memory_listener(&mut self, listener: MemoryListener) {
+        *self.memory_listener.get_mut().unwrap() = Some(listener);
+    }
+
+    /// Take a snapshot of the state of the server.
+    pub fn stats(&self) -> ServerStats {
+        self.stats_handle().stats()
+    }
+
+    /// Count the ports of the port range that tunnels listen on, and the ports in the range.
+    pub fn port_utilization(&self) -> (usize, usize) {
+        self.stats_handle().port_utilization()
+    }
+
+    /// Get a handle for reading stats later, since listening consumes the server.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            port_range: self.port_range.clone(),
+            port_owners: Arc::clone(&self.port_owners),
+            conns: Arc::clone(&self.conns),
+            started: Arc::clone(&self.started),
+        }
+    }
+
+    /// Check the configuration for mistakes, without binding or serving anything.
+    ///
+    /// This checks that port ranges are not empty, that bind addresses belong to this
+    /// host, and that the control socket's directory and the network interface exist.
+    /// The same checks run when the server starts listening.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.port_range.is_empty(), ServerError::EmptyPortRange);
+        for (_, port_range) in &self.secrets {
+            ensure!(!port_range.is_empty(), "port range of a secret is empty");
+        }
+        ensure!(
+            !self.heartbeat_interval.is_zero(),
+            "heartbeat interval must be positive"
+        );
+        if let Some((bytes_per_sec, _)) = self.bandwidth_limit {
+            ensure!(bytes_per_sec > 0, "bandwidth limit must be positive");
+        }
+        if let Some(port_range) = &self.preferred_ports {
+            ensure!(
+                !port_range.is_empty(),
+                "preferred port range must not be empty"
+            );
+        }
+        ensure!(
+            !self.require_secret || self.has_secret(),
+            "a secret is required, but none is configured"
+        );
+        if let Some(host) = &self.advertised_host {
+            // Clients read the host in a single frame, so it must fit in one.
+            let frame = serde_json::to_vec(&ServerMessage::Host(host.clone()))?;
+            ensure!(
+                frame.len() <= MAX_FRAME_LENGTH,
+                "advertised host is too long"
+            );
+        }
+        if let Some(fraction) = self.port_utilization_warning {
+            ensure!(
+                fraction > 0.0 && fraction <= 1.0,
+                "port utilization warning must be above 0 and at most 1"
+            );
+        }
+        if let Some((per_sec, burst)) = self.tunnel_rate_limit {
+            ensure!(
+                per_sec > 0 && burst > 0,
+                "tunnel rate limit must be positive"
+            );
+        }
+        let memory_control = self.memory_listener.lock().unwrap().is_some();
+        #[cfg(unix)]
+        let tcp_control =
+            self.control_socket.is_none() && self.control_listener.is_none() && !memory_control;
+        #[cfg(not(unix))]
+        let tcp_control = self.control_listener.is_none() && !memory_control;
+        if tcp_control {
+            check_local_addr(self.bind_addr)
+                .with_context(|| format!("cannot bind to {}", self.bind_addr))?;
+        }
+        for &addr in self.tunnel_addrs().iter().chain(&self.client_bind_tunnels) {
+            check_local_addr(addr).with_context(|| format!("cannot bind tunnels to {addr}"))?;
+        }
+        if let Some(addr) = self.metrics_addr {
+            check_local_addr(addr.ip())
+                .with_context(|| format!("cannot serve metrics on {addr}"))?;
+        }
+        if let Some(addr) = self.health_addr {
+            check_local_addr(addr.ip())
+                .with_context(|| format!("cannot serve health checks on {addr}"))?;
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.control_socket {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            if let Some(dir) = dir {
+                ensure!(dir.is_dir(), "directory {} does not exist", dir.display());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        self.check_bind_interface()?;
+        Ok(())
+    }
+
+    /// Whether clients must authenticate with a secret.
+    fn has_secret(&self) -> bool {
+        self.auth.is_some() || !self.secrets.is_empty() || self.secrets_file.is_some()
+    }
+
+    /// Start the server, listening for new connections.
+    pub async fn listen(self) -> Result<()> {
+        self.listen_with_shutdown(future::pending()).await
+    }
+
+    /// Start the server, listening for new connections until `shutdown` completes.
+    ///
+    /// On shutdown, the server stops accepting control connections and closes all
+    /// tunnels, sending an error to their clients. Connections that are currently
+    /// being forwarded are given up to the drain timeout to finish.
+    pub async fn listen_with_shutdown(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let this = Arc::new(self);
+        this.validate()?;
+        let mut listener = this.bind_control().await?;
+        this.started.get_or_init(Instant::now);
+
+        let metrics_task = match this.metrics_addr {
+            Some(addr) => {
+                let metrics_listener = TcpListener::bind(addr).await?;
+                let metrics = Arc::clone(&this.metrics);
+                let max_tunnels = this.max_tunnels;
+                let tunnel_slots = Arc::clone(&this.tunnel_slots);
+                let conns = Arc::clone(&this.conns);
+                let stats = this.stats_handle();
+                let render = move || {
+                    let (ports_used, ports_total) = stats.port_utilization();
+                    metrics.render(&Gauges {
+                        tunnels: max_tunnels - tunnel_slots.available_permits(),
+                        max_tunnels,
+                        pending_connections: conns.len(),
+                        ports_used,
+                        ports_total,
+                    })
+                };
+                let serve =
+                    metrics::serve(metrics_listener, "/metrics", metrics::CONTENT_TYPE, render);
+                Some(tokio::spawn(serve))
+            }
+            None => None,
+        };
+
+        let health_task = match this.health_addr {
+            Some(addr) => {
+                let health_listener = TcpListener::bind(addr).await?;
+                let stats = this.stats_handle();
+                let render = move || {
+                    let stats = stats.stats();
+                    let health = json!({
+                        "status": "ok",
+                        "uptime_secs": stats.uptime.as_secs(),
+                        "tunnels": stats.listeners.len(),
+                    });
+                    health.to_string()
+                };
+                let serve = metrics::serve(health_listener, "/health", "application/json", render);
+                Some(tokio::spawn(serve))
+            }
+            None => None,
+        };
+
+        let secrets_task = this
+            .secrets_file
+            .as_ref()
+            .map(|file| tokio::spawn(Arc::clone(file).watch()));
+
+        let http_task = match this.http_port {
+            Some(port) => {
+                let mut listeners = Vec::new();
+                for &addr in this.tunnel_addrs() {
+                    listeners.push(this.listen_tcp((addr, port).into(), None, true)?);
+                }
+                info!(?port, "routing http connections to named tunnels");
+                Some(tokio::spawn(Arc::clone(&this).http_router(listeners)))
+            }
+            None => None,
+        };
+
+        let mut tasks = JoinSet::new();
+        tokio::pin!(shutdown);
+        let idle_exit = this.idle_exit();
+        tokio::pin!(idle_exit);
+        let result = loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(ControlStream::Tcp(stream, addr)) => {
+                        if !this.allow_ip(addr.ip()) {
+                            warn!(?addr, "control connection from disallowed address");
+                            continue;
+                        }
+                        if !this.allow_control(addr.ip()) {
+                            warn!(?addr, "control connection rate limit exceeded");
+                            tokio::spawn(reject(stream, "rate limit exceeded"));
+                            continue;
+                        }
+                        this.configure_stream(&stream);
+                        let span = info_span!("control", ?addr, key = field::Empty, label = field::Empty, peer = field::Empty);
+                        tasks.spawn(Arc::clone(&this).run_control(stream, Some(addr)).instrument(span));
+                    }
+                    #[cfg(unix)]
+                    Ok(ControlStream::Unix(stream)) => {
+                        let span = info_span!("control", addr = "unix", key = field::Empty, label = field::Empty, peer = field::Empty);
+                        tasks.spawn(Arc::clone(&this).run_control(stream, None).instrument(span));
+                    }
+                    Ok(ControlStream::Memory(stream)) => {
+                        let span = info_span!("control", addr = "memory", key = field::Empty, label = field::Empty, peer = field::Empty);
+                        tasks.spawn(Arc::clone(&this).run_control(stream, None).instrument(span));
+                    }
+                    Err(err) => break Err(err.into()),
+                },
+                Some(_) = tasks.join_next() => (),
+                _ = &mut shutdown => break Ok(()),
+                _ = &mut idle_exit => {
+                    info!(idle = ?this.exit_when_idle, "no tunnels for the idle period");
+                    break Ok(());
+                }
+            }
+        };
+
+        info!("server shutting down");
+        drop(listener);
+        #[cfg(unix)]
+        if let Some(path) = &this.control_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        if let Some(metrics_task) = metrics_task {
+            metrics_task.abort();
+        }
+        if let Some(health_task) = health_task {
+            // Stop passing health checks before draining, so load balancers move on.
+            health_task.abort();
+        }
+        if let Some(http_task) = http_task {
+            http_task.abort();
+        }
+        if let Some(secrets_task) = secrets_task {
+            secrets_task.abort();
+        }
+        this.shutdown.cancel();
+        this.conns.clear();
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        if timeout(this.drain_timeout, drain).await.is_err() {
+            warn!(
+                remaining = tasks.len(),
+                "drain timeout elapsed, closing connections"
+            );
+            tasks.shutdown().await;
+        }
+        // Tunnel tasks exit on cancellation; abort any that have not yet.
+        this.port_owners.retain(|_, tunnel| {
+            tunnel.handle.abort();
+            false
+        });
+        this.reservations.clear();
+        info!("server shut down");
+        result
+    }
+
+    /// Wait until the server may exit for having no tunnels, or forever if it never does.
+    async fn idle_exit(&self) {
+        let Some(idle) = self.exit_when_idle else {
+            return future::pending().await;
+        };
+        let started = Instant::now();
+        let mut idle_since = started;
+        let check = (idle / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        loop {
+            sleep(check).await;
+            if self.tunnel_slots.available_permits() < self.max_tunnels {
+                idle_since = Instant::now();
+            } else if idle_since.elapsed() >= idle && started.elapsed() >= self.min_uptime {
+                return;
+            }
+        }
+    }
+
+    /// Check whether a connection from this peer on a tunnel passes the accept filter.
+    fn accept_peer(&self, port: u16, peer: SocketAddr) -> bool {
+        match &self.accept_filter {
+            Some(filter) => filter(port, peer),
+            None => true,
+        }
+    }
+
+    /// Check whether this address passes the allowlist and denylist.
+    fn allow_ip(&self, ip: IpAddr) -> bool {
+        let allowed =
+            self.ip_allowlist.is_empty() || self.ip_allowlist.iter().any(|net| net.contains(ip));
+        allowed && !self.ip_denylist.iter().any(|net| net.contains(ip))
+    }
+
+    /// Check whether a new control connection from this address is within the rate limit.
+    fn allow_control(&self, ip: IpAddr) -> bool {
+        match &self.control_rate_limit {
+            Some(limit) => limit.check(ip),
+            None => true,
+        }
+    }
+
+    async fn bind_control(&self) -> Result<ControlListener> {
+        #[cfg(unix)]
+        if let Some(path) = &self.control_socket {
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("could not bind to {}", path.display()))?;
+            info!(?path, accept_timeout = ?self.accept_timeout, "server listening");
+            return Ok(ControlListener::Unix(listener));
+        }
+        if let Some(listener) = self.memory_listener.lock().unwrap().take() {
+            info!(accept_timeout = ?self.accept_timeout, "server listening in memory");
+            return Ok(ControlListener::Memory(listener));
+        }
+        if let Some(listener) = &self.control_listener {
+            let listener = listener.try_clone()?;
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)?;
+            let addr = listener.local_addr()?;
+            info!(?addr, accept_timeout = ?self.accept_timeout, "server listening on inherited socket");
+            return Ok(ControlListener::Tcp(listener));
+        }
+        let addr = (self.bind_addr, self.control_port).into();
+        let listener = self.listen_tcp(addr, None, true)?;
+        info!(addr = ?self.bind_addr, port = self.control_port, accept_timeout = ?self.accept_timeout, "server listening");
+        if !self.bind_addr.is_loopback() && !self.has_secret() {
+            warn!(addr = ?self.bind_addr, "control port is open to other hosts without a secret");
+        }
+        Ok(ControlListener::Tcp(listener))
+    }
+
+    /// Fail early if the interface that tunnels are restricted to does not exist.
+    #[cfg(target_os = "linux")]
+    fn check_bind_interface(&self) -> Result<()> {
+        if let Some(name) = &self.bind_interface {
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+            socket
+                .bind_device(Some(name.as_bytes()))
+                .with_context(|| format!("cannot bind to network interface {name:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Bind a TCP listener for a tunnel, on the bind interface if there is one.
+    async fn bind_tcp(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        #[cfg(target_os = "linux")]
+        let interface = self.bind_interface.as_deref();
+        #[cfg(not(target_os = "linux"))]
+        let interface = None;
+        self.listen_tcp(addr, interface, false)
+    }
+
+    /// Apply the server's socket options to a TCP connection it accepted.
+    fn configure_stream(&self, stream: &TcpStream) {
+        if let Err(err) = set_stream_options(stream, self.nodelay, self.keepalive) {
+            warn!(%err, "could not set socket options");
+        }
+    }
+
+    /// Bind a TCP listener with the server's socket options, restricted to a network
+    /// interface if one is given.
+    ///
+    /// Only `shared` listeners get `SO_REUSEPORT`, so that tunnel ports stay exclusive.
+    fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        interface: Option<&str>,
+        shared: bool,
+    ) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.set_reuse_address(self.reuse_address)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(shared && self.reuse_port)?;
+        #[cfg(target_os = "linux")]
+        if let Some(name) = interface {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = interface;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Bind a UDP socket for a tunnel, on the bind interface if there is one.
+    async fn bind_udp(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = &self.bind_interface {
+            let socket = device_socket(addr, name)?;
+            return UdpSocket::from_std(socket.into());
+        }
+        UdpSocket::bind(addr).await
+    }
+
+    /// Handle a control connection, keeping track of it in the metrics.
+    async fn run_control<S>(self: Arc<Self>, stream: S, remote_addr: Option<SocketAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        info!("incoming connection");
+        let metrics = Arc::clone(&self.metrics);
+        metrics.control_connections.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = self.handle_connection(stream, remote_addr).await {
+            warn!(%err, "control connection exited with error");
+        } else {
+            info!("control connection exited");
+        }
+        metrics.control_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Authenticate the client if required, returning the ports it may forward.
+    async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut Delimited<S>,
+    ) -> Result<Grant> {
+        if self.auth.is_none() && self.secrets.is_empty() && self.secrets_file.is_none() {
+            return Ok(Grant {
+                port_range: self.port_range.clone(),
+                quota: None,
+                label: None,
+                bind_addr: None,
+            });
+        }
+        let file_secrets = self.secrets_file.as_ref().map(|file| file.current());
+        let candidates: Vec<_> = self
+            .auth
+            .iter()
+            .map(|auth| (auth, &self.port_range))
+            .chain(
+                self.secrets
+                    .iter()
+                    .chain(file_secrets.iter().flat_map(|secrets| secrets.iter()))
+                    .map(|(auth, range)| (auth, range)),
+            )
+            .collect();
+        let auths: Vec<_> = candidates.iter().map(|&(auth, _)| auth).collect();
+        let index = Authenticator::server_handshake_any(&auths, stream).await?;
+        let (auth, port_range) = candidates[index];
+        Span::current().record("key", auth.key_id());
+        self.metrics.add_authentication(auth.key_id());
+        Ok(Grant {
+            port_range: port_range.clone(),
+            quota: self.quotas.get(auth.key_id()).cloned(),
+            label: None,
+            bind_addr: None,
+        })
+    }
+
+    /// Addresses where tunnels listen on, defaulting to all IPv4 interfaces.
+    fn tunnel_addrs(&self) -> &[IpAddr] {
+        if self.bind_tunnels.is_empty() {
+            &[IpAddr::V4(Ipv4Addr::UNSPECIFIED)]
+        } else {
+            &self.bind_tunnels
+        }
+    }
+
+    /// Addresses where the tunnels of a client listen on.
+    fn grant_addrs<'a>(&'a self, grant: &'a Grant) -> &'a [IpAddr] {
+        match &grant.bind_addr {
+            Some(addr) => slice::from_ref(addr),
+            None => self.tunnel_addrs(),
+        }
+    }
+
+    /// Bind a tunnel's listeners on each of these addresses, returning them with the
+    /// address of the first, which has the port that was chosen.
+    async fn create_listener(
+        &self,
+        addrs: &[IpAddr],
+        port: u16,
+        port_range: &RangeInclusive<u16>,
+    ) -> Result<(Vec<TcpListener>, SocketAddr), &'static str> {
+        self.bind_port(port, port_range, |port| async move {
+            let mut listeners = Vec::new();
+            for &addr in addrs {
+                listeners.push(self.bind_tcp(SocketAddr::new(addr, port)).await?);
+            }
+            let local_addr = listeners[0].local_addr()?;
+            Ok((listeners, local_addr))
+        })
+        .await
+    }
+
+    /// Like [`Server::create_listener`], for the sockets of a UDP tunnel.
+    async fn create_udp_socket(
+        &self,
+        addrs: &[IpAddr],
+        port: u16,
+        port_range: &RangeInclusive<u16>,
+    ) -> Result<(Vec<UdpSocket>, SocketAddr), &'static str> {
+        self.bind_port(port, port_range, |port| async move {
+            let mut sockets = Vec::new();
+            for &addr in addrs {
+                sockets.push(self.bind_udp(SocketAddr::new(addr, port)).await?);
+            }
+            let local_addr = sockets[0].local_addr()?;
+            Ok((sockets, local_addr))
+        })
+        .await
+    }
+
+    async fn bind_port<T, F, Fut>(
+        &self,
+        port: u16,
+        port_range: &RangeInclusive<u16>,
+        bind: F,
+    ) -> Result<T, &'static str>
+    where
+        F: Fn(u16) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let try_bind = |port: u16| {
+            let fut = bind(port);
+            async move {
+                fut.await.map_err(|err| match err.kind() {
+                    io::ErrorKind::AddrInUse => "port already in use",
+                    io::ErrorKind::PermissionDenied => "permission denied",
+                    _ => "failed to bind to port",
+                })
+            }
+        };
+        if port > 0 {
+            // Client requests a specific port number.
+            if !port_range.contains(&port) {
+                return Err("client port number not in allowed range");
+            }
+            try_bind(port).await
+        } else {
+            // Client requests any available port in range, preferably a preferred one.
+            if let Some(preferred) = self.preferred_subrange(port_range) {
+                if let Some(listener) = self.probe_ports(&preferred, &try_bind).await {
+                    return Ok(listener);
+                }
+                info!(
+                    ?preferred,
+                    "no preferred port available, trying the whole range"
+                );
+            }
+            if let Some(listener) = self.probe_ports(port_range, &try_bind).await {
+                return Ok(listener);
+            }
+            match self.allocation_strategy {
+                AllocationStrategy::Random => warn!(
+                    attempts = self.port_probe_attempts,
+                    port_range = ?port_range,
+                    "no available port found, consider widening the port range"
+                ),
+                AllocationStrategy::Sequential => warn!(
+                    port_range = ?port_range,
+                    "every port in range is in use, consider widening the port range"
+                ),
+            }
+            Err("failed to find an available port")
+        }
+    }
+
+    /// Try ports in a range with the allocation strategy, returning the first that binds.
+    async fn probe_ports<T, F, Fut>(
+        &self,
+        port_range: &RangeInclusive<u16>,
+        try_bind: F,
+    ) -> Option<T>
+    where
+        F: Fn(u16) -> Fut,
+        Fut: Future<Output = Result<T, &'static str>>,
+    {
+        match self.allocation_strategy {
+            AllocationStrategy::Random => {
+                // In this case, we bind to 150 random port numbers by default. We choose this
+                // value because in order to find a free port with probability at least 1-δ,
+                // when ε proportion of the ports are currently available, it suffices to check
+                // approximately -2 ln(δ) / ε independently and uniformly chosen ports (up to a
+                // second-order term in ε).
+                //
+                // Checking 150 times gives us 99.999% success at utilizing 85% of ports under
+                // these conditions, when ε=0.15 and δ=0.00001.
+                for _ in 0..self.port_probe_attempts {
+                    let port = fastrand::u16(port_range.clone());
+                    if let Ok(listener) = try_bind(port).await {
+                        return Some(listener);
+                    }
+                }
+            }
+            AllocationStrategy::Sequential => {
+                for port in port_range.clone() {
+                    if let Ok(listener) = try_bind(port).await {
+                        return Some(listener);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Part of a port range that is preferred, if it is neither empty nor the whole range.
+    fn preferred_subrange(&self, port_range: &RangeInclusive<u16>) -> Option<RangeInclusive<u16>> {
+        let preferred = self.preferred_ports.as_ref()?;
+        let start = *preferred.start().max(port_range.start());
+        let end = *preferred.end().min(port_range.end());
+        let subrange = start..=end;
+        (!subrange.is_empty() && subrange != *port_range).then_some(subrange)
+    }
+
+    /// Abort the listener tasks on this port that the client may take over, if any.
+    ///
+    /// Which tunnels those are depends on the [`PortTakeover`] policy. Clients are
+    /// matched by address as described in [`owner_key`].
+    async fn abort_owner(&self, port: u16, remote_addr: Option<SocketAddr>) {
+        let keys = match self.port_takeover {
+            PortTakeover::Reject => return,
+            PortTakeover::SameAddr if remote_addr.is_none() => return,
+            PortTakeover::SameAddr => vec![owner_key(port, remote_addr)],
+            PortTakeover::Any => self
+                .port_owners
+                .iter()
+                .filter(|entry| entry.key().0 == port)
+                .map(|entry| *entry.key())
+                .collect(),
+        };
+        for key in keys {
+            let Some((_, old)) = self.port_owners.remove(&key) else {
+                continue;
+            };
+            let old_addr = old.remote_addr;
+            let message = ServerMessage::Error("port taken over by another connection".into());
+            let _ = old.tx.try_send(message);
+            abort_and_wait(&old.handle).await;
+            info!(
+                ?port,
+                ?old_addr,
+                ?remote_addr,
+                "aborted old listener for this port/addr"
+            );
+            self.emit(EventKind::ListenerAborted {
+                port,
+                remote_addr: old_addr,
+            });
+        }
+    }
+
+    async fn handle_connection<S>(
+        self: Arc<Self>,
+        stream: S,
+        remote_addr: Option<SocketAddr>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut stream = Delimited::new(stream);
+        let mut grant = match self.authenticate(&mut stream).await {
+            Ok(grant) => grant,
+            Err(err) => {
+                warn!(%err, "server handshake failed");
+                self.emit(EventKind::AuthFailed {
+                    remote_addr,
+                    reason: err.to_string(),
+                });
+                stream.send(ServerMessage::Error(err.to_string())).await?;
+                return Ok(());
+            }
+        };
+
+        let mut msg = stream.recv_timeout().await?;
+        let mut version = 0;
+        if let Some(ClientMessage::Version(client_version)) = msg {
+            version = client_version.min(PROTOCOL_VERSION);
+            stream.send(ServerMessage::Version(version)).await?;
+            msg = stream.recv_timeout().await?;
+        }
+        // Optional features are only enabled for clients that negotiated them.
+        let peers = version >= 1 && matches!(msg, Some(ClientMessage::ReportPeers));
+        if peers {
+            msg = stream.recv_timeout().await?;
+        }
+        let mut token = None;
+        if version >= 3 {
+            if let Some(ClientMessage::Reserve(reserve)) = msg {
+                token = Some(reserve);
+                msg = stream.recv_timeout().await?;
+            }
+        }
+        let token = token.as_deref();
+        if version >= 6 {
+            if let Some(ClientMessage::Label(label)) = msg {
+                grant.label = sanitize_label(&label);
+                if let Some(label) = &grant.label {
+                    Span::current().record("label", label.as_str());
+                }
+                msg = stream.recv_timeout().await?;
+            }
+        }
+        let status = version >= 7 && matches!(msg, Some(ClientMessage::ReportStatus));
+        if status {
+            msg = stream.recv_timeout().await?;
+        }
+        if version >= 8 {
+            if let Some(ClientMessage::BindTunnels(addr)) = msg {
+                if self.client_bind_tunnels.contains(&addr) {
+                    grant.bind_addr = Some(addr);
+                } else {
+                    warn!(%addr, "client asked for a tunnel address that is not allowed");
+                }
+                msg = stream.recv_timeout().await?;
+            }
+        }
+        if version >= 9 && matches!(msg, Some(ClientMessage::Compress)) {
+            if self.compression {
+                stream.send(ServerMessage::Compress).await?;
+            }
+            msg = stream.recv_timeout().await?;
+        }
+        let features = Features { peers, status };
+        let hello = matches!(
+            msg,
+            Some(
+                ClientMessage::Hello(_) | ClientMessage::HelloUdp(_) | ClientMessage::HelloNamed(_)
+            )
+        );
+        if hello && version >= 5 {
+            if let Some(host) = &self.advertised_host {
+                stream.send(ServerMessage::Host(host.clone())).await?;
+            }
+        }
+
+        match msg {
+            Some(
+                ClientMessage::Authenticate(_)
+                | ClientMessage::Version(_)
+                | ClientMessage::ReportPeers
+                | ClientMessage::Reserve(_)
+                | ClientMessage::Label(_)
+                | ClientMessage::ReportStatus
+                | ClientMessage::BindTunnels(_)
+                | ClientMessage::Release(_),
+            ) => {
+                warn!("unexpected message before hello");
+                Ok(())
+            }
+            // Data connections of clients that negotiated compression ask for it first.
+            Some(ClientMessage::Compress) if self.compression => {
+                match stream.recv_timeout().await? {
+                    Some(ClientMessage::Accept(id)) => {
+                        self.forward_accepted(stream, id, false, true).await
+                    }
+                    Some(ClientMessage::AcceptWithReply(id)) => {
+                        self.forward_accepted(stream, id, true, true).await
+                    }
+                    _ => {
+                        warn!("expected an accept after compress");
+                        Ok(())
+                    }
+                }
+            }
+            Some(ClientMessage::Compress) => {
+                warn!("compression from a client that did not negotiate it");
+                Ok(())
+            }
+            Some(command @ (ClientMessage::AdminList | ClientMessage::AdminKill(_)))
+                if version >= 4 =>
+            {
+                self.admin(stream, command).await
+            }
+            Some(ClientMessage::AdminList | ClientMessage::AdminKill(_)) => {
+                warn!("admin command from a client that did not negotiate it");
+                Ok(())
+            }
+            Some(ClientMessage::Hello(port)) => {
+                let request = TunnelRequest::Port(Transport::Tcp, port);
+                self.serve_tunnels(stream, remote_addr, &grant, request, features, token)
+                    .await
+            }
+            Some(ClientMessage::HelloUdp(port)) => {
+                let request = TunnelRequest::Port(Transport::Udp, port);
+                self.serve_tunnels(stream, remote_addr, &grant, request, features, token)
+                    .await
+            }
+            Some(ClientMessage::HelloNamed(name)) => {
+                let request = TunnelRequest::Named(name);
+                self.serve_tunnels(stream, remote_addr, &grant, request, features, token)
+                    .await
+            }
+            Some(ClientMessage::Accept(id)) => {
+                self.forward_accepted(stream, id, false, false).await
+            }
+            Some(ClientMessage::AcceptWithReply(id)) => {
+                self.forward_accepted(stream, id, true, false).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Run an admin command, once the administrator proves they hold the admin secret.
+    async fn admin<S>(&self, mut stream: Delimited<S>, command: ClientMessage) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(admin_auth) = &self.admin_auth else {
+            warn!("admin command, but no admin secret is set");
+            let message = ServerMessage::Error("admin commands are disabled".into());
+            return stream.send(message).await;
+        };
+        if let Err(err) = admin_auth.server_handshake(&mut stream).await {
+            warn!(%err, "admin handshake failed");
+            return stream.send(ServerMessage::Error(err.to_string())).await;
+        }
+        if let ClientMessage::AdminKill(port) = command {
+            let closed = self.kill_tunnels(port).await;
+            return stream.send(ServerMessage::Killed { port, closed }).await;
+        }
+        let mut tunnels: Vec<_> = self
+            .port_owners
+            .iter()
+            .map(|entry| TunnelInfo {
+                port: entry.key().0,
+                client: entry.remote_addr,
+                uptime: entry.opened.elapsed(),
+                label: entry.label.clone(),
+            })
+            .collect();
+        tunnels.sort_unstable_by_key(|tunnel| tunnel.port);
+        info!(count = tunnels.len(), "listing tunnels for admin");
+        stream.send(ServerMessage::TunnelList(tunnels)).await
+    }
+
+    /// Close the tunnels on a port, telling their clients why, and return whether there were any.
+    async fn kill_tunnels(&self, port: u16) -> bool {
+        let keys: Vec<_> = self
+            .port_owners
+            .iter()
+            .filter(|entry| entry.key().0 == port)
+            .map(|entry| *entry.key())
+            .collect();
+        let mut closed = false;
+        for key in keys {
+            let Some((_, tunnel)) = self.port_owners.remove(&key) else {
+                continue;
+            };
+            // Only this tunnel closes, and the connection stays up so it is not reopened.
+            let _ = tunnel.tx.try_send(ServerMessage::Released(port));
+            abort_and_wait(&tunnel.handle).await;
+            info!(port, remote_addr = ?tunnel.remote_addr, "admin closed tunnel");
+            self.emit(EventKind::ListenerAborted {
+                port,
+                remote_addr: tunnel.remote_addr,
+            });
+            closed = true;
+        }
+        closed
+    }
+
+    /// Forward a pending connection that the client accepted on this stream.
+    ///
+    /// If asked to reply, the client is told whether the connection is still
+    /// pending before anything is forwarded. A `compressed` TCP connection is sent as
+    /// a deflate stream on it, while UDP sessions never are. Only errors of the control
+    /// protocol are returned, as a connection that fails while forwarding is logged here.
+    async fn forward_accepted<S>(
+        &self,
+        mut stream: Delimited<S>,
+        id: Uuid,
+        reply: bool,
+        compressed: bool,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some((_, pending)) = self.conns.remove(&id) else {
+            warn!(%id, "missing connection");
+            if reply {
+                stream
+                    .send(ServerMessage::Error("connection expired".into()))
+                    .await?;
+            }
+            return Ok(());
+        };
+        if reply {
+            stream.send(ServerMessage::Accepted(id)).await?;
+        }
+        // Later logs for this connection then carry the peer, for audit trails.
+        Span::current().record("peer", field::display(pending.peer_addr));
+        info!(%id, "forwarding connection");
+        self.emit(EventKind::ConnectionForwarded { id });
+        let Pending {
+            incoming,
+            peer_addr: _,
+            bandwidth,
+            _permit,
+            queued,
+            pending_slot,
+            activity,
+        } = pending;
+        drop((queued, pending_slot));
+        activity.connections.fetch_add(1, Ordering::Relaxed);
+        let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+        let forwarded = match incoming {
+            Incoming::Tcp(stream2) => {
+                self.forward_tcp(
+                    stream,
+                    stream2,
+                    &[],
+                    compressed,
+                    bandwidth,
+                    &sent,
+                    &received,
+                )
+                .await
+            }
+            Incoming::Http(stream2, head) => {
+                self.forward_tcp(
+                    stream, stream2, &head, compressed, bandwidth, &sent, &received,
+                )
+                .await
+            }
+            Incoming::Udp(session) => {
+                session
+                    .forward(stream, &self.metrics, &sent, &received)
+                    .await
+            }
+        };
+        activity.connections.fetch_sub(1, Ordering::Relaxed);
+        let (bytes_to_peer, bytes_from_peer) = (sent.into_inner(), received.into_inner());
+        activity
+            .bytes
+            .fetch_add(bytes_to_peer + bytes_from_peer, Ordering::Relaxed);
+        // Both sides were closed when forwarding ended, and the control protocol is
+        // done with this stream, so a failure only ends this one connection.
+        if let Err(err) = forwarded {
+            warn!(%id, %err, "forwarding connection failed");
+        }
+        info!(%id, bytes_to_peer, bytes_from_peer, "connection closed");
+        self.emit(EventKind::ConnectionClosed {
+            id,
+            bytes_to_peer,
+            bytes_from_peer,
+        });
+        Ok(())
+    }
+
+    /// Proxy an accepted TCP connection over the client's stream.
+    ///
+    /// Any bytes already read from the connection are sent to the client first.
+    /// The bytes sent to the remote peer, and received from it, are added to `sent`
+    /// and `received`, including those copied before a failure. If `compressed`, the
+    /// client's stream carries a deflate stream each way.
+    #[allow(clippy::too_many_arguments)]
+    async fn forward_tcp<S>(
+        &self,
+        stream: Delimited<S>,
+        mut stream2: TcpStream,
+        read: &[u8],
+        compressed: bool,
+        bandwidth: Option<Arc<Bandwidth>>,
+        sent: &AtomicU64,
+        received: &AtomicU64,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let forwarded = async {
+            let parts = stream.into_parts();
+            debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+            let (mut client, buffered) = if compressed {
+                // Bytes the client sent after its accept are part of the compressed stream.
+                let client = Compressed::new(parts.io, &parts.read_buf);
+                (Either::Left(client), &[][..])
+            } else {
+                (Either::Right(parts.io), &parts.read_buf[..])
+            };
+            if let Some(version) = self.proxy_protocol {
+                let header = version.header(stream2.peer_addr()?, stream2.local_addr()?);
+                client.write_all(&header).await?;
+            }
+            client.write_all(read).await?;
+            // A compressed stream holds on to what was written until it is flushed.
+            client.flush().await?;
+            received.fetch_add(read.len() as u64, Ordering::Relaxed);
+            stream2.write_all(buffered).await?;
+            let idle_timeout = self.connection_idle_timeout;
+            match bandwidth {
+                Some(bandwidth) => {
+                    let stream2 = Throttled::new(stream2, bandwidth);
+                    proxy_counted(client, stream2, idle_timeout, sent, received).await
+                }
+                None => proxy_counted(client, stream2, idle_timeout, sent, received).await,
+            }
+        };
+        let forwarded: io::Result<()> = forwarded.await;
+        let bytes = sent.load(Ordering::Relaxed) + received.load(Ordering::Relaxed);
+        self.metrics.add_forwarded_bytes(bytes);
+        Ok(forwarded?)
+    }
+
+    /// Serve the tunnels requested on a control connection, until the client leaves.
+    ///
+    /// The client may request more tunnels at any time by sending another hello.
+    async fn serve_tunnels<S: AsyncRead + AsyncWrite + Unpin>(
+        self: &Arc<Self>,
+        mut stream: Delimited<S>,
+        remote_addr: Option<SocketAddr>,
+        grant: &Grant,
+        first: TunnelRequest,
+        features: Features,
+        token: Option<&str>,
+    ) -> Result<()> {
+        let (tx, mut notifications) = mpsc::channel(64);
+        let mut tunnels = JoinSet::new();
+        let mut opened = 0;
+        // Keyed by the order of the tunnels, as several may share a port number.
+        let mut owned = BTreeMap::new();
+        let mut killed = false;
+        let mut hello = Some(first);
+        let mut heartbeat = interval(self.heartbeat_interval);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            if let Some(request) = hello.take() {
+                // Only tunnels after the first are tagged, so older clients are unaffected.
+                let tunnel_activity = Arc::new(Activity::default());
+                let notifier = Notifier {
+                    tx: tx.clone(),
+                    port: 0,
+                    remote_addr,
+                    tagged: opened > 0,
+                    peers: features.peers,
+                    queue: self
+                        .pending_queue_depth
+                        .map(|depth| Arc::new(Semaphore::new(depth))),
+                    quota: grant
+                        .quota
+                        .as_ref()
+                        .and_then(|quota| quota.connections.clone()),
+                    activity: Arc::clone(&tunnel_activity),
+                };
+                // A client that never learns a chosen port cannot take its tunnel back.
+                let known_port = matches!(request, TunnelRequest::Port(_, port) if port > 0);
+                let tunnels = &mut tunnels;
+                match self
+                    .open_tunnel(tunnels, notifier, remote_addr, grant, request, token)
+                    .await
+                {
+                    Ok((port, handle)) => {
+                        if let Err(err) = stream.send(ServerMessage::Hello(port)).await {
+                            // The client never learns the port, so close the tunnel now,
+                            // rather than when the task set is dropped. Reserved tunnels
+                            // on a port the client asked for are held for their grace
+                            // period instead, as on any lost connection.
+                            if token.is_none() || self.reservation_grace.is_none() || !known_port {
+                                abort_and_wait(&handle).await;
+                                self.emit(EventKind::ListenerAborted { port, remote_addr });
+                            }
+                            return Err(err.context("failed to send hello"));
+                        }
+                        owned.insert(opened, (port, handle, tunnel_activity));
+                        opened += 1;
+                    }
+                    Err(err) => {
+                        stream.send(ServerMessage::Error(err.into())).await?;
+                        if opened == 0 {
+                            return Ok(());
+                        }
+                        // Only this tunnel is refused, the earlier ones stay open.
+                        warn!(?remote_addr, err, "refused a later tunnel");
+                    }
+                }
+            }
+            tokio::select! {
+                _ = heartbeat.tick() => {
+                    if features.status {
+                        // Tunnels that were taken over may not be joined yet.
+                        owned.retain(|_, (_, handle, _)| !handle.is_finished());
+                        for (port, _, activity) in owned.values() {
+                            let status = TunnelStatus {
+                                port: *port,
+                                connections: activity.connections.load(Ordering::Relaxed),
+                                bytes: activity.bytes.load(Ordering::Relaxed),
+                            };
+                            stream
+                                .send(ServerMessage::Status(status))
+                                .await
+                                .context("failed to send heartbeat")?;
+                        }
+                    } else {
+                        stream
+                            .send(ServerMessage::Heartbeat)
+                            .await
+                            .context("failed to send heartbeat")?;
+                    }
+                }
+                Some(msg) = notifications.recv() => {
+                    // An administrator closed a tunnel.
+                    killed |= matches!(msg, ServerMessage::Released(_));
+                    stream.send(msg).await?;
+                }
+                msg = stream.recv() => match msg? {
+                    Some(ClientMessage::Hello(port)) => {
+                        hello = Some(TunnelRequest::Port(Transport::Tcp, port));
+                    }
+                    Some(ClientMessage::HelloUdp(port)) => {
+                        hello = Some(TunnelRequest::Port(Transport::Udp, port));
+                    }
+                    Some(ClientMessage::HelloNamed(name)) => {
+                        hello = Some(TunnelRequest::Named(name));
+                    }
+                    Some(ClientMessage::Release(port)) => {
+                        let key = owned
+                            .iter()
+                            .find(|(_, (owned_port, ..))| *owned_port == port)
+                            .map(|(&key, _)| key);
+                        if let Some((_, handle, _)) = key.and_then(|key| owned.remove(&key)) {
+                            abort_and_wait(&handle).await;
+                            info!(port, "released tunnel");
+                            self.emit(EventKind::ListenerAborted { port, remote_addr });
+                        }
+                        stream.send(ServerMessage::Released(port)).await?;
+                    }
+                    Some(_) => warn!("unexpected message on control connection"),
+                    None => return Ok(()),
+                },
+                Some(_) = tunnels.join_next() => {
+                    owned.retain(|_, (_, handle, _)| !handle.is_finished());
+                    if tunnels.is_empty() {
+                        // Every tunnel was closed or taken over, so flush any errors and
+                        // leave, unless an administrator closed one. A client that
+                        // reconnected would just open it again.
+                        while let Ok(msg) = notifications.try_recv() {
+                            killed |= matches!(msg, ServerMessage::Released(_));
+                            stream.send(msg).await?;
+                        }
+                        if !killed {
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = self.shutdown.cancelled() => {
+                    let _ = stream.send(ServerMessage::Error(SHUTDOWN_MESSAGE.into())).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Bind a new tunnel and spawn its listener task, returning the public port and the task.
+    ///
+    /// With a reservation token, a TCP tunnel held for the token on the requested
+    /// port is taken over instead.
+    async fn open_tunnel(
+        self: &Arc<Self>,
+        tunnels: &mut JoinSet<()>,
+        mut notifier: Notifier,
+        remote_addr: Option<SocketAddr>,
+        grant: &Grant,
+        request: TunnelRequest,
+        token: Option<&str>,
+    ) -> Result<(u16, Arc<AbortHandle>), &'static str> {
+        let Ok(quota) = acquire(&grant.quota.as_ref().and_then(|quota| quota.tunnels.clone()))
+        else {
+            warn!(?remote_addr, "tunnel quota exceeded");
+            return Err("tunnel quota exceeded");
+        };
+        let Ok(slot) = Arc::clone(&self.tunnel_slots).try_acquire_owned() else {
+            warn!(max_tunnels = self.max_tunnels, "tunnel limit reached");
+            return Err("server at capacity, retry later");
+        };
+        let permit = (slot, quota);
+        let port_range = &grant.port_range;
+        let (transport, port) = match request {
+            TunnelRequest::Port(transport, port) => (transport, port),
+            TunnelRequest::Named(name) => {
+                return self.open_named_tunnel(tunnels, notifier, name, permit)
+            }
+        };
+        if let (Transport::Tcp, Some(token)) = (transport, token) {
+            if port > 0 && port_range.contains(&port) {
+                if let Some(handle) = self.adopt(tunnels, token, port, &notifier) {
+                    return Ok((port, handle));
+                }
+            }
+        }
+        // Before creating listener, check for an existing owner of the port from this client
+        self.abort_owner(port, remote_addr).await;
+        let host = self.grant_addrs(grant);
+        let (id, handle) = match transport {
+            Transport::Tcp => {
+                let (listeners, local_addr) = self.create_listener(host, port, port_range).await?;
+                notifier.port = local_addr.port();
+                info!(?host, port = notifier.port, "new client");
+                self.emit(EventKind::ListenerCreated {
+                    port: notifier.port,
+                    remote_addr,
+                    udp: false,
+                });
+                let owner = PortOwner::new(self, notifier.port, remote_addr, permit);
+                let id = owner.id;
+                let span = info_span!("tunnel", port = notifier.port, ?remote_addr);
+                let handle = match (token, self.reservation_grace) {
+                    (Some(token), Some(grace)) => {
+                        // Held tunnels outlive the connection, which only keeps a lease on them.
+                        let (lease, leased) = oneshot::channel();
+                        let reservation = Reservation {
+                            token: token.into(),
+                            owner: id,
+                            grace,
+                            lease,
+                        };
+                        let task = Arc::clone(self).tcp_tunnel(
+                            listeners,
+                            notifier.clone(),
+                            Some(reservation),
+                        );
+                        let handle = tokio::spawn(owner.run(task).instrument(span));
+                        tunnels.spawn(async move {
+                            let _ = leased.await;
+                        });
+                        handle.abort_handle()
+                    }
+                    _ => {
+                        let task = Arc::clone(self).tcp_tunnel(listeners, notifier.clone(), None);
+                        tunnels.spawn(owner.run(task).instrument(span))
+                    }
+                };
+                (id, Arc::new(handle))
+            }
+            Transport::Udp => {
+                let (sockets, local_addr) = self.create_udp_socket(host, port, port_range).await?;
+                notifier.port = local_addr.port();
+                info!(?host, port = notifier.port, "new udp client");
+                self.emit(EventKind::ListenerCreated {
+                    port: notifier.port,
+                    remote_addr,
+                    udp: true,
+                });
+                let owner = PortOwner::new(self, notifier.port, remote_addr, permit);
+                let task = Arc::clone(self).udp_tunnel(sockets, notifier.clone());
+                let span = info_span!("tunnel", port = notifier.port, ?remote_addr, udp = true);
+                let id = owner.id;
+                let handle = tunnels.spawn(owner.run(task).instrument(span));
+                (id, Arc::new(handle))
+            }
+        };
+        // Track the listener task for this port/addr
+        let tunnel = TunnelEntry {
+            id,
+            remote_addr,
+            handle: Arc::clone(&handle),
+            opened: Instant::now(),
+            tx: notifier.tx.clone(),
+            label: grant.label.clone(),
+        };
+        let key = owner_key(notifier.port, remote_addr);
+        match self.port_utilization_warning {
+            Some(fraction) => {
+                // Opens are counted one at a time, so that only one of them sees the crossing.
+                let _guard = self.port_insert.lock().unwrap();
+                self.port_owners.insert(key, tunnel);
+                self.check_port_utilization(notifier.port, fraction);
+            }
+            None => _ = self.port_owners.insert(key, tunnel),
+        }
+        if handle.is_finished() {
+            // The task exited before the entry was inserted, so it could not remove it.
+            self.port_owners
+                .remove_if(&key, |_, tunnel| tunnel.id == id);
+        }
+        Ok((notifier.port, handle))
+    }
+
+    /// Warn if the tunnel that just opened took the port range past the warning threshold.
+    fn check_port_utilization(&self, port: u16, fraction: f64) {
+        let mut ports = HashSet::new();
+        let mut owners = 0;
+        for entry in self.port_owners.iter() {
+            let owned = entry.key().0;
+            owners += usize::from(owned == port);
+            if self.port_range.contains(&owned) {
+                ports.insert(owned);
+            }
+        }
+        // A tunnel that shares its port with another owner takes no new port.
+        if owners > 1 {
+            return;
+        }
+        let used = ports.len();
+        let total = self.port_range.len();
+        let threshold = fraction * total as f64;
+        if used as f64 > threshold && (used - 1) as f64 <= threshold {
+            warn!(used, total, "tunnels are using most of the port range");
+        }
+    }
+
+    /// Hand a tunnel held for this token and port to a new control connection.
+    ///
+    /// Returns the listener task of the tunnel, or `None` if nothing is held.
+    fn adopt(
+        &self,
+        tunnels: &mut JoinSet<()>,
+        token: &str,
+        port: u16,
+        notifier: &Notifier,
+    ) -> Option<Arc<AbortHandle>> {
+        let (_, held) = self.reservations.remove(&(token.into(), port))?;
+        let handle = self
+            .port_owners
+            .iter_mut()
+            .find(|entry| entry.key().0 == port && entry.id == held.owner)
+            .map(|mut entry| {
+                entry.tx = notifier.tx.clone();
+                Arc::clone(&entry.handle)
+            })?;
+        let mut notifier = notifier.clone();
+        notifier.port = port;
+        let (lease, leased) = oneshot::channel();
+        // This fails if the grace period ran out just now.
+        held.adopt.send((notifier, lease)).ok()?;
+        tunnels.spawn(async move {
+            let _ = leased.await;
+        });
+        info!(port, "client reclaimed held tunnel");
+        Some(handle)
+    }
+
+    /// Keep a tunnel's listeners open until its client comes back, or the grace period ends.
+    ///
+    /// Returns the new control connection state, if the tunnel was adopted in time.
+    async fn hold(&self, port: u16, reservation: &Reservation) -> Option<Adoption> {
+        let key = (reservation.token.clone(), port);
+        let (adopt, adopted) = oneshot::channel();
+        let held = Held {
+            owner: reservation.owner,
+            adopt,
+        };
+        self.reservations.insert(key.clone(), held);
+        // The entry goes away when the hold ends, even if the task is aborted.
+        let _unhold = Unhold {
+            reservations: &self.reservations,
+            key,
+            owner: reservation.owner,
+        };
+        info!(port, grace = ?reservation.grace, "holding tunnel for client");
+        timeout(reservation.grace, adopted).await.ok()?.ok()
+    }
+
+    /// Register a named HTTP tunnel and spawn a task holding its route.
+    fn open_named_tunnel(
+        self: &Arc<Self>,
+        tunnels: &mut JoinSet<()>,
+        mut notifier: Notifier,
+        name: String,
+        permit: TunnelPermits,
+    ) -> Result<(u16, Arc<AbortHandle>), &'static str> {
+        let Some(http_port) = self.http_port else {
+            return Err("server does not route http tunnels by name");
+        };
+        if notifier.tagged {
+            // Tagged notifications carry the shared port, which does not identify the tunnel.
+            return Err("named tunnel must be the first on its connection");
+        }
+        if !http_mux::valid_name(&name) {
+            return Err("invalid tunnel name");
+        }
+        notifier.port = http_port;
+        match self.named_tunnels.entry(name.clone()) {
+            Entry::Occupied(_) => return Err("tunnel name already in use"),
+            Entry::Vacant(entry) => entry.insert((notifier.clone(), self.bandwidth_limit())),
+        };
+        info!(%name, port = http_port, "new named client");
+        self.emit(EventKind::ListenerCreated {
+            port: http_port,
+            remote_addr: notifier.remote_addr,
+            udp: false,
+        });
+        let named_tunnels = Arc::clone(&self.named_tunnels);
+        let expiry = self.tunnel_expiry();
+        let handle = tunnels.spawn(async move {
+            // Remove the route when the control connection closes and aborts this task.
+            let _route = NamedRoute(named_tunnels, name);
+            let _permit = permit;
+            expired(expiry).await;
+            info!(port = http_port, "closing tunnel at its maximum lifetime");
+            notifier.close(LIFETIME_MESSAGE).await;
+        });
+        Ok((http_port, Arc::new(handle)))
+    }
+
+    /// Accept connections on the shared HTTP port, routing each to a named tunnel.
+    async fn http_router(self: Arc<Self>, listeners: Vec<TcpListener>) {
+        loop {
+            let accept = select_all(listeners.iter().map(|listener| Box::pin(listener.accept())));
+            let (stream, addr) = match accept.await.0 {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(%err, "failed to accept http connection");
+                    sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            self.configure_stream(&stream);
+            tokio::spawn(Arc::clone(&self).route_http(stream, addr));
+        }
+    }
+
+    /// Read the head of an HTTP request, and hand it to the tunnel named by its host.
+    async fn route_http(self: Arc<Self>, mut stream: TcpStream, addr: SocketAddr) {
+        let head = match timeout(NETWORK_TIMEOUT, http_mux::read_head(&mut stream)).await {
+            Ok(Ok(head)) => head,
+            Ok(Err(err)) => return warn!(%err, ?addr, "invalid http request"),
+            Err(_) => return warn!(?addr, "timed out reading http request"),
+        };
+        let tunnel = http_mux::host_name(&head)
+            .and_then(|name| self.named_tunnels.get(&name).map(|entry| entry.clone()));
+        let Some((notifier, bandwidth)) = tunnel else {
+            info!(?addr, "no tunnel for http request");
+            return http_mux::respond_error(&mut stream, "404 Not Found").await;
+        };
+        info!(?addr, port = notifier.port, "new http connection");
+        if !self.accept_peer(notifier.port, addr) {
+            warn!(
+                ?addr,
+                port = notifier.port,
+                "connection refused by accept filter"
+            );
+            return http_mux::respond_error(&mut stream, "403 Forbidden").await;
+        }
+        let Ok(quota) = notifier.take_quota() else {
+            warn!(
+                ?addr,
+                port = notifier.port,
+                "connection quota exceeded, closing connection"
+            );
+            return http_mux::respond_error(&mut stream, "503 Service Unavailable").await;
+        };
+        let Ok(queued) = notifier.enqueue() else {
+            warn!(
+                ?addr,
+                port = notifier.port,
+                "pending queue full, closing connection"
+            );
+            return http_mux::respond_error(&mut stream, "503 Service Unavailable").await;
+        };
+        let Ok(pending_slot) = acquire(&self.pending_slots) else {
+            warn!(
+                ?addr,
+                port = notifier.port,
+                "pending connection limit reached, closing connection"
+            );
+            return http_mux::respond_error(&mut stream, "503 Service Unavailable").await;
+        };
+        let incoming = Incoming::Http(stream, head);
+        let places = (queued, pending_slot);
+        let id = self.insert_pending(incoming, addr, (None, quota), places, bandwidth, &notifier);
+        self.emit(EventKind::ConnectionAccepted {
+            port: notifier.port,
+            peer_addr: addr,
+            id,
+        });
+        notifier.notify(id, addr).await;
+    }
+
+    /// Listener task for a TCP tunnel, notifying the client of new connections.
+    ///
+    /// Returns the reason that the tunnel closed.
+    ///
+    /// With a reservation, the tunnel is held when its control connection closes,
+    /// rather than closing with it.
+    async fn tcp_tunnel(
+        self: Arc<Self>,
+        listeners: Vec<TcpListener>,
+        mut notifier: Notifier,
+        mut reservation: Option<Reservation>,
+    ) -> &'static str {
+        let port = notifier.port;
+        let limit = self.connection_limit();
+        let bandwidth = self.bandwidth_limit();
+        let mut rate = self.connection_rate();
+        let expiry = self.tunnel_expiry();
+        loop {
+            let accept = select_all(listeners.iter().map(|listener| Box::pin(listener.accept())));
+            let event = tokio::select! {
+                accepted = self.until_idle(accept) => Some(accepted),
+                () = disconnected(&notifier, reservation.is_some()) => None,
+                () = expired(expiry) => {
+                    self.close_expired(&notifier).await;
+                    return "max lifetime";
+                }
+            };
+            let Some(accepted) = event else {
+                let reservation = reservation.as_mut().unwrap();
+                let Some(adoption) = self.hold(port, reservation).await else {
+                    return "reservation expired";
+                };
+                (notifier, reservation.lease) = adoption;
+                continue;
+            };
+            let Some((result, _, _)) = accepted else {
+                self.close_idle(&notifier).await;
+                return "idle timeout";
+            };
+            let (stream2, addr) = match result {
+                Ok(accepted) => accepted,
+                Err(err) if is_fatal_accept_error(&err) => {
+                    warn!(%err, ?port, "listener failed, closing tunnel");
+                    self.emit(EventKind::ListenerAborted {
+                        port,
+                        remote_addr: notifier.remote_addr,
+                    });
+                    notifier.close("tunnel listener failed").await;
+                    return "accept error";
+                }
+                Err(err) => {
+                    warn!(%err, ?port, "failed to accept connection");
+                    sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let local = stream2.local_addr().ok();
+            info!(?addr, ?local, ?port, "new connection");
+            if !self.accept_peer(port, addr) {
+                warn!(
+                    ?addr,
+                    ?port,
+                    "connection refused by accept filter, closing connection"
+                );
+                continue;
+            }
+            self.configure_stream(&stream2);
+            if rate.as_mut().is_some_and(|rate| !rate.check()) {
+                warn!(?addr, ?port, "connection rate exceeded, closing connection");
+                continue;
+            }
+            let Ok(permit) = acquire(&limit) else {
+                warn!(?addr, ?port, "connection limit reached, closing connection");
+                continue;
+            };
+            let Ok(quota) = notifier.take_quota() else {
+                warn!(
+                    ?addr,
+                    ?port,
+                    "connection quota exceeded, closing connection"
+                );
+                continue;
+            };
+            let permit = (permit, quota);
+            let Ok(queued) = notifier.enqueue() else {
+                warn!(?addr, ?port, "pending queue full, closing connection");
+                continue;
+            };
+            let Ok(pending_slot) = acquire(&self.pending_slots) else {
+                warn!(
+                    ?addr,
+                    ?port,
+                    "pending connection limit reached, closing connection"
+                );
+                continue;
+            };
+            let incoming = Incoming::Tcp(stream2);
+            let bandwidth = bandwidth.clone();
+            let places = (queued, pending_slot);
+            let id = self.insert_pending(incoming, addr, permit, places, bandwidth, &notifier);
+            self.emit(EventKind::ConnectionAccepted {
+                port,
+                peer_addr: addr,
+                id,
+            });
+            // Held tunnels notice the closed connection when they next wait for one.
+            if !notifier.notify(id, addr).await && reservation.is_none() {
+                return "control connection closed";
+            }
+        }
+    }
+
+    /// Listener task for a UDP tunnel, notifying the client of new sessions.
+    ///
+    /// Returns the reason that the tunnel closed.
+    async fn udp_tunnel(
+        self: Arc<Self>,
+        sockets: Vec<UdpSocket>,
+        notifier: Notifier,
+    ) -> &'static str {
+        let port = notifier.port;
+        let sockets: Vec<_> = sockets.into_iter().map(Arc::new).collect();
+        let limit = self.connection_limit();
+        let mut rate = self.connection_rate();
+        let mut sessions: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+        let mut bufs = vec![vec![0u8; u16::MAX as usize]; sockets.len()];
+        let expiry = self.tunnel_expiry();
+        loop {
+            sessions.retain(|_, tx| !tx.is_closed());
+            let recv = select_all(
+                sockets
+                    .iter()
+                    .zip(bufs.iter_mut())
+                    .map(|(socket, buf)| Box::pin(socket.recv_from(buf))),
+            );
+            // Drop the pending receives, releasing their borrows of the buffers.
+            let received = tokio::select! {
+                received = self.until_idle(recv) => received.map(|(result, index, _)| (result, index)),
+                () = expired(expiry) => {
+                    self.close_expired(&notifier).await;
+                    return "max lifetime";
+                }
+            };
+            let Some((result, index)) = received else {
+                self.close_idle(&notifier).await;
+                return "idle timeout";
+            };
+            let (len, addr) = match result {
+                Ok((len, addr)) => (len, addr),
+                Err(err) => {
+                    // Usually an ICMP error from a previous send, not fatal.
+                    warn!(%err, ?port, "udp receive error");
+                    continue;
+                }
+            };
+            let socket = &sockets[index];
+            let datagram = Bytes::copy_from_slice(&bufs[index][..len]);
+            if let Some(tx) = sessions.get(&addr) {
+                if tx.try_send(datagram.clone()).is_ok() || !tx.is_closed() {
+                    continue; // delivered, or dropped because the session is busy
+                }
+            }
+            if !self.accept_peer(port, addr) {
+                warn!(
+                    ?addr,
+                    ?port,
+                    "session refused by accept filter, dropping datagram"
+                );
+                continue;
+            }
+            if rate.as_mut().is_some_and(|rate| !rate.check()) {
+                warn!(?addr, ?port, "connection rate exceeded, dropping datagram");
+                continue;
+            }
+            let Ok(permit) = acquire(&limit) else {
+                warn!(?addr, ?port, "connection limit reached, dropping datagram");
+                continue;
+            };
+            let Ok(quota) = notifier.take_quota() else {
+                warn!(?addr, ?port, "connection quota exceeded, dropping datagram");
+                continue;
+            };
+            let permit = (permit, quota);
+            let Ok(queued) = notifier.enqueue() else {
+                warn!(?addr, ?port, "pending queue full, dropping datagram");
+                continue;
+            };
+            let Ok(pending_slot) = acquire(&self.pending_slots) else {
+                warn!(
+                    ?addr,
+                    ?port,
+                    "pending connection limit reached, dropping datagram"
+                );
+                continue;
+            };
+            let local = socket.local_addr().ok();
+            info!(?addr, ?local, ?port, "new udp session");
+            let (tx, rx) = mpsc::channel(64);
+            let _ = tx.try_send(datagram);
+            sessions.insert(addr, tx);
+            let session = UdpSession {
+                socket: Arc::clone(socket),
+                peer: addr,
+                datagrams: rx,
+            };
+            let incoming = Incoming::Udp(session);
+            let places = (queued, pending_slot);
+            let id = self.insert_pending(incoming, addr, permit, places, None, &notifier);
+            self.emit(EventKind::ConnectionAccepted {
+                port,
+                peer_addr: addr,
+                id,
+            });
+            if !notifier.notify(id, addr).await {
+                return "control connection closed";
+            }
+        }
+    }
+
+    /// Wait for activity on a tunnel, returning `None` if it stays idle for too long.
+    async fn until_idle<F: Future>(&self, activity: F) -> Option<F::Output> {
+        match self.idle_tunnel_timeout {
+            Some(idle_timeout) => timeout(idle_timeout, activity).await.ok(),
+            None => Some(activity.await),
+        }
+    }
+
+    /// Release an idle tunnel's port and tell its client.
+    async fn close_idle(&self, notifier: &Notifier) {
+        let port = notifier.port;
+        info!(?port, "closing idle tunnel");
+        self.emit(EventKind::ListenerAborted {
+            port,
+            remote_addr: notifier.remote_addr,
+        });
+        notifier.close("idle timeout").await;
+    }
+
+    /// When a tunnel opened now reaches its maximum lifetime, if it has one.
+    fn tunnel_expiry(&self) -> Option<Instant> {
+        self.max_tunnel_lifetime
+            .map(|lifetime| Instant::now() + lifetime)
+    }
+
+    /// Release the port of a tunnel that reached its maximum lifetime and tell its client.
+    async fn close_expired(&self, notifier: &Notifier) {
+        let port = notifier.port;
+        info!(?port, "closing tunnel at its maximum lifetime");
+        self.emit(EventKind::ListenerAborted {
+            port,
+            remote_addr: notifier.remote_addr,
+        });
+        notifier.close(LIFETIME_MESSAGE).await;
+    }
+
+    /// Create the semaphore bounding outstanding connections on a single tunnel.
+    fn connection_limit(&self) -> Option<Arc<Semaphore>> {
+        self.max_conns_per_port
+            .map(|max_conns| Arc::new(Semaphore::new(max_conns)))
+    }
+
+    /// Create the limiter on the rate of new connections to a single tunnel, if limited.
+    fn connection_rate(&self) -> Option<ConnectionRate> {
+        self.tunnel_rate_limit
+            .map(|(per_sec, burst)| ConnectionRate::new(per_sec, burst))
+    }
+
+    /// Create the budget shared by the connections of a single tunnel, if limited.
+    fn bandwidth_limit(&self) -> Option<Arc<Bandwidth>> {
+        self.bandwidth_limit
+            .map(|(rate, burst)| Arc::new(Bandwidth::new(rate, burst)))
+    }
+
+    /// Store an incoming connection until the client accepts it, returning its new ID.
+    ///
+    /// Connections are kept until the accept timeout, if there is one, and queued
+    /// connections only until the client disconnects, if that comes first.
+    fn insert_pending(
+        &self,
+        incoming: Incoming,
+        peer_addr: SocketAddr,
+        permit: ConnectionPermits,
+        places: PendingPermits,
+        bandwidth: Option<Arc<Bandwidth>>,
+        notifier: &Notifier,
+    ) -> Uuid {
+        let id = (self.id_generator)();
+        let (queued, pending_slot) = places;
+        let client = queued.is_some().then(|| notifier.tx.clone());
+        let pending = Pending {
+            incoming,
+            peer_addr,
+            bandwidth,
+            _permit: permit,
+            queued,
+            pending_slot,
+            activity: Arc::clone(&notifier.activity),
+        };
+        self.conns.insert(id, pending);
+        self.metrics
+            .connections_total
+            .fetch_add(1, Ordering::Relaxed);
+        let expired: BoxFuture<'static, ()> = match (client, self.accept_timeout) {
+            (Some(tx), Some(accept_timeout)) => Box::pin(async move {
+                tokio::select! {
+                    _ = tx.closed() => (),
+                    _ = sleep(accept_timeout) => (),
+                }
+            }),
+            (Some(tx), None) => Box::pin(async move { tx.closed().await }),
+            (None, Some(accept_timeout)) => Box::pin(sleep(accept_timeout)),
+            // Without an accept timeout, only an accept removes the connection.
+            (None, None) => return id,
+        };
+        let conns = Arc::clone(&self.conns);
+        let events = self.events.clone();
+        let banner = self.unavailable_banner.clone();
+        // Stay in the span of the tunnel, so that the removal is logged with it.
+        tokio::spawn(
+            async move {
+                expired.await;
+                if let Some((_, pending)) = conns.remove(&id) {
+                    warn!(%id, "removed stale connection");
+                    events.send(Event::now(EventKind::StaleConnectionRemoved { id }));
+                    if let Some(banner) = banner {
+                        send_banner(pending.incoming, &banner).await;
+                    }
+                }
+            }
+            .instrument(Span::current()),
+        );
+        id
+    }
+
+    /// Send an event to the event sink and subscribers, and to the webhook if it takes it.
+    fn emit(&self, kind: EventKind) {
+        let event = Event::now(kind);
+        if let Some(webhook) = &self.webhook {
+            if matches!(
+                event.kind,
+                EventKind::ListenerCreated { .. }
+                    | EventKind::ListenerAborted { .. }
+                    | EventKind::ConnectionForwarded { .. }
+                    | EventKind::ConnectionClosed { .. }
+            ) {
+                webhook.send(&event);
+            }
+        }
+        self.events.send(event);
+    }
+}
+
+impl UdpSession {
+    /// Relay datagrams between the remote peer and the client's accepted stream.
+    ///
+    /// The bytes sent to the remote peer, and received from it, are added to `sent`
+    /// and `received` as they are relayed.
+    async fn forward<S: AsyncRead + AsyncWrite + Unpin>(
+        mut self,
+        stream: Delimited<S>,
+        metrics: &Metrics,
+        sent: &AtomicU64,
+        received: &AtomicU64,
+    ) -> Result<()> {
+        let mut stream = stream.into_datagrams();
+        loop {
+            tokio::select! {
+                datagram = self.datagrams.recv() => match datagram {
+                    Some(datagram) => {
+                        metrics.add_forwarded_bytes(datagram.len() as u64);
+                        received.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                        stream.send(datagram).await?;
+                    }
+                    None => break,
+                },
+                frame = stream.next() => match frame {
+                    Some(frame) => {
+                        let frame = frame?;
+                        metrics.add_forwarded_bytes(frame.len() as u64);
+                        sent.fetch_add(frame.len() as u64, Ordering::Relaxed);
+                        self.socket.send_to(&frame, self.peer).await?;
+                    }
+                    None => break,
+                },
+                _ = sleep(UDP_SESSION_TIMEOUT) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that an address can be bound to, meaning that it belongs to this host.
+fn check_local_addr(addr: IpAddr) -> io::Result<()> {
+    std::net::UdpSocket::bind((addr, 0)).map(drop)
+}
+
+/// Route of a named tunnel, which is removed when dropped.
+struct NamedRoute(Arc<NamedTunnels>, String);
+
+impl Drop for NamedRoute {
+    fn drop(&mut self) {
+        self.0.remove(&self.1);
+    }
+}
+
+/// Take a permit from an optional connection limit, failing if none are left.
+fn acquire(
+    limit: &Option<Arc<Semaphore>>,
+) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+    match limit {
+        Some(limit) => Arc::clone(limit).try_acquire_owned().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Returns whether an error from accepting a connection means the listener is broken.
+///
+/// Other errors, such as a connection reset before it was accepted or running out of
+/// file descriptors, are expected to pass.
+fn is_fatal_accept_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+    )
+}
+
+/// Wait until a tunnel reaches its maximum lifetime, or forever if it has none.
+async fn expired(expiry: Option<Instant>) {
+    match expiry {
+        Some(expiry) => sleep_until(expiry).await,
+        None => future::pending().await,
+    }
+}
+
+/// Abort a task, waiting until it has dropped its listeners so that the port can be bound again.
+async fn abort_and_wait(handle: &AbortHandle) {
+    handle.abort();
+    let finished = async {
+        while !handle.is_finished() {
+            sleep(Duration::from_millis(1)).await;
+        }
+    };
+    let _ = timeout(NETWORK_TIMEOUT, finished).await;
+}
+
+/// Key identifying the owner of a port in [`PortOwners`].
+///
+/// Clients are identified by IP address alone, because a reconnecting client
+/// almost always comes from a new source port. IPv4-mapped IPv6 addresses are
+/// converted to plain IPv4, so the same host matches whether it reached a
+/// dual-stack or an IPv4 listener. As a consequence, clients that share a
+/// public IP, such as those behind carrier-grade NAT, can take over ports
+/// requested by one another.
+fn owner_key(port: u16, remote_addr: Option<SocketAddr>) -> (u16, Option<IpAddr>) {
+    (port, remote_addr.map(|addr| addr.ip().to_canonical()))
+}
+
+/// A tunnel listening on a port, as tracked in [`PortOwners`].
+struct TunnelEntry {
+    /// Distinguishes this tunnel from a later owner of the same port and address.
+    id: Uuid,
+
+    /// Full address of the client.
+    remote_addr: Option<SocketAddr>,
+
+    /// Listener task of the tunnel.
+    handle: Arc<AbortHandle>,
+
+    /// Time when the tunnel opened.
+    opened: Instant,
+
+    /// Channel of messages to the control connection that owns the tunnel.
+    tx: mpsc::Sender<ServerMessage>,
+
+    /// Label that the client gave itself, if any.
+    label: Option<String>,
+}
+
+/// Ownership of a port by a listener task, released when the task exits or is aborted.
+struct PortOwner {
+    port_owners: Arc<PortOwners>,
+    key: (u16, Option<IpAddr>),
+
+    /// Distinguishes this task from a later owner of the same port and address.
+    id: Uuid,
+
+    /// Why the task exited, logged when it is dropped.
+    reason: &'static str,
+
+    /// Slot of the tunnel in the server's tunnel limit, and in its secret's quota.
+    _permit: TunnelPermits,
+}
+
+impl PortOwner {
+    fn new(
+        server: &Server,
+        port: u16,
+        remote_addr: Option<SocketAddr>,
+        permit: TunnelPermits,
+    ) -> Self {
+        PortOwner {
+            port_owners: Arc::clone(&server.port_owners),
+            key: owner_key(port, remote_addr),
+            id: Uuid::new_v4(),
+            reason: "aborted",
+            _permit: permit,
+        }
+    }
+
+    /// Run the listener task, recording the reason it returns.
+    async fn run(mut self, task: impl Future<Output = &'static str>) {
+        self.reason = task.await;
+    }
+}
+
+impl Drop for PortOwner {
+    fn drop(&mut self) {
+        // The port may already belong to a new task, if a reconnecting client took it over.
+        self.port_owners
+            .remove_if(&self.key, |_, tunnel| tunnel.id == self.id);
+        let (port, remote_ip) = self.key;
+        info!(port, ?remote_ip, reason = self.reason, "listener exited");
+    }
+}
+
+/// Reservation of a TCP tunnel for a client that may reconnect.
+struct Reservation {
+    /// Token that the client must send again to take the tunnel back.
+    token: String,
+
+    /// ID of the tunnel in [`PortOwners`].
+    owner: Uuid,
+
+    /// Time to hold the tunnel after its control connection closes.
+    grace: Duration,
+
+    /// Lease of the current control connection, released when the tunnel closes.
+    lease: oneshot::Sender<()>,
+}
+
+/// A tunnel whose client disconnected, waiting for it to come back.
+struct Held {
+    /// ID of the tunnel in [`PortOwners`].
+    owner: Uuid,
+
+    /// Hands the tunnel to the new control connection.
+    adopt: oneshot::Sender<Adoption>,
+}
+
+/// Removes a held tunnel from the reservations when it is dropped, unless it was adopted.
+struct Unhold<'a> {
+    reservations: &'a Reservations,
+    key: (String, u16),
+    owner: Uuid,
+}
+
+impl Drop for Unhold<'_> {
+    fn drop(&mut self) {
+        self.reservations
+            .remove_if(&self.key, |_, held| held.owner == self.owner);
+    }
+}
+
+/// Wait for the control connection of a held tunnel to close, or forever if it is not held.
+async fn disconnected(notifier: &Notifier, held: bool) {
+    if held {
+        notifier.tx.closed().await;
+    } else {
+        future::pending().await
+    }
+}
+
+/// Create a non-blocking UDP socket bound to an address, restricted to a network interface.
+#[cfg(target_os = "linux")]
+fn device_socket(addr: SocketAddr, interface: &str) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.bind_device(Some(interface.as_bytes()))?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Make a client label safe to log, returning `None` if nothing is left of it.
+///
+/// Control characters are dropped, so a label cannot forge log lines or terminal
+/// escapes, and the label is cut to [`MAX_LABEL_LENGTH`] characters.
+fn sanitize_label(label: &str) -> Option<String> {
+    let label: String = label
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_LABEL_LENGTH)
+        .collect();
+    let label = label.trim();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// Write a banner to a TCP connection that no client accepted, then close it.
+async fn send_banner(incoming: Incoming, banner: &[u8]) {
+    let (Incoming::Tcp(mut stream) | Incoming::Http(mut stream, _)) = incoming else {
+        return;
+    };
+    let write = async {
+        stream.write_all(banner).await?;
+        stream.shutdown().await
+    };
+    if let Ok(Err(err)) = timeout(NETWORK_TIMEOUT, write).await {
+        warn!(%err, "could not write unavailable banner");
+    }
+}
+
+/// Send an error to a control connection that is refused, then close it.
+async fn reject(stream: TcpStream, message: &str) {
+    let mut stream = Delimited::new(stream);
+    let message = ServerMessage::Error(message.into());
+    let _ = timeout(NETWORK_TIMEOUT, stream.send(message)).await;
 }
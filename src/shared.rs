@@ -1,13 +1,22 @@
 //! Shared data structures, utilities, and protocol definitions.
 
+use std::fmt;
+use std::future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::io::{self, AsyncRead, AsyncWrite};
-use tokio::time::timeout;
-use tokio_util::codec::{AnyDelimiterCodec, Framed, FramedParts};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Instant};
+use tokio_util::codec::{
+    AnyDelimiterCodec, AnyDelimiterCodecError, Framed, FramedParts, LengthDelimitedCodec,
+};
 use tracing::trace;
 use uuid::Uuid;
 
@@ -17,20 +26,122 @@ pub const CONTROL_PORT: u16 = 7835;
 /// Maximum byte length for a JSON frame in the stream.
 pub const MAX_FRAME_LENGTH: usize = 256;
 
+/// Longest client label that the server keeps, in characters.
+pub const MAX_LABEL_LENGTH: usize = 64;
+
+/// Error sent by the server to a client whose secret it does not accept.
+pub const INVALID_SECRET: &str = "invalid secret";
+
+/// Error sent by the server to a client that did not answer its challenge.
+pub const SECRET_REQUIRED: &str = "server requires secret, but no secret was provided";
+
+/// Latest version of the control protocol, which peers negotiate down to a common one.
+///
+/// Version 0 is the original protocol, where the client never sends a version. Version 1
+/// adds named tunnels and peer addresses, version 2 adds replies to accepts, version 3
+/// adds port reservations, version 4 adds admin commands, version 5 adds advertised
+/// hostnames, version 6 adds client labels, version 7 adds tunnel status reports,
+/// version 8 adds tunnel addresses chosen by the client, and version 9 adds compression.
+pub const PROTOCOL_VERSION: u32 = 9;
+
 /// Timeout for network connections and initial protocol messages.
 pub const NETWORK_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Inactivity timeout after which a forwarded UDP session is closed.
+pub const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
 /// A message from the client on the control connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessage {
     /// Response to an authentication challenge from the server.
     Authenticate(String),
 
+    /// Latest protocol version that the client supports, sent before the first hello.
+    ///
+    /// The server replies with [`ServerMessage::Version`]. Clients that do not send
+    /// this are assumed to speak version 0.
+    Version(u32),
+
     /// Initial client message specifying a port to forward.
+    ///
+    /// Sending it again on the same control connection requests another tunnel.
     Hello(u16),
 
+    /// Initial client message specifying a UDP port to forward.
+    HelloUdp(u16),
+
+    /// Initial client message requesting an HTTP tunnel by name, instead of by port.
+    ///
+    /// The server routes HTTP requests on its shared HTTP port to this tunnel when
+    /// the first label of their `Host` header matches the name.
+    HelloNamed(String),
+
     /// Accepts an incoming TCP connection, using this stream as a proxy.
     Accept(Uuid),
+
+    /// Like [`ClientMessage::Accept`], asking the server to reply before forwarding.
+    ///
+    /// The server replies with [`ServerMessage::Accepted`], or with an error if the
+    /// connection is no longer pending, such as after the accept timeout.
+    AcceptWithReply(Uuid),
+
+    /// Asks to be told the address of each remote peer, sent before the first hello.
+    ///
+    /// The server then announces connections with [`ServerMessage::PeerConnection`].
+    ReportPeers,
+
+    /// Asks the server to hold this connection's TCP tunnels when it closes.
+    ///
+    /// This is sent before the first hello, after [`ClientMessage::ReportPeers`]. A
+    /// later connection with the same token takes back a held tunnel by requesting
+    /// its port.
+    Reserve(String),
+
+    /// Human-readable name of the client, which the server logs with its tunnels.
+    ///
+    /// This is sent before the first hello, after [`ClientMessage::Reserve`].
+    Label(String),
+
+    /// Asks for the status of each tunnel with every heartbeat, sent before the first hello.
+    ///
+    /// This is sent after [`ClientMessage::Label`]. The server then sends
+    /// [`ServerMessage::Status`] messages instead of [`ServerMessage::Heartbeat`].
+    ReportStatus,
+
+    /// Asks for this connection's tunnels to listen on one address of the server.
+    ///
+    /// This is sent before the first hello, after [`ClientMessage::ReportStatus`]. Servers
+    /// bind the tunnels to their usual addresses instead if this one is not allowed.
+    BindTunnels(IpAddr),
+
+    /// Asks to compress the data of forwarded TCP connections, sent before the first hello.
+    ///
+    /// This is sent after [`ClientMessage::BindTunnels`]. Servers that allow it reply with
+    /// [`ServerMessage::Compress`]. The client then sends this again first on each data
+    /// connection that it compresses, followed by the accept, and both sides send the
+    /// forwarded bytes as a raw deflate stream.
+    Compress,
+
+    /// Closes the tunnel on this public port, freeing the port right away.
+    ///
+    /// The server replies with [`ServerMessage::Released`], even if the connection had
+    /// no such tunnel. When no tunnels are left, the control connection closes.
+    Release(u16),
+
+    /// Asks for the tunnels open on the server, as an administrator.
+    ///
+    /// Admin commands are sent instead of the first hello. The server then sends a
+    /// challenge for its admin secret, on top of any for the client secret, and replies
+    /// with [`ServerMessage::TunnelList`].
+    AdminList,
+
+    /// Closes the tunnels on this public port, as an administrator.
+    ///
+    /// This is authenticated like [`ClientMessage::AdminList`]. The clients that owned
+    /// the tunnels are sent [`ServerMessage::Released`], and the server replies with
+    /// [`ServerMessage::Killed`]. Their control connections stay open, even with no
+    /// tunnels left, so clients that reconnect on a lost connection do not reopen them.
+    AdminKill(u16),
 }
 
 /// A message from the server on the control connection.
@@ -42,23 +153,238 @@ pub enum ServerMessage {
     /// Response to a client's initial message, with actual public port.
     Hello(u16),
 
+    /// Protocol version used for the rest of the connection, in reply to the client's.
+    Version(u32),
+
+    /// Public hostname of the server, sent before the reply to the first hello.
+    ///
+    /// This is only sent if the server is configured with one, to clients that
+    /// negotiated version 5 or later.
+    Host(String),
+
+    /// Agrees to compress data connections, in reply to [`ClientMessage::Compress`].
+    ///
+    /// This is sent before the reply to the first hello, and only if the server allows
+    /// compression.
+    Compress,
+
     /// No-op used to test if the client is still reachable.
     Heartbeat,
 
-    /// Asks the client to accept a forwarded TCP connection.
+    /// Like [`ServerMessage::Heartbeat`], with the status of a tunnel on the connection.
+    ///
+    /// Clients that sent [`ClientMessage::ReportStatus`] get one of these for each
+    /// tunnel instead of each heartbeat, which keeps every frame short.
+    Status(TunnelStatus),
+
+    /// Asks the client to accept a forwarded TCP connection or UDP session.
     Connection(Uuid),
 
+    /// Like [`ServerMessage::Connection`], for a tunnel requested by a later hello.
+    ///
+    /// Carries the public port of the tunnel, so that a client forwarding several
+    /// ports over one control connection knows where the connection belongs.
+    TunnelConnection(u16, Uuid),
+
+    /// Like [`ServerMessage::TunnelConnection`], with the address of the remote peer.
+    ///
+    /// This is sent for every tunnel instead, if the client asked for it.
+    PeerConnection {
+        /// Public port of the tunnel.
+        port: u16,
+        /// ID of the connection to accept.
+        id: Uuid,
+        /// Address of the remote peer that connected to the tunnel.
+        remote: SocketAddr,
+    },
+
+    /// Confirms that a connection is forwarded, in reply to [`ClientMessage::AcceptWithReply`].
+    Accepted(Uuid),
+
+    /// Confirms that a tunnel was closed in reply to [`ClientMessage::Release`].
+    ///
+    /// This is also sent unprompted when an administrator closes the tunnel.
+    Released(u16),
+
+    /// Tunnels open on the server, in reply to [`ClientMessage::AdminList`].
+    TunnelList(Vec<TunnelInfo>),
+
+    /// Reply to [`ClientMessage::AdminKill`].
+    Killed {
+        /// Public port that was asked for.
+        port: u16,
+        /// Whether a tunnel was open on the port and is now closed.
+        closed: bool,
+    },
+
     /// Indicates a server error that terminates the connection.
+    ///
+    /// The exceptions are when a single incoming connection is refused because the
+    /// client's quota of connections is used up, which leaves the tunnel open, and
+    /// when a hello after the first is refused, which leaves the earlier tunnels open.
     Error(String),
 }
 
+/// A tunnel open on the server, as listed for administrators.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    /// Public port of the tunnel.
+    pub port: u16,
+
+    /// Address of the client, or `None` for clients connected over a Unix socket.
+    pub client: Option<SocketAddr>,
+
+    /// Time since the tunnel opened.
+    pub uptime: Duration,
+
+    /// Label that the client gave itself, if any.
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+/// Activity on a tunnel, as reported to its client with heartbeats.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    /// Public port of the tunnel.
+    pub port: u16,
+
+    /// Connections and UDP sessions that are being forwarded.
+    pub connections: u64,
+
+    /// Bytes forwarded in either direction by connections that have closed.
+    pub bytes: u64,
+}
+
+/// Failure modes of the client that callers may want to handle.
+///
+/// Errors returned when connecting to the server can be downcast to this type,
+/// for example with [`anyhow::Error::downcast_ref`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ClientError {
+    /// Could not open a connection.
+    Connect(io::Error),
+
+    /// The server requires a secret, but none was provided.
+    AuthRequired,
+
+    /// The server rejected the secret.
+    AuthRejected,
+
+    /// A secret was provided, but the server does not require one.
+    AuthNotRequired,
+
+    /// A secret was provided, but the server sent no challenge in time.
+    ///
+    /// Servers that do not require a secret send none, but a slow server may look
+    /// the same, so this is kept apart from [`ClientError::AuthNotRequired`].
+    AuthTimeout,
+
+    /// The requested port is outside the range that the server allows.
+    PortNotInRange,
+
+    /// The requested port is already in use on the server.
+    PortInUse,
+
+    /// The server has no free port to assign.
+    NoPortAvailable,
+
+    /// The server is refusing connections from this address for now.
+    RateLimited,
+
+    /// The server already has as many tunnels open as it allows.
+    AtCapacity,
+
+    /// The secret already has as many tunnels open as its quota allows.
+    QuotaExceeded,
+
+    /// A connection was accepted after the server stopped waiting for it.
+    ConnectionExpired,
+
+    /// Any other error reported by the server.
+    Other(String),
+}
+
+impl ClientError {
+    /// Categorize an error message sent by the server.
+    pub fn from_server(message: String) -> Self {
+        match message.as_str() {
+            SECRET_REQUIRED => Self::AuthRequired,
+            INVALID_SECRET => Self::AuthRejected,
+            "client port number not in allowed range" => Self::PortNotInRange,
+            "port already in use" => Self::PortInUse,
+            "failed to find an available port" => Self::NoPortAvailable,
+            "rate limit exceeded" => Self::RateLimited,
+            "server at capacity, retry later" => Self::AtCapacity,
+            "tunnel quota exceeded" => Self::QuotaExceeded,
+            "connection expired" => Self::ConnectionExpired,
+            _ => Self::Other(message),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect(err) => write!(f, "{err}"),
+            Self::AuthRequired => {
+                write!(
+                    f,
+                    "server requires authentication, but no client secret was provided"
+                )
+            }
+            Self::AuthRejected => write!(f, "authentication failed: check your secret"),
+            Self::AuthNotRequired => {
+                write!(
+                    f,
+                    "server does not require a secret; remove it from the client"
+                )
+            }
+            Self::AuthTimeout => {
+                write!(
+                    f,
+                    "server sent no challenge in time; it may not require a secret"
+                )
+            }
+            Self::PortNotInRange => {
+                write!(f, "server error: client port number not in allowed range")
+            }
+            Self::PortInUse => write!(f, "server error: port already in use"),
+            Self::NoPortAvailable => write!(f, "server error: failed to find an available port"),
+            Self::RateLimited => write!(f, "server error: rate limit exceeded"),
+            Self::AtCapacity => write!(f, "server error: server at capacity, retry later"),
+            Self::QuotaExceeded => write!(f, "server error: tunnel quota exceeded"),
+            Self::ConnectionExpired => write!(f, "server error: connection expired"),
+            Self::Other(message) => write!(f, "server error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 /// Transport stream with JSON frames delimited by null characters.
 pub struct Delimited<U>(Framed<U, AnyDelimiterCodec>);
 
 impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     /// Construct a new delimited stream.
     pub fn new(stream: U) -> Self {
-        let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], MAX_FRAME_LENGTH);
+        Self::with_max_length(stream, MAX_FRAME_LENGTH)
+    }
+
+    /// Construct a new delimited stream, accepting frames of up to `max_length` bytes.
+    ///
+    /// Longer frames are rejected with an error as soon as the limit is passed, so at
+    /// most this many bytes are buffered. The default is [`MAX_FRAME_LENGTH`], which
+    /// fits every control message.
+    pub fn with_max_length(stream: U, max_length: usize) -> Self {
+        let codec = AnyDelimiterCodec::new_with_max_length(vec![0], vec![0], max_length);
         Self(Framed::new(stream, codec))
     }
 
@@ -66,7 +392,14 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
         trace!("waiting to receive json message");
         if let Some(next_message) = self.0.next().await {
-            let byte_message = next_message.context("frame error, invalid byte length")?;
+            let byte_message = match next_message {
+                Ok(byte_message) => byte_message,
+                Err(AnyDelimiterCodecError::MaxChunkLengthExceeded) => {
+                    let max_length = self.0.codec().max_length();
+                    bail!("frame exceeds maximum length of {max_length} bytes");
+                }
+                Err(err) => return Err(err).context("frame error, invalid byte length"),
+            };
             let serialized_obj =
                 serde_json::from_slice(&byte_message).context("unable to parse message")?;
             Ok(serialized_obj)
@@ -96,19 +429,207 @@ impl<U: AsyncRead + AsyncWrite + Unpin> Delimited<U> {
     pub fn into_parts(self) -> FramedParts<U, AnyDelimiterCodec> {
         self.0.into_parts()
     }
+
+    /// Consume this object, continuing the stream as length-prefixed UDP datagrams.
+    pub fn into_datagrams(self) -> Framed<U, LengthDelimitedCodec> {
+        let parts = self.0.into_parts();
+        debug_assert!(parts.write_buf.is_empty(), "framed write buffer not empty");
+        let codec = LengthDelimitedCodec::builder()
+            .length_field_length(2)
+            .new_codec();
+        let mut new_parts = FramedParts::new::<Bytes>(parts.io, codec);
+        new_parts.read_buf = parts.read_buf;
+        Framed::from_parts(new_parts)
+    }
 }
 
 /// Copy data mutually between two read/write streams.
-pub async fn proxy<S1, S2>(stream1: S1, stream2: S2) -> io::Result<()>
+///
+/// When one stream reaches EOF, the write side of the other is shut down, and data
+/// keeps flowing the other way, so protocols that half-close a connection still work.
+/// This returns once both directions are done, with the number of bytes copied from
+/// `stream1` to `stream2`, and from `stream2` to `stream1`, respectively.
+pub async fn proxy<S1, S2>(stream1: S1, stream2: S2) -> io::Result<(u64, u64)>
+where
+    S1: AsyncRead + AsyncWrite + Unpin,
+    S2: AsyncRead + AsyncWrite + Unpin,
+{
+    proxy_with_idle_timeout(stream1, stream2, None).await
+}
+
+/// Like [`proxy`], but fails with [`io::ErrorKind::TimedOut`] if no data is copied in
+/// either direction for `idle_timeout`.
+///
+/// This frees connections where the other side went away without closing them.
+pub async fn proxy_with_idle_timeout<S1, S2>(
+    stream1: S1,
+    stream2: S2,
+    idle_timeout: Option<Duration>,
+) -> io::Result<(u64, u64)>
+where
+    S1: AsyncRead + AsyncWrite + Unpin,
+    S2: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+    proxy_counted(stream1, stream2, idle_timeout, &sent, &received).await?;
+    Ok((sent.into_inner(), received.into_inner()))
+}
+
+/// Like [`proxy_with_idle_timeout`], but adds the bytes to `sent` and `received` as they
+/// are copied, so that they are counted even if proxying fails.
+pub(crate) async fn proxy_counted<S1, S2>(
+    stream1: S1,
+    stream2: S2,
+    idle_timeout: Option<Duration>,
+    sent: &AtomicU64,
+    received: &AtomicU64,
+) -> io::Result<()>
 where
     S1: AsyncRead + AsyncWrite + Unpin,
     S2: AsyncRead + AsyncWrite + Unpin,
 {
     let (mut s1_read, mut s1_write) = io::split(stream1);
     let (mut s2_read, mut s2_write) = io::split(stream2);
+    let activity = Activity::new();
+    let copy = async {
+        tokio::try_join!(
+            copy_counted(&mut s1_read, &mut s2_write, sent, &activity),
+            copy_counted(&mut s2_read, &mut s1_write, received, &activity),
+        )
+    };
     tokio::select! {
-        res = io::copy(&mut s1_read, &mut s2_write) => res,
-        res = io::copy(&mut s2_read, &mut s1_write) => res,
-    }?;
+        res = copy => res.map(|_| ()),
+        res = activity.until_idle(idle_timeout) => res,
+    }
+}
+
+/// Time of the last data copied by [`proxy_with_idle_timeout`].
+struct Activity {
+    start: Instant,
+
+    /// Milliseconds from `start` to the last copy.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that data was just copied.
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// Wait until there has been no activity for the timeout, or forever if there is none.
+    async fn until_idle(&self, idle_timeout: Option<Duration>) -> io::Result<()> {
+        let Some(idle_timeout) = idle_timeout else {
+            return future::pending().await;
+        };
+        loop {
+            let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let idle = self.start.elapsed().saturating_sub(last);
+            if idle >= idle_timeout {
+                let message = format!("no data transferred for {idle_timeout:?}");
+                return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+            }
+            sleep(idle_timeout - idle).await;
+        }
+    }
+}
+
+/// Copy data from a reader to a writer until EOF, keeping a running byte count, then
+/// shut down the writer.
+async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    count: &AtomicU64,
+    activity: &Activity,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            // The other side may have closed already, which leaves nothing to shut down.
+            return match writer.shutdown().await {
+                Err(err) if err.kind() == io::ErrorKind::NotConnected => Ok(()),
+                res => res,
+            };
+        }
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        count.fetch_add(n as u64, Ordering::Relaxed);
+        activity.touch();
+    }
+}
+
+/// TCP keepalive settings, which probe idle connections to detect peers that went away.
+///
+/// See [`Server::set_keepalive`](crate::server::Server::set_keepalive) and
+/// [`Client::set_keepalive`](crate::client::Client::set_keepalive).
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    /// Time a connection is idle before the first probe is sent.
+    pub idle: Duration,
+
+    /// Time between probes, or `None` for the system default.
+    pub interval: Option<Duration>,
+
+    /// Number of unanswered probes before the connection is dropped, or `None` for the
+    /// system default.
+    pub count: Option<u32>,
+}
+
+impl Keepalive {
+    /// Socket option for these settings, leaving out what the platform does not support.
+    fn to_socket(self) -> TcpKeepalive {
+        let keepalive = TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "netbsd",
+            target_vendor = "apple",
+            windows,
+        ))]
+        let keepalive = match self.interval {
+            Some(interval) => keepalive.with_interval(interval),
+            None => keepalive,
+        };
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "netbsd",
+            target_vendor = "apple",
+        ))]
+        let keepalive = match self.count {
+            Some(count) => keepalive.with_retries(count),
+            None => keepalive,
+        };
+        keepalive
+    }
+}
+
+/// Set `TCP_NODELAY` and keepalive on a connection, leaving the defaults if they are not set.
+pub fn set_stream_options(
+    stream: &TcpStream,
+    nodelay: bool,
+    keepalive: Option<Keepalive>,
+) -> io::Result<()> {
+    if nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&keepalive.to_socket())?;
+    }
     Ok(())
 }
@@ -0,0 +1,285 @@
+//! Minimal SOCKS5 support: a server used as the local target of a client tunnel, and
+//! a client for reaching the bore server through a SOCKS5 proxy.
+//!
+//! Only the `CONNECT` command is supported, as described in
+//! [RFC 1928](https://www.rfc-editor.org/rfc/rfc1928). The server requires no
+//! authentication, while the client may also authenticate with a user and password,
+//! as described in [RFC 1929](https://www.rfc-editor.org/rfc/rfc1929).
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Version number that starts every SOCKS5 message.
+const VERSION: u8 = 5;
+
+/// Authentication method that requires nothing of the client.
+const NO_AUTH: u8 = 0x00;
+
+/// Authentication method with a user and password.
+const USER_PASSWORD: u8 = 0x02;
+
+/// Version number of the user and password subnegotiation.
+const USER_PASSWORD_VERSION: u8 = 1;
+
+/// Reply to a greeting when none of the offered methods are supported.
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+/// Command to open a TCP connection to the destination.
+const CONNECT: u8 = 0x01;
+
+/// Reply code for a request that succeeded.
+const SUCCEEDED: u8 = 0x00;
+
+/// Reply code for a failure that has no more specific code.
+const GENERAL_FAILURE: u8 = 0x01;
+
+/// Reply code for a destination network that cannot be reached.
+const NETWORK_UNREACHABLE: u8 = 0x03;
+
+/// Reply code for a destination host that cannot be reached.
+const HOST_UNREACHABLE: u8 = 0x04;
+
+/// Reply code for a destination that refused the connection.
+const CONNECTION_REFUSED: u8 = 0x05;
+
+/// Reply code for a command other than `CONNECT`.
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Reply code for an unknown address type.
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Negotiate a SOCKS5 request, returning a connection to the destination it asked for.
+///
+/// The request is read from `reader`, and the client is sent a reply on `writer` in
+/// every case, so it learns why a request failed. Replies are flushed, as `writer` may
+/// buffer them, such as on a compressed tunnel.
+pub(crate) async fn accept<R, W>(reader: &mut R, writer: &mut W) -> Result<TcpStream>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let [version, method_count] = read_array(reader).await?;
+    if version != VERSION {
+        bail!("unsupported socks version {version}");
+    }
+    let mut methods = vec![0; method_count.into()];
+    reader.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        writer.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        writer.flush().await?;
+        bail!("socks client requires authentication");
+    }
+    writer.write_all(&[VERSION, NO_AUTH]).await?;
+    writer.flush().await?;
+
+    let [_, command, _, address_type] = read_array(reader).await?;
+    let host = match address_type {
+        0x01 => Ipv4Addr::from(read_array::<4>(reader).await?).to_string(),
+        0x03 => {
+            let [len] = read_array(reader).await?;
+            let mut domain = vec![0; len.into()];
+            reader.read_exact(&mut domain).await?;
+            String::from_utf8_lossy(&domain).into_owned()
+        }
+        0x04 => Ipv6Addr::from(read_array::<16>(reader).await?).to_string(),
+        _ => {
+            reply(writer, ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            bail!("unsupported socks address type {address_type}");
+        }
+    };
+    let port = u16::from_be_bytes(read_array(reader).await?);
+    if command != CONNECT {
+        reply(writer, COMMAND_NOT_SUPPORTED, None).await?;
+        bail!("unsupported socks command {command}");
+    }
+
+    let connect = timeout(NETWORK_TIMEOUT, TcpStream::connect((host.as_str(), port))).await;
+    match connect {
+        Ok(Ok(target)) => {
+            reply(writer, SUCCEEDED, target.local_addr().ok()).await?;
+            Ok(target)
+        }
+        Ok(Err(err)) => {
+            reply(writer, failure_code(&err), None).await?;
+            bail!("could not connect to {host}:{port}: {err}");
+        }
+        Err(_) => {
+            reply(writer, HOST_UNREACHABLE, None).await?;
+            bail!("timed out connecting to {host}:{port}");
+        }
+    }
+}
+
+/// A SOCKS5 proxy that tunnels TCP connections with `CONNECT` requests.
+#[derive(Clone, Debug)]
+pub(crate) struct Socks5Proxy {
+    /// Host name or IP address of the proxy.
+    pub host: String,
+
+    /// Port of the proxy.
+    pub port: u16,
+
+    /// User and password to authenticate with, if the proxy requires them.
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    /// Create a proxy from its address as `host:port`, and credentials if it needs them.
+    pub fn new(addr: &str, credentials: Option<(&str, &str)>) -> Result<Self> {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .filter(|(_, port)| !port.contains(']'))
+            .with_context(|| format!("socks proxy address {addr:?} has no port"))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("socks proxy address {addr:?} has an invalid port"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        ensure!(!host.is_empty(), "socks proxy address {addr:?} has no host");
+        if let Some((user, password)) = credentials {
+            ensure!(
+                user.len() <= 255 && password.len() <= 255,
+                "socks proxy user and password must be at most 255 bytes"
+            );
+        }
+        Ok(Socks5Proxy {
+            host: host.into(),
+            port,
+            credentials: credentials.map(|(user, password)| (user.into(), password.into())),
+        })
+    }
+
+    /// Ask the proxy to open a tunnel to a destination, on a stream connected to it.
+    ///
+    /// Host names are resolved by the proxy, which is needed to reach hidden services
+    /// over Tor. Once this returns, the stream carries data to and from the destination.
+    pub async fn connect<S>(&self, stream: &mut S, to: &str, port: u16) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let method = if self.credentials.is_some() {
+            USER_PASSWORD
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let [version, chosen] = read_array(stream)
+            .await
+            .context("proxy closed the connection")?;
+        ensure!(
+            version == VERSION,
+            "proxy replied with socks version {version}"
+        );
+        match (chosen, &self.credentials) {
+            (NO_AUTH, _) => (),
+            (USER_PASSWORD, Some((user, password))) => {
+                let mut message = vec![USER_PASSWORD_VERSION, user.len() as u8];
+                message.extend(user.as_bytes());
+                message.push(password.len() as u8);
+                message.extend(password.as_bytes());
+                stream.write_all(&message).await?;
+                let [_, status] = read_array(stream).await?;
+                ensure!(status == SUCCEEDED, "proxy rejected the user and password");
+            }
+            _ => bail!("proxy requires authentication"),
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match to.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend(ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend(ip.octets());
+            }
+            Err(_) => {
+                ensure!(to.len() <= 255, "host name {to} is too long for socks");
+                request.extend([0x03, to.len() as u8]);
+                request.extend(to.as_bytes());
+            }
+        }
+        request.extend(port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let [_, code, _, address_type] = read_array(stream).await?;
+        if code != SUCCEEDED {
+            bail!(
+                "proxy refused to connect to {to}:{port}: {}",
+                reply_reason(code)
+            );
+        }
+        // Skip the address that the proxy bound, which the client has no use for.
+        let len = match address_type {
+            0x01 => 4,
+            0x03 => read_array::<1>(stream).await?[0].into(),
+            0x04 => 16,
+            _ => bail!("proxy replied with address type {address_type}"),
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+/// Describe a reply code for a request that failed.
+fn reply_reason(code: u8) -> &'static str {
+    match code {
+        GENERAL_FAILURE => "general failure",
+        0x02 => "not allowed by ruleset",
+        NETWORK_UNREACHABLE => "network unreachable",
+        HOST_UNREACHABLE => "host unreachable",
+        CONNECTION_REFUSED => "connection refused",
+        0x06 => "TTL expired",
+        COMMAND_NOT_SUPPORTED => "command not supported",
+        ADDRESS_TYPE_NOT_SUPPORTED => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Read a fixed number of bytes from the stream.
+async fn read_array<const N: usize>(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Reply to a request, with the address bound to reach the destination, if any.
+async fn reply(
+    stream: &mut (impl AsyncWrite + Unpin),
+    code: u8,
+    bound: Option<SocketAddr>,
+) -> io::Result<()> {
+    let bound = bound.unwrap_or_else(|| (Ipv4Addr::UNSPECIFIED, 0).into());
+    let mut message = vec![VERSION, code, 0];
+    match bound {
+        SocketAddr::V4(addr) => {
+            message.push(0x01);
+            message.extend(addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            message.push(0x04);
+            message.extend(addr.ip().octets());
+        }
+    }
+    message.extend(bound.port().to_be_bytes());
+    stream.write_all(&message).await?;
+    stream.flush().await
+}
+
+/// Reply code describing why a connection to the destination failed.
+fn failure_code(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        io::ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable => HOST_UNREACHABLE,
+        _ => GENERAL_FAILURE,
+    }
+}
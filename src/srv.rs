@@ -0,0 +1,389 @@
+//! A minimal DNS client for SRV records, used to find the local target of a tunnel.
+//!
+//! Only what a lookup needs is supported: one question, sent over UDP and retried
+//! over TCP if the answer is truncated, as described in
+//! [RFC 1035](https://www.rfc-editor.org/rfc/rfc1035). Targets are ordered by
+//! priority and weight, as described in [RFC 2782](https://www.rfc-editor.org/rfc/rfc2782).
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+use crate::shared::NETWORK_TIMEOUT;
+
+/// Resolver configuration consulted for the system's nameserver.
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Record type of a service location.
+const TYPE_SRV: u16 = 33;
+
+/// Record class of the Internet.
+const CLASS_IN: u16 = 1;
+
+/// Header flag set on responses.
+const FLAG_RESPONSE: u16 = 0x8000;
+
+/// Header flag set on responses that did not fit in a UDP datagram.
+const FLAG_TRUNCATED: u16 = 0x0200;
+
+/// Header flag asking the nameserver to resolve the query recursively.
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+/// Response code for a name that does not exist.
+const NAME_ERROR: u16 = 3;
+
+/// Most compression pointers followed in one name, which stops pointer loops.
+const MAX_POINTERS: usize = 16;
+
+/// A target of a service, from one SRV record.
+struct Record {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    host: String,
+}
+
+/// Look up the SRV records of `name`, returning their targets in the order to try them.
+///
+/// The query goes to `nameserver`, or to the first nameserver of the system.
+pub(crate) async fn lookup(
+    name: &str,
+    nameserver: Option<SocketAddr>,
+) -> Result<Vec<(String, u16)>> {
+    let nameserver = match nameserver {
+        Some(nameserver) => nameserver,
+        None => system_nameserver()?,
+    };
+    let id = fastrand::u16(..);
+    let query = encode_query(id, name)?;
+    let mut response = query_udp(nameserver, &query).await?;
+    if flags(&response)? & FLAG_TRUNCATED != 0 {
+        response = query_tcp(nameserver, &query).await?;
+    }
+    let records =
+        parse_response(id, &response).with_context(|| format!("bad answer for {name}"))?;
+    // A single record with the root as its target says that there is no such service.
+    ensure!(
+        !records.is_empty() && !records[0].host.is_empty(),
+        "no targets in the SRV records of {name}"
+    );
+    Ok(order(records)
+        .into_iter()
+        .map(|record| (record.host, record.port))
+        .collect())
+}
+
+/// Read the first nameserver from the system's resolver configuration.
+fn system_nameserver() -> Result<SocketAddr> {
+    let conf = std::fs::read_to_string(RESOLV_CONF)
+        .with_context(|| format!("could not read {RESOLV_CONF}"))?;
+    conf.lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .with_context(|| format!("no nameserver in {RESOLV_CONF}"))
+}
+
+/// Encode a recursive query for the SRV records of a name.
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend(id.to_be_bytes());
+    query.extend(FLAG_RECURSION_DESIRED.to_be_bytes());
+    query.extend([0, 1, 0, 0, 0, 0, 0, 0]); // one question, no other records
+    for label in name.trim_end_matches('.').split('.') {
+        ensure!(
+            !label.is_empty() && label.len() < 64,
+            "invalid domain name {name}"
+        );
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(TYPE_SRV.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Send a query in a UDP datagram and wait for the response.
+async fn query_udp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if nameserver.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(nameserver).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; 4096];
+    let len = timeout(NETWORK_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .with_context(|| format!("timed out waiting for nameserver {nameserver}"))??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Send a query over TCP, where messages are prefixed with their length.
+async fn query_tcp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(nameserver).await?;
+        // Queries are built from a single name, so they are far below the limit.
+        stream
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(query).await?;
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0; len.into()];
+        stream.read_exact(&mut buf).await?;
+        anyhow::Ok(buf)
+    };
+    timeout(NETWORK_TIMEOUT, exchange)
+        .await
+        .with_context(|| format!("timed out waiting for nameserver {nameserver}"))?
+}
+
+/// Read the flags from the header of a message.
+fn flags(message: &[u8]) -> Result<u16> {
+    Ok(u16::from_be_bytes(read_array(message, 2)?))
+}
+
+/// Parse the SRV records in the answer section of a response.
+fn parse_response(id: u16, response: &[u8]) -> Result<Vec<Record>> {
+    ensure!(
+        u16::from_be_bytes(read_array(response, 0)?) == id,
+        "mismatched query id"
+    );
+    let flags = flags(response)?;
+    ensure!(flags & FLAG_RESPONSE != 0, "message is not a response");
+    match flags & 0x000f {
+        0 => (),
+        NAME_ERROR => bail!("no such name"),
+        rcode => bail!("nameserver failed with code {rcode}"),
+    }
+    let questions = u16::from_be_bytes(read_array(response, 4)?);
+    let answers = u16::from_be_bytes(read_array(response, 6)?);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(response, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(response, pos)?.1;
+        let [kind, class] = [0, 2].map(|i| read_array(response, pos + i).map(u16::from_be_bytes));
+        let len = u16::from_be_bytes(read_array(response, pos + 8)?) as usize;
+        let data = pos + 10;
+        pos = data + len;
+        ensure!(pos <= response.len(), "record is cut off");
+        // Other records, such as the CNAME that led to the SRV records, are skipped.
+        if kind? != TYPE_SRV || class? != CLASS_IN {
+            continue;
+        }
+        let [priority, weight, port] =
+            [0, 2, 4].map(|i| read_array(response, data + i).map(u16::from_be_bytes));
+        records.push(Record {
+            priority: priority?,
+            weight: weight?,
+            port: port?,
+            host: read_name(response, data + 6)?.0,
+        });
+    }
+    Ok(records)
+}
+
+/// Read a possibly compressed name, returning it and the position after it.
+///
+/// The name has no trailing dot, so the root is empty.
+fn read_name(message: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(pos).context("name is cut off")?;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                pointers += 1;
+                ensure!(pointers <= MAX_POINTERS, "too many compression pointers");
+                let [high, low] = read_array(message, pos)?;
+                end.get_or_insert(pos + 2);
+                pos = usize::from(u16::from_be_bytes([high & 0x3f, low]));
+            }
+            len => {
+                let label = message
+                    .get(pos + 1..pos + 1 + usize::from(len))
+                    .context("name is cut off")?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + usize::from(len);
+            }
+        }
+    }
+    Ok((name, end.unwrap_or(pos + 1)))
+}
+
+/// Read a fixed number of bytes at a position of a message.
+fn read_array<const N: usize>(message: &[u8], pos: usize) -> Result<[u8; N]> {
+    let bytes = message.get(pos..pos + N).context("message is cut off")?;
+    Ok(bytes.try_into().unwrap())
+}
+
+/// Order records by priority, and randomly by weight among records of the same priority.
+fn order(mut records: Vec<Record>) -> Vec<Record> {
+    // Records without weight come first, so they keep a small chance to be picked first.
+    records.sort_by_key(|record| (record.priority, record.weight));
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let same = records
+            .iter()
+            .take_while(|r| r.priority == priority)
+            .count();
+        let total: u32 = records[..same].iter().map(|r| u32::from(r.weight)).sum();
+        let pick = fastrand::u32(0..=total);
+        let mut sum = 0;
+        let index = records[..same]
+            .iter()
+            .position(|r| {
+                sum += u32::from(r.weight);
+                sum >= pick
+            })
+            .unwrap_or(0);
+        ordered.push(records.remove(index));
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Build a response to the query with this id, with one SRV record per target.
+    fn response(id: u16, flags: u16, targets: &[(u16, u16, u16, &str)]) -> Vec<u8> {
+        let mut message = encode_query(id, "_svc._tcp.example.com").unwrap();
+        message[2..4].copy_from_slice(&(FLAG_RESPONSE | flags).to_be_bytes());
+        message[6..8].copy_from_slice(&(targets.len() as u16).to_be_bytes());
+        for &(priority, weight, port, host) in targets {
+            message.extend([0xc0, 12]); // the name of the question
+            message.extend(TYPE_SRV.to_be_bytes());
+            message.extend(CLASS_IN.to_be_bytes());
+            message.extend([0, 0, 0, 60]);
+            let mut data = [priority, weight, port].map(u16::to_be_bytes).concat();
+            for label in host.split('.').filter(|label| !label.is_empty()) {
+                data.push(label.len() as u8);
+                data.extend(label.as_bytes());
+            }
+            data.push(0);
+            message.extend((data.len() as u16).to_be_bytes());
+            message.extend(data);
+        }
+        message
+    }
+
+    #[test]
+    fn parse_records() {
+        let message = response(7, 0, &[(1, 5, 8080, "a.example.com"), (2, 0, 9090, "b")]);
+        let records = parse_response(7, &message).unwrap();
+        let targets: Vec<_> = records
+            .iter()
+            .map(|r| (r.priority, r.weight, r.port, r.host.as_str()))
+            .collect();
+        assert_eq!(targets, [(1, 5, 8080, "a.example.com"), (2, 0, 9090, "b")]);
+
+        let err = parse_response(8, &message).err().unwrap();
+        assert_eq!(err.to_string(), "mismatched query id");
+    }
+
+    #[test]
+    fn truncated_response() {
+        let message = response(7, 0, &[(1, 5, 8080, "a.example.com")]);
+        for len in [0, 11, 20, message.len() - 1] {
+            let err = parse_response(7, &message[..len]).err().unwrap();
+            assert!(err.to_string().contains("cut off"), "{len}: {err}");
+        }
+    }
+
+    #[test]
+    fn pointer_loop() {
+        let mut message = response(7, 0, &[]);
+        // Two pointers that lead to each other.
+        message.extend([0xc0, message.len() as u8 + 2, 0xc0, message.len() as u8]);
+        let start = message.len() - 4;
+        let err = read_name(&message, start).unwrap_err();
+        assert_eq!(err.to_string(), "too many compression pointers");
+
+        // A pointer that leads to a name is followed, and the name ends after it.
+        message.extend([0xc0, 12]);
+        let start = message.len() - 2;
+        let (name, end) = read_name(&message, start).unwrap();
+        assert_eq!(name, "_svc._tcp.example.com");
+        assert_eq!(end, message.len());
+    }
+
+    #[test]
+    fn name_error() {
+        let message = response(7, NAME_ERROR, &[]);
+        let err = parse_response(7, &message).err().unwrap();
+        assert_eq!(err.to_string(), "no such name");
+
+        let message = response(7, 2, &[]);
+        let err = parse_response(7, &message).err().unwrap();
+        assert_eq!(err.to_string(), "nameserver failed with code 2");
+    }
+
+    #[test]
+    fn order_by_priority_and_weight() {
+        let record = |priority, weight, port| Record {
+            priority,
+            weight,
+            port,
+            host: String::new(),
+        };
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let records = vec![record(2, 0, 1), record(1, 0, 2), record(1, 100, 3)];
+            let ports: Vec<_> = order(records).iter().map(|r| r.port).collect();
+            assert_eq!(ports[2], 1, "lower priority comes first");
+            if ports[0] == 3 {
+                heavy_first += 1;
+            }
+        }
+        // The record without weight only comes first when the pick is zero.
+        assert!(heavy_first > 900, "{heavy_first}");
+    }
+
+    #[tokio::test]
+    async fn truncated_answer_is_retried_over_tcp() -> Result<()> {
+        let udp = UdpSocket::bind("127.0.0.1:0").await?;
+        let nameserver = udp.local_addr()?;
+        let tcp = TcpListener::bind(nameserver).await?;
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (_, peer) = udp.recv_from(&mut buf).await?;
+            let id = u16::from_be_bytes([buf[0], buf[1]]);
+            udp.send_to(&response(id, FLAG_TRUNCATED, &[]), peer)
+                .await?;
+
+            let (mut stream, _) = tcp.accept().await?;
+            let len = stream.read_u16().await?;
+            let mut query = vec![0; len.into()];
+            stream.read_exact(&mut query).await?;
+            let id = u16::from_be_bytes([query[0], query[1]]);
+            let answer = response(id, 0, &[(1, 0, 8080, "a"), (0, 0, 9090, "b")]);
+            stream
+                .write_all(&(answer.len() as u16).to_be_bytes())
+                .await?;
+            stream.write_all(&answer).await?;
+            anyhow::Ok(())
+        });
+
+        let targets = lookup("_svc._tcp.example.com", Some(nameserver)).await?;
+        assert_eq!(targets, [("b".into(), 9090), ("a".into(), 8080)]);
+        Ok(())
+    }
+}
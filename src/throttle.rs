@@ -0,0 +1,126 @@
+//! Bandwidth limits on forwarded connections, shaped with a token bucket.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// A budget of bytes shared by the connections of one tunnel.
+///
+/// Tokens refill at a steady rate up to the burst size, and each byte transferred
+/// takes one. Transfers may go into debt, which is then paid off by waiting.
+pub(crate) struct Bandwidth {
+    /// Sustained rate, in bytes per second.
+    rate: f64,
+
+    /// Maximum number of tokens that can accumulate.
+    burst: f64,
+
+    /// Tokens available, and when they were last refilled.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bandwidth {
+    /// Create a full bucket with this rate in bytes per second, and burst in bytes.
+    pub fn new(bytes_per_sec: u64, burst: u64) -> Self {
+        Bandwidth {
+            rate: bytes_per_sec as f64,
+            burst: burst as f64,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Take tokens for bytes that were transferred, returning how long to wait to repay any debt.
+    fn consume(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, updated) = &mut *state;
+        let now = Instant::now();
+        let refill = now.duration_since(*updated).as_secs_f64() * self.rate;
+        *tokens = (*tokens + refill).min(self.burst) - bytes as f64;
+        *updated = now;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+/// A stream whose reads and writes both draw from a [`Bandwidth`] budget.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    bandwidth: Arc<Bandwidth>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// Wrap a stream, limiting it to the given bandwidth.
+    pub fn new(inner: S, bandwidth: Arc<Bandwidth>) -> Self {
+        Throttled {
+            inner,
+            bandwidth,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// Wait for a pending delay to finish, if there is one.
+fn poll_delay(delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = delay {
+        ready!(sleep.as_mut().poll(cx));
+        *delay = None;
+    }
+    Poll::Ready(())
+}
+
+/// Charge transferred bytes to the budget, delaying the next transfer if in debt.
+fn charge(bandwidth: &Bandwidth, delay: &mut Option<Pin<Box<Sleep>>>, bytes: usize) {
+    let wait = bandwidth.consume(bytes);
+    if !wait.is_zero() {
+        *delay = Some(Box::pin(sleep(wait)));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.read_delay, cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let bytes = buf.filled().len() - before;
+        charge(&this.bandwidth, &mut this.read_delay, bytes);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.write_delay, cx));
+        let bytes = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        charge(&this.bandwidth, &mut this.write_delay, bytes);
+        Poll::Ready(Ok(bytes))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,95 @@
+//! Delivery of server events to an HTTP webhook.
+
+use std::sync::Arc;
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::events::Event;
+use crate::shared::NETWORK_TIMEOUT;
+
+/// An HTTP endpoint that events are posted to, as JSON.
+pub(crate) struct Webhook {
+    /// Host and optional port of the endpoint, as written in the URL.
+    authority: String,
+
+    /// Path of the endpoint, including any query string.
+    path: String,
+}
+
+impl Webhook {
+    /// Parse a webhook from a URL of the form `http://host[:port][/path]`.
+    pub fn new(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("webhook URL {url:?} must start with http://");
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        ensure!(!authority.is_empty(), "webhook URL {url:?} has no host");
+        Ok(Webhook {
+            authority: authority.into(),
+            path: path.into(),
+        })
+    }
+
+    /// Post an event in the background, logging a warning if delivery fails.
+    ///
+    /// Each request is given [`NETWORK_TIMEOUT`] to complete, so a slow endpoint
+    /// never holds up the server.
+    pub fn send(self: &Arc<Self>, event: &Event) {
+        let body = match serde_json::to_string(event) {
+            Ok(body) => body,
+            Err(err) => return warn!(%err, "failed to serialize webhook event"),
+        };
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            match timeout(NETWORK_TIMEOUT, this.post(&body)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => warn!(%err, authority = %this.authority, "webhook delivery failed"),
+                Err(_) => warn!(authority = %this.authority, "webhook delivery timed out"),
+            }
+        });
+    }
+
+    async fn post(&self, body: &str) -> Result<()> {
+        let mut stream = TcpStream::connect(self.socket_addr())
+            .await
+            .context("could not connect")?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.authority,
+            body.len(),
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line).await?;
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(status) => bail!("endpoint responded with status {status}"),
+            None => bail!("invalid response from endpoint"),
+        }
+    }
+
+    /// Address to connect to, with the default HTTP port if the URL has none.
+    fn socket_addr(&self) -> String {
+        let has_port = match self.authority.rsplit_once(':') {
+            Some((host, port)) => {
+                port.parse::<u16>().is_ok() && (!host.starts_with('[') || host.ends_with(']'))
+            }
+            None => false,
+        };
+        if has_port {
+            self.authority.clone()
+        } else {
+            format!("{}:80", self.authority)
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use bore_cli::cidr::IpNet;
+
+#[test]
+fn parse_and_contains() {
+    let net: IpNet = "10.1.0.0/16".parse().unwrap();
+    assert!(net.contains("10.1.2.3".parse().unwrap()));
+    assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!net.contains("10.2.0.1".parse().unwrap()));
+    assert!(!net.contains("fd00::1".parse().unwrap()));
+
+    let net: IpNet = "fd00::/8".parse().unwrap();
+    assert!(net.contains("fd12::1".parse().unwrap()));
+    assert!(!net.contains("fe80::1".parse().unwrap()));
+
+    let any: IpNet = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains("192.0.2.1".parse().unwrap()));
+
+    let single: IpNet = "192.0.2.1".parse().unwrap();
+    assert_eq!(single.to_string(), "192.0.2.1/32");
+    assert!(!single.contains("192.0.2.2".parse().unwrap()));
+}
+
+#[test]
+fn parse_invalid() {
+    assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    assert!("10.0.0.0/".parse::<IpNet>().is_err());
+    assert!("not an address".parse::<IpNet>().is_err());
+}
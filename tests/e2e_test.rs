@@ -1,14 +1,27 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
-use bore_cli::{client::Client, server::Server, shared::CONTROL_PORT};
+use bore_cli::admin;
+use bore_cli::auth::Authenticator;
+use bore_cli::client::{Balance, Client, ClientError, ReconnectPolicy};
+use bore_cli::events::{Event, EventKind};
+use bore_cli::memory;
+use bore_cli::proxy_protocol::ProxyProtocol;
+use bore_cli::server::{AllocationStrategy, Keepalive, PortTakeover, Quota, Server, ServerError};
+use bore_cli::shared::{
+    ClientMessage, Delimited, ServerMessage, TunnelStatus, CONTROL_PORT, NETWORK_TIMEOUT,
+    PROTOCOL_VERSION,
+};
 use lazy_static::lazy_static;
 use rstest::*;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio::time;
+use uuid::Uuid;
 
 lazy_static! {
     /// Guard to make sure that tests are run serially, not concurrently.
@@ -21,63 +34,2829 @@ async fn spawn_server(secret: Option<&str>) {
     time::sleep(Duration::from_millis(50)).await;
 }
 
+/// Spawn a server configured by `configure`, giving it time to start like [`spawn_server`].
+async fn spawn_server_with(configure: impl FnOnce(&mut Server)) {
+    let mut server = Server::new(1024..=65535, None);
+    configure(&mut server);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+}
+
 /// Spawns a client with randomly assigned ports, returning the listener and remote address.
 async fn spawn_client(secret: Option<&str>) -> Result<(TcpListener, SocketAddr)> {
     let listener = TcpListener::bind("localhost:0").await?;
     let local_port = listener.local_addr()?.port();
-    let client = Client::new("localhost", local_port, "localhost", 0, secret).await?;
-    let remote_addr = ([127, 0, 0, 1], client.remote_port()).into();
+    let client = Client::new("localhost", local_port, "localhost", 0, secret).await?;
+    let remote_addr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+    Ok((listener, remote_addr))
+}
+
+#[rstest]
+#[tokio::test]
+async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(secret).await;
+    let (listener, addr) = spawn_client(secret).await?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = [0u8; 11];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world");
+
+        stream.write_all(b"I can send a message too!").await?;
+        anyhow::Ok(())
+    });
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello world").await?;
+
+    let mut buf = [0u8; 25];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"I can send a message too!");
+
+    // Ensure that the client end of the stream is closed now.
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    // Also ensure that additional connections do not produce any data.
+    let mut stream = TcpStream::connect(addr).await?;
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn half_close() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    // The local service reads the whole request before it replies.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await?;
+        assert_eq!(request, b"request");
+        stream.write_all(b"response").await?;
+        anyhow::Ok(())
+    });
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"request").await?;
+    stream.shutdown().await?;
+    let mut response = Vec::new();
+    time::timeout(Duration::from_secs(3), stream.read_to_end(&mut response)).await??;
+    assert_eq!(response, b"response");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_returns_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    assert_eq!(client.remote_port(), 0);
+    let port = client.connect().await?;
+    assert!(port >= 1024);
+    assert_eq!(client.remote_port(), port);
+
+    // The assigned port is reachable even before the client starts listening.
+    TcpStream::connect(("localhost", port)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn advertised_host() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_advertised_host("tunnel.example.com")).await;
+
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    assert_eq!(client.public_host(), "localhost");
+    client.connect().await?;
+    assert_eq!(client.public_host(), "tunnel.example.com");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn advertised_host_too_long() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_advertised_host("a".repeat(250));
+    let err = server.validate().unwrap_err();
+    assert_eq!(err.to_string(), "advertised host is too long");
+    assert!(server.listen().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_proxy() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let local = UdpSocket::bind("127.0.0.1:0").await?;
+    let local_port = local.local_addr()?.port();
+    let client = Client::new_udp("127.0.0.1", local_port, "localhost", 0, None).await?;
+    let remote_addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        loop {
+            let (len, addr) = local.recv_from(&mut buf).await?;
+            local.send_to(&buf[..len], addr).await?;
+        }
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(remote_addr).await?;
+    let mut buf = [0u8; 64];
+    for msg in [&b"hello"[..], b"udp world"] {
+        socket.send(msg).await?;
+        let len = time::timeout(Duration::from_secs(3), socket.recv(&mut buf)).await??;
+        assert_eq!(&buf[..len], msg);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn graceful_shutdown() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (tx, rx) = oneshot::channel();
+    let server = Server::new(1024..=65535, None).listen_with_shutdown(async {
+        rx.await.ok();
+    });
+    let server = tokio::spawn(server);
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (_listener, addr) = spawn_client(None).await?;
+    TcpStream::connect(addr).await?;
+
+    tx.send(()).unwrap();
+    time::timeout(Duration::from_secs(3), server).await???;
+
+    // Both the control port and the tunnel are closed after shutdown.
+    assert!(TcpStream::connect(("localhost", CONTROL_PORT))
+        .await
+        .is_err());
+    assert!(TcpStream::connect(addr).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_drains_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (tx, rx) = oneshot::channel();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_drain_timeout(Duration::from_secs(5));
+    let server = tokio::spawn(server.listen_with_shutdown(async {
+        rx.await.ok();
+    }));
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    // The connection keeps forwarding after shutdown, and holds the server up.
+    tx.send(()).unwrap();
+    time::sleep(Duration::from_millis(200)).await;
+    assert!(!server.is_finished());
+    assert!(TcpStream::connect(addr).await.is_err());
+    local.write_all(b"world").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"world");
+
+    // The server exits as soon as the connection is closed, before the drain timeout.
+    drop(local);
+    drop(stream);
+    time::timeout(Duration::from_secs(3), server).await???;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn drain_timeout_closes_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (tx, rx) = oneshot::channel();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_drain_timeout(Duration::from_millis(300));
+    let server = tokio::spawn(server.listen_with_shutdown(async {
+        rx.await.ok();
+    }));
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (_local, _) = listener.accept().await?;
+
+    // A connection that stays open is closed once the drain timeout elapses.
+    tx.send(()).unwrap();
+    time::timeout(Duration::from_secs(3), server).await???;
+    let mut buf = [0u8; 1];
+    let read = time::timeout(Duration::from_secs(3), stream.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn exit_when_idle() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_exit_when_idle(Duration::from_millis(200));
+    server.set_min_uptime(Duration::from_millis(400));
+    let server = tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The server stays up while it has a tunnel, and for its minimum uptime.
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.connect().await?;
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(!server.is_finished());
+
+    // Once the tunnel closes, the server exits after the idle period.
+    drop(client);
+    time::timeout(Duration::from_secs(3), server).await???;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_conns_per_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_max_conns_per_port(1)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    // The second connection is over the limit and is closed right away.
+    let mut stream2 = TcpStream::connect(addr).await?;
+    let result = time::timeout(Duration::from_secs(3), stream2.read(&mut buf)).await?;
+    assert!(matches!(result, Ok(0) | Err(_)));
+
+    // Once the first connection closes, new connections are accepted again.
+    drop(stream);
+    drop(local);
+    time::sleep(Duration::from_millis(100)).await;
+    let mut stream3 = TcpStream::connect(addr).await?;
+    stream3.write_all(b"again").await?;
+    let (mut local, _) = listener.accept().await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"again");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_rate_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_tunnel_rate_limit(1, 2)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let mut buf = [0u8; 5];
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"hello").await?;
+        let (mut local, _) = listener.accept().await?;
+        local.read_exact(&mut buf).await?;
+        streams.push((stream, local));
+    }
+
+    // The burst is used up, so the third connection is closed right away.
+    let mut stream3 = TcpStream::connect(addr).await?;
+    let result = time::timeout(Duration::from_secs(3), stream3.read(&mut buf)).await?;
+    assert!(matches!(result, Ok(0) | Err(_)));
+
+    // After a second, a token is available again.
+    time::sleep(Duration::from_millis(1100)).await;
+    let mut stream4 = TcpStream::connect(addr).await?;
+    stream4.write_all(b"again").await?;
+    let (mut local, _) = listener.accept().await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"again");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn accept_filter() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let refuse = Arc::new(AtomicBool::new(true));
+    let filter = Arc::clone(&refuse);
+    spawn_server_with(|server| {
+        server.set_accept_filter(move |_, peer| {
+            !(filter.load(Ordering::SeqCst) && peer.ip().is_loopback())
+        });
+    })
+    .await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    // The refused connection is closed without reaching the client.
+    let mut buf = [0u8; 5];
+    let mut stream = TcpStream::connect(addr).await?;
+    let result = time::timeout(Duration::from_secs(3), stream.read(&mut buf)).await?;
+    assert!(matches!(result, Ok(0) | Err(_)));
+    assert!(time::timeout(Duration::from_millis(200), listener.accept())
+        .await
+        .is_err());
+
+    refuse.store(false, Ordering::SeqCst);
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn one_shot() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_one_shot(true);
+    let addr: SocketAddr = ([127, 0, 0, 1], client.connect().await?).into();
+    let listen = tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    assert!(!listen.is_finished());
+
+    // Closing the connection ends the client, which releases the tunnel.
+    drop((stream, local));
+    time::timeout(Duration::from_secs(3), listen).await???;
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_bind_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_client_bind_tunnels(vec![[127, 0, 0, 1].into()])).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_bind_tunnels([127, 0, 0, 1].into());
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    // The tunnel listens on the requested address alone.
+    assert!(TcpStream::connect(("127.0.0.2", port)).await.is_err());
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    // Addresses that are not allowed fall back to every interface.
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_bind_tunnels([127, 0, 0, 2].into());
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+    TcpStream::connect(("127.0.0.3", port)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_retry() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let local_port = TcpListener::bind("localhost:0").await?.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_local_retry(5, Duration::from_millis(50));
+    let addr: SocketAddr = ([127, 0, 0, 1], client.connect().await?).into();
+    tokio::spawn(client.listen());
+
+    // The local service comes up while the connection is being retried.
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    time::sleep(Duration::from_millis(200)).await;
+    let listener = TcpListener::bind(("localhost", local_port)).await?;
+    let (mut local, _) = time::timeout(Duration::from_secs(3), listener.accept()).await??;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn pending_queue_depth() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_pending_queue_depth(1);
+        server.set_accept_timeout(Duration::from_millis(200));
+    })
+    .await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = control.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+
+    // The first connection is queued, and the second is closed because the queue is full.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let id = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    };
+    let mut stream2 = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut buf = [0u8; 5];
+    let result = time::timeout(Duration::from_secs(3), stream2.read(&mut buf)).await?;
+    assert!(matches!(result, Ok(0) | Err(_)));
+
+    // Queued connections expire after the accept timeout too, which frees their place.
+    let result = time::timeout(Duration::from_secs(3), stream.read(&mut buf)).await?;
+    assert!(matches!(result, Ok(0) | Err(_)));
+    let mut stream3 = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream3.write_all(b"again").await?;
+    let next = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    };
+    assert_ne!(next, id);
+    let mut accept = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    accept.send(ClientMessage::Accept(next)).await?;
+    let mut accept = accept.into_parts().io;
+    accept.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"again");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_pending_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_max_pending_connections(1)).await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = control.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+
+    // The first connection waits for the client, and the second is over the limit.
+    let _stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let id = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    };
+    let mut stream2 = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut buf = [0u8; 5];
+    let result = time::timeout(Duration::from_secs(3), stream2.read(&mut buf)).await?;
+    assert!(matches!(result, Ok(0) | Err(_)));
+
+    // Accepting the first connection frees its place for another.
+    let mut accept = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    accept.send(ClientMessage::Accept(id)).await?;
+    time::sleep(Duration::from_millis(50)).await;
+    let _stream3 = TcpStream::connect(("127.0.0.1", port)).await?;
+    let msg = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            msg => break msg,
+        }
+    };
+    assert!(matches!(msg, Some(ServerMessage::Connection(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn accept_expired_connection() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_accept_timeout(Duration::from_millis(200))).await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = control.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+    let _stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let id = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    };
+
+    // The connection is gone by the time it is accepted, and the server says so.
+    time::sleep(Duration::from_millis(500)).await;
+    let mut accept = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    accept.send(ClientMessage::AcceptWithReply(id)).await?;
+    let msg = accept.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Error(err)) if err == "connection expired"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn no_accept_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_accept_timeout(Duration::from_millis(200));
+        server.set_accept_timeout(None);
+    })
+    .await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = control.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let id = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    };
+
+    // Connections never expire, so a late accept still gets the connection.
+    time::sleep(Duration::from_millis(500)).await;
+    let mut accept = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    accept.send(ClientMessage::Accept(id)).await?;
+    let mut accept = accept.into_parts().io;
+    let mut buf = [0u8; 5];
+    accept.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn id_generator() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let next = AtomicU64::new(1);
+    spawn_server_with(|server| {
+        server
+            .set_id_generator(move || Uuid::from_u128(next.fetch_add(1, Ordering::Relaxed).into()))
+    })
+    .await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = control.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+    let mut ids = Vec::new();
+    let _streams = [
+        TcpStream::connect(("127.0.0.1", port)).await?,
+        TcpStream::connect(("127.0.0.1", port)).await?,
+    ];
+    while ids.len() < 2 {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => ids.push(id),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+    assert_eq!(ids, [Uuid::from_u128(1), Uuid::from_u128(2)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unavailable_banner() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_accept_timeout(Duration::from_millis(200));
+        server.set_unavailable_banner("tunnel unavailable\n");
+    })
+    .await;
+
+    let mut control = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    control.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = control.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+
+    // The client never accepts, so the connection gets the banner and is closed.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut buf = Vec::new();
+    time::timeout(Duration::from_secs(3), stream.read_to_end(&mut buf)).await??;
+    assert_eq!(buf, b"tunnel unavailable\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn health_endpoint() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let health_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let (tx, rx) = oneshot::channel();
+    let mut server = Server::new(1024..=65535, None);
+    server.set_health_addr(health_addr);
+    let server = tokio::spawn(server.listen_with_shutdown(async {
+        rx.await.ok();
+    }));
+    time::sleep(Duration::from_millis(50)).await;
+    let _client = spawn_client(None).await?;
+
+    let mut http = TcpStream::connect(health_addr).await?;
+    http.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    http.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Content-Type: application/json"));
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let health: serde_json::Value = serde_json::from_str(body)?;
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["tunnels"], 1);
+
+    // The endpoint stops answering once the server shuts down.
+    tx.send(()).unwrap();
+    server.await??;
+    assert!(TcpStream::connect(health_addr).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_endpoint() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let metrics_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    spawn_server_with(|server| server.set_metrics_addr(metrics_addr)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    let mut http = TcpStream::connect(metrics_addr).await?;
+    http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    http.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("\nbore_tunnels 1\n"));
+    assert!(response.contains("\nbore_max_tunnels 64512\n"));
+    assert!(response.contains("\nbore_ports_used 1\n"));
+    assert!(response.contains("\nbore_ports_total 64512\n"));
+    assert!(response.contains("\nbore_connections_total 1\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn inherited_control_listener() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let control_port = listener.local_addr()?.port();
+    spawn_server_with(|server| server.set_control_listener(listener)).await;
+
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_control_port(control_port);
+    client.connect().await?;
+    assert_ne!(client.remote_port(), 0);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_control_socket() -> Result<()> {
+    use tokio::net::UnixStream;
+
+    let path = std::env::temp_dir().join(format!("bore-test-{}.sock", std::process::id()));
+    spawn_server_with(|server| server.set_control_socket(&path)).await;
+
+    let mut control = Delimited::new(UnixStream::connect(&path).await?);
+    control.send(ClientMessage::Hello(0)).await?;
+    let port = match control.recv_timeout().await? {
+        Some(ServerMessage::Hello(port)) => port,
+        msg => return Err(anyhow!("unexpected message: {msg:?}")),
+    };
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let id = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => return Err(anyhow!("unexpected message: {msg:?}")),
+        }
+    };
+
+    let mut accept = Delimited::new(UnixStream::connect(&path).await?);
+    accept.send(ClientMessage::Accept(id)).await?;
+    let mut accept = accept.into_parts().io;
+    let mut buf = [0u8; 5];
+    accept.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_bind_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_bind_tunnels([127, 0, 0, 1].into());
+        server.add_bind_tunnel([127, 0, 0, 2].into());
+    })
+    .await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    for ip in [[127, 0, 0, 1], [127, 0, 0, 2]] {
+        let mut stream = TcpStream::connect(SocketAddr::from((ip, addr.port()))).await?;
+        stream.write_all(b"hello").await?;
+        let (mut local, _) = listener.accept().await?;
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_list() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_control_port(30600);
+    server.set_admin_secret("admin");
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, Some("secret"));
+    client.set_control_port(30600);
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    let tunnels = admin::list_tunnels("localhost", 30600, Some("secret"), "admin").await?;
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].port, port);
+    assert!(tunnels[0]
+        .client
+        .is_some_and(|addr| addr.ip().is_loopback()));
+
+    // The client secret does not grant admin commands.
+    let result = admin::list_tunnels("localhost", 30600, Some("secret"), "secret").await;
+    assert!(result.is_err());
+    let result = admin::list_tunnels("localhost", 30600, None, "admin").await;
+    assert!(result.is_err());
+
+    // Without an admin secret, admin commands are disabled.
+    spawn_server(None).await;
+    let err = admin::list_tunnels("localhost", CONTROL_PORT, None, "admin")
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("disabled"), "{err:#}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_label() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_control_port(30601);
+        server.set_admin_secret("admin");
+    })
+    .await;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_control_port(30601);
+    client.set_label("ci-runner-7\n\x1b[31mforged");
+    client.connect().await?;
+    tokio::spawn(client.listen());
+
+    // Control characters are dropped before the label is stored.
+    let tunnels = admin::list_tunnels("localhost", 30601, None, "admin").await?;
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].label.as_deref(), Some("ci-runner-7[31mforged"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn long_client_label() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_admin_secret("admin")).await;
+    for (label, expected) in [("a".repeat(300), 64), ("\u{1f980}".repeat(300), 61)] {
+        let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+        client.set_label(&label);
+        client.connect().await?;
+        let tunnels = admin::list_tunnels("localhost", CONTROL_PORT, None, "admin").await?;
+        assert_eq!(tunnels.len(), 1);
+        let kept = tunnels[0].label.as_deref().unwrap();
+        assert_eq!(kept.chars().count(), expected);
+        client.release().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_kill() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_admin_secret("admin")).await;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_reconnect(ReconnectPolicy {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        max_attempts: None,
+    });
+    let port = client.connect().await?;
+    let client = tokio::spawn(client.listen());
+
+    // The tunnel closes, but the control connection stays up, so it is not reopened.
+    assert!(admin::kill_tunnel("localhost", CONTROL_PORT, None, "admin", port).await?);
+    time::sleep(Duration::from_millis(200)).await;
+    assert!(!client.is_finished());
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    assert!(
+        admin::list_tunnels("localhost", CONTROL_PORT, None, "admin")
+            .await?
+            .is_empty()
+    );
+    assert!(!admin::kill_tunnel("localhost", CONTROL_PORT, None, "admin", port).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_kill_keeps_other_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_admin_secret("admin")).await;
+    let second = TcpListener::bind("localhost:0").await?;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.add_tunnel("localhost", second.local_addr()?.port(), 0);
+    client.connect().await?;
+    let remote_ports = client.remote_ports().to_vec();
+    tokio::spawn(client.listen());
+
+    assert!(admin::kill_tunnel("localhost", CONTROL_PORT, None, "admin", remote_ports[0]).await?);
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(TcpStream::connect(("127.0.0.1", remote_ports[0]))
+        .await
+        .is_err());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", remote_ports[1])).await?;
+    let (mut local, _) = second.accept().await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_options() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_nodelay(true);
+        server.set_keepalive(Keepalive {
+            idle: Duration::from_secs(60),
+            interval: Some(Duration::from_secs(10)),
+            count: Some(3),
+        });
+    })
+    .await;
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_nodelay(true);
+    client.set_keepalive(Keepalive {
+        idle: Duration::from_secs(60),
+        interval: None,
+        count: None,
+    });
+    let addr: SocketAddr = ([127, 0, 0, 1], client.connect().await?).into();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn bind_interface() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_bind_interface("bore-missing0");
+    let err = server.listen().await.unwrap_err();
+    assert!(err.to_string().contains("bore-missing0"));
+
+    spawn_server_with(|server| server.set_bind_interface("lo")).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reuse_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Two servers share the control port, each with its own range of tunnel ports.
+    for port_range in [30500..=30509, 30510..=30519] {
+        let mut server = Server::new(port_range, None);
+        server.set_control_port(30500);
+        server.set_reuse_port(true);
+        tokio::spawn(server.listen());
+    }
+    time::sleep(Duration::from_millis(50)).await;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_control_port(30500);
+    let port = client.connect().await?;
+    assert!((30500..=30519).contains(&port));
+
+    // Without the option, the port is taken.
+    let mut server = Server::new(30520..=30529, None);
+    server.set_control_port(30500);
+    assert!(server.listen().await.is_err());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reuse_port_keeps_tunnels_exclusive() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let mut server = Server::new(30530..=30531, None);
+    server.set_reuse_port(true);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let first = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    let second = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert_ne!(first.remote_port(), second.remote_port());
+    let result = Client::new("localhost", 5000, "localhost", 0, None).await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_takes_over_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let mut stale = Delimited::new(TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?);
+    stale.send(ClientMessage::Hello(port)).await?;
+    let msg: Option<ServerMessage> = stale.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Hello(p)) if p == port));
+
+    // The same host reconnects from another source port while the old connection lingers.
+    let mut control = Delimited::new(TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?);
+    control.send(ClientMessage::Hello(port)).await?;
+    let msg: Option<ServerMessage> = control.recv_timeout().await?;
+    assert!(
+        matches!(msg, Some(ServerMessage::Hello(p)) if p == port),
+        "{msg:?}"
+    );
+
+    // Connections on the port are now announced to the new control connection.
+    let _peer = TcpStream::connect(("127.0.0.1", port)).await?;
+    loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(_)) => break,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_takeover() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_port_takeover(PortTakeover::Reject);
+    tokio::spawn(server.listen());
+    let (connector, listener) = memory::channel();
+    spawn_server_with(|server| {
+        server.set_memory_listener(listener);
+        server.set_port_takeover(PortTakeover::Any);
+    })
+    .await;
+
+    // Even the same host cannot take the port back.
+    let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let mut first = Delimited::new(TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?);
+    first.send(ClientMessage::Hello(port)).await?;
+    let msg: Option<ServerMessage> = first.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Hello(p)) if p == port));
+    let mut second = Delimited::new(TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?);
+    second.send(ClientMessage::Hello(port)).await?;
+    let msg: Option<ServerMessage> = second.recv_timeout().await?;
+    assert!(
+        matches!(&msg, Some(ServerMessage::Error(e)) if e == "port already in use"),
+        "{msg:?}"
+    );
+
+    // Any connection takes over the port, even one without an address.
+    let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let mut first = Delimited::new(connector.connect()?);
+    first.send(ClientMessage::Hello(port)).await?;
+    let msg: Option<ServerMessage> = first.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Hello(p)) if p == port));
+    let mut second = Delimited::new(connector.connect()?);
+    second.send(ClientMessage::Hello(port)).await?;
+    let msg: Option<ServerMessage> = second.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Hello(p)) if p == port));
+    loop {
+        match first.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Error(message)) => {
+                assert_eq!(message, "port taken over by another connection");
+                break;
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_reconnects() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (tx, rx) = oneshot::channel();
+    let server = Server::new(1024..=65535, None).listen_with_shutdown(async {
+        rx.await.ok();
+    });
+    let server = tokio::spawn(server);
+    time::sleep(Duration::from_millis(50)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let mut client = Client::new("localhost", local_port, "localhost", port, None).await?;
+    client.set_reconnect(ReconnectPolicy {
+        initial_delay: Duration::from_millis(100),
+        ..Default::default()
+    });
+    let client = tokio::spawn(client.listen());
+
+    // Restart the server, and the client should claim the same port again.
+    tx.send(()).unwrap();
+    server.await??;
+    spawn_server(None).await;
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(!client.is_finished());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_targets() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let first = TcpListener::bind("localhost:0").await?;
+    let second = TcpListener::bind("localhost:0").await?;
+    let unreachable = TcpListener::bind("localhost:0").await?.local_addr()?.port();
+    let mut client = Client::configure(
+        "localhost",
+        first.local_addr()?.port(),
+        "localhost",
+        0,
+        None,
+    );
+    client.add_local_target("localhost", unreachable);
+    client.add_local_target("localhost", second.local_addr()?.port());
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    // Connections take turns, skipping over the target that cannot be reached.
+    for listener in [&first, &second] {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream.write_all(b"hello").await?;
+        let (mut local, _) = time::timeout(NETWORK_TIMEOUT, listener.accept()).await??;
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+
+    Ok(())
+}
+
+/// Connect to a tunnel, returning both ends once `listener` accepts the forwarded connection.
+async fn connect_through(port: u16, listener: &TcpListener) -> Result<(TcpStream, TcpStream)> {
+    let remote = TcpStream::connect(("127.0.0.1", port)).await?;
+    let (local, _) = time::timeout(NETWORK_TIMEOUT, listener.accept()).await??;
+    Ok((remote, local))
+}
+
+#[tokio::test]
+async fn least_connections() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let first = TcpListener::bind("127.0.0.1:0").await?;
+    // The second target refuses connections until it listens.
+    let second = TcpSocket::new_v4()?;
+    second.bind("127.0.0.1:0".parse()?)?;
+    let mut client = Client::configure(
+        "127.0.0.1",
+        first.local_addr()?.port(),
+        "localhost",
+        0,
+        None,
+    );
+    client.add_local_target("127.0.0.1", second.local_addr()?.port());
+    client.set_balance(Balance::LeastConnections);
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    // Each connection is kept open, so that it counts against its target.
+    let mut open = Vec::new();
+    // Both connections go to the first target while the second is unreachable.
+    open.push(connect_through(port, &first).await?);
+    open.push(connect_through(port, &first).await?);
+
+    // Then the second target is the idle one, even when round-robin would pick the first.
+    let second = second.listen(1024)?;
+    open.push(connect_through(port, &second).await?);
+    open.push(connect_through(port, &second).await?);
+
+    Ok(())
+}
+
+/// Answer a DNS query with SRV records for `127.0.0.1`, each a priority and a port.
+fn srv_answer(query: &[u8], records: &[(u16, u16)]) -> Vec<u8> {
+    let mut answer = query.to_vec();
+    answer[2..4].copy_from_slice(&0x8180u16.to_be_bytes()); // response, no error
+    answer[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+    for &(priority, port) in records {
+        answer.extend([0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 17]); // name at the question
+        answer.extend(priority.to_be_bytes());
+        answer.extend(10u16.to_be_bytes());
+        answer.extend(port.to_be_bytes());
+        answer.extend(b"\x03127\x010\x010\x011\x00");
+    }
+    answer
+}
+
+#[tokio::test]
+async fn local_srv() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let unreachable = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    let records = [(20, listener.local_addr()?.port()), (10, unreachable)];
+    let nameserver = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut client = Client::configure("localhost", 0, "localhost", 0, None);
+    client.set_local_srv("_echo._tcp.example.com");
+    client.set_srv_nameserver(nameserver.local_addr()?);
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((len, addr)) = nameserver.recv_from(&mut buf).await {
+            let answer = srv_answer(&buf[..len], &records);
+            let _ = nameserver.send_to(&answer, addr).await;
+        }
+    });
+
+    // The preferred target cannot be reached, so the connection goes to the other one.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = time::timeout(NETWORK_TIMEOUT, listener.accept()).await??;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_retries_startup() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_control_port(30400);
+    client.set_startup_retry(ReconnectPolicy {
+        initial_delay: Duration::from_millis(100),
+        ..Default::default()
+    });
+    let client = tokio::spawn(async move { client.connect().await });
+
+    // The server comes up after the client's first attempts have failed.
+    time::sleep(Duration::from_millis(300)).await;
+    assert!(!client.is_finished());
+    let mut server = Server::new(1024..=65535, None);
+    server.set_control_port(30400);
+    tokio::spawn(server.listen());
+    let port = time::timeout(Duration::from_secs(2), client).await???;
+    assert_ne!(port, 0);
+
+    // Without retries, an unreachable server is an error right away.
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_control_port(30401);
+    client.set_connect_timeout(Duration::from_millis(500));
+    assert!(client.connect().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn heartbeat_interval() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_heartbeat_interval(Duration::from_millis(400))).await;
+
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    stream.send(ClientMessage::Hello(0)).await?;
+    let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Hello(_))));
+
+    let start = time::Instant::now();
+    for _ in 0..3 {
+        let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+        assert!(matches!(msg, Some(ServerMessage::Heartbeat)));
+    }
+    // The first heartbeat is immediate, and the next two follow at the interval.
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(750),
+        "heartbeats too fast: {elapsed:?}"
+    );
+    assert!(
+        elapsed < Duration::from_millis(1500),
+        "heartbeats too slow: {elapsed:?}"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reservation_token() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_reservation_grace(Duration::from_secs(5))).await;
+
+    async fn reserve(port: u16) -> Result<(Delimited<TcpStream>, Option<ServerMessage>)> {
+        let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+        stream.send(ClientMessage::Version(3)).await?;
+        let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+        assert!(matches!(msg, Some(ServerMessage::Version(3))));
+        stream.send(ClientMessage::Reserve("token".into())).await?;
+        stream.send(ClientMessage::Hello(port)).await?;
+        let reply = stream.recv_timeout().await?;
+        Ok((stream, reply))
+    }
+
+    let (control, reply) = reserve(0).await?;
+    let Some(ServerMessage::Hello(port)) = reply else {
+        panic!("expected hello");
+    };
+
+    // While the client is away, the listener stays open for connections.
+    drop(control);
+    time::sleep(Duration::from_millis(100)).await;
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+
+    // Reconnecting with the token takes back the port, and the waiting connection.
+    let (mut control, reply) = reserve(port).await?;
+    assert!(matches!(reply, Some(ServerMessage::Hello(p)) if p == port));
+    let id = loop {
+        match control.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Connection(id)) => break id,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    };
+    let mut accept = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    accept.send(ClientMessage::Accept(id)).await?;
+    let mut accept = accept.into_parts().io;
+    let mut buf = [0u8; 5];
+    accept.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reservation_without_hello() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (connector, listener) = memory::channel();
+    let (tx, mut events) = mpsc::unbounded_channel();
+    spawn_server_with(|server| {
+        server.set_memory_listener(listener);
+        server.set_reservation_grace(Duration::from_secs(5));
+        server.set_event_sink(tx);
+    })
+    .await;
+
+    // The client leaves before the server can send its hello.
+    let connector = &connector;
+    let reserve_and_leave = |port| async move {
+        let mut stream = Delimited::new(connector.connect()?);
+        stream.send(ClientMessage::Version(3)).await?;
+        let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+        assert!(matches!(msg, Some(ServerMessage::Version(3))));
+        stream.send(ClientMessage::Reserve("token".into())).await?;
+        stream.send(ClientMessage::Hello(port)).await?;
+        anyhow::Ok(())
+    };
+
+    // A port chosen by the server is closed, as the client cannot ask for it again.
+    reserve_and_leave(0).await?;
+    let event = events.recv().await.unwrap();
+    let EventKind::ListenerCreated { port, .. } = event.kind else {
+        panic!("expected listener_created, got {event:?}");
+    };
+    let event = time::timeout(NETWORK_TIMEOUT, events.recv())
+        .await?
+        .unwrap();
+    assert!(
+        matches!(event.kind, EventKind::ListenerAborted { port: aborted, .. } if aborted == port),
+        "{event:?}"
+    );
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+    // A port that the client asked for is held for its token.
+    let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+    reserve_and_leave(port).await?;
+    time::sleep(Duration::from_millis(100)).await;
+    TcpStream::connect(("127.0.0.1", port)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tunnel_status() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_heartbeat_interval(Duration::from_millis(50))).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    let mut status = client.watch_status();
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    let expected = TunnelStatus {
+        port,
+        connections: 1,
+        bytes: 0,
+    };
+    time::timeout(
+        Duration::from_secs(1),
+        status.wait_for(|s| *s == [expected.clone()]),
+    )
+    .await??;
+
+    // Bytes are counted once the connection closes.
+    drop((stream, local));
+    let expected = TunnelStatus {
+        port,
+        connections: 0,
+        bytes: 5,
+    };
+    time::timeout(
+        Duration::from_secs(1),
+        status.wait_for(|s| *s == [expected.clone()]),
+    )
+    .await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_forward_keeps_control() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_heartbeat_interval(Duration::from_millis(50));
+        server.set_connection_idle_timeout(Duration::from_millis(200));
+    })
+    .await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    let mut status = client.watch_status();
+    let port = client.connect().await?;
+    let client = tokio::spawn(client.listen());
+
+    // The idle timeout fails this forward, and its bytes are still counted.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    let expected = TunnelStatus {
+        port,
+        connections: 0,
+        bytes: 5,
+    };
+    time::timeout(
+        Duration::from_secs(2),
+        status.wait_for(|s| *s == [expected.clone()]),
+    )
+    .await??;
+
+    // The control connection is still up, so the tunnel keeps forwarding.
+    assert!(!client.is_finished());
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"again").await?;
+    let (mut local, _) = listener.accept().await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"again");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeated_hello_reports_one_status() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server_with(|server| server.set_heartbeat_interval(Duration::from_millis(300))).await;
+
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    stream
+        .send(ClientMessage::Version(PROTOCOL_VERSION))
+        .await?;
+    let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Version(_))));
+    stream.send(ClientMessage::ReportStatus).await?;
+    stream.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = stream.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+
+    // Asking for the same port again replaces the tunnel, rather than adding another.
+    stream.send(ClientMessage::Hello(port)).await?;
+    loop {
+        match stream.recv_timeout().await? {
+            Some(ServerMessage::Hello(p)) if p == port => break,
+            Some(ServerMessage::Status(_) | ServerMessage::Error(_)) => continue,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+    loop {
+        match stream.recv_timeout().await? {
+            Some(ServerMessage::Status(status)) if status.port == port => break,
+            Some(ServerMessage::Error(_)) => continue,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+    let next = time::timeout(Duration::from_millis(100), async {
+        loop {
+            match stream.recv::<ServerMessage>().await {
+                Ok(Some(ServerMessage::Error(_))) => continue,
+                msg => return msg,
+            }
+        }
+    })
+    .await;
+    assert!(next.is_err(), "unexpected message {next:?}");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn listener_exiting_at_once_leaves_no_owner() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    let mut server = Server::new(1024..=65535, None);
+    server.set_max_tunnel_lifetime(Duration::ZERO);
+    let stats = server.stats_handle();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Each listener exits as soon as it starts, maybe before it is recorded as the owner.
+    for _ in 0..200 {
+        let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+        stream.send(ClientMessage::Hello(0)).await?;
+        let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+        assert!(matches!(msg, Some(ServerMessage::Hello(_))), "{msg:?}");
+    }
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(stats.stats().listeners, []);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn report_peers() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    stream.send(ClientMessage::Version(1)).await?;
+    let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Version(1))));
+    stream.send(ClientMessage::ReportPeers).await?;
+    stream.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = stream.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+
+    let peer = TcpStream::connect(("127.0.0.1", port)).await?;
+    loop {
+        match stream.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::PeerConnection {
+                port: p, remote, ..
+            }) => {
+                assert_eq!(p, port);
+                assert_eq!(remote, peer.local_addr()?);
+                break;
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn version_negotiation() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    stream.send(ClientMessage::Version(u32::MAX)).await?;
+    let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+    assert!(matches!(
+        msg,
+        Some(ServerMessage::Version(PROTOCOL_VERSION))
+    ));
+
+    // Without a version, clients speak version 0 and cannot ask for peer addresses.
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    stream.send(ClientMessage::ReportPeers).await?;
+    stream.send(ClientMessage::Hello(0)).await?;
+    let msg: Option<ServerMessage> = stream.recv_timeout().await?;
+    assert!(msg.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn version_fallback() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A server from before version negotiation, which hangs up on unknown messages.
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    tokio::spawn(async move {
+        let (stream, _) = control.accept().await?;
+        let mut stream = Delimited::new(stream);
+        let msg: Option<ClientMessage> = stream.recv().await?;
+        assert!(matches!(msg, Some(ClientMessage::Version(_))));
+        drop(stream);
+
+        let (stream, _) = control.accept().await?;
+        let mut stream = Delimited::new(stream);
+        let msg: Option<ClientMessage> = stream.recv().await?;
+        assert!(matches!(msg, Some(ClientMessage::Hello(0))));
+        stream.send(ServerMessage::Hello(5000)).await?;
+        time::sleep(Duration::from_secs(1)).await;
+        anyhow::Ok(())
+    });
+
+    let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert_eq!(client.remote_port(), 5000);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn missed_heartbeat() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // A server that assigns a port, then never sends a heartbeat.
+    let control = TcpListener::bind(("0.0.0.0", CONTROL_PORT)).await?;
+    tokio::spawn(async move {
+        let (stream, _) = control.accept().await?;
+        let mut stream = Delimited::new(stream);
+        let _: Option<ClientMessage> = stream.recv().await?;
+        stream
+            .send(ServerMessage::Version(PROTOCOL_VERSION))
+            .await?;
+        let _: Option<ClientMessage> = stream.recv().await?;
+        stream.send(ServerMessage::Hello(5000)).await?;
+        time::sleep(Duration::from_secs(10)).await;
+        anyhow::Ok(())
+    });
+
+    let mut client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    client.set_heartbeat_timeout(Duration::from_millis(200));
+    let result = time::timeout(Duration::from_secs(3), client.listen()).await?;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_probe_exhausted() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let taken = TcpListener::bind("0.0.0.0:0").await?;
+    let port = taken.local_addr()?.port();
+    let mut server = Server::new(port..=port, None);
+    server.set_port_probe_attempts(5);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let result = Client::new("localhost", 5000, "localhost", 0, None).await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sequential_allocation() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let taken = TcpListener::bind("0.0.0.0:30000").await?;
+    let mut server = Server::new(30000..=30010, None);
+    server.set_allocation_strategy(AllocationStrategy::Sequential);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let first = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert_eq!(first.remote_port(), 30001);
+    let second = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert_eq!(second.remote_port(), 30002);
+
+    drop(taken);
+    Ok(())
+}
+
+#[tokio::test]
+async fn preferred_port_range() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(30000..=30010, None);
+    server.set_preferred_port_range(30008..=30009);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        clients.push(Client::new("localhost", 5000, "localhost", 0, None).await?);
+    }
+    let mut ports: Vec<_> = clients.iter().map(Client::remote_port).collect();
+    ports.sort_unstable();
+    assert_eq!(ports, [30008, 30009]);
+
+    // Once the preferred ports are taken, the rest of the range is used.
+    let third = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    assert!((30000..=30010).contains(&third.remote_port()));
+    assert!(!ports.contains(&third.remote_port()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn control_rate_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_control_rate_limit(1, 2)).await;
+
+    let _first = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    let _second = TcpStream::connect(("localhost", CONTROL_PORT)).await?;
+    let mut third = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    let msg: Option<ServerMessage> = third.recv_timeout().await?;
+    assert!(matches!(msg, Some(ServerMessage::Error(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ip_denylist() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let denylist = vec!["127.0.0.0/8".parse()?];
+    spawn_server_with(|server| server.set_ip_denylist(denylist)).await;
+
+    let result = Client::new("localhost", 5000, "127.0.0.1", 0, None).await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn per_secret_port_range() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("admin"));
+    server.add_secret("tenant", 30100..=30100);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client = Client::new("localhost", 5000, "localhost", 0, Some("tenant")).await?;
+    assert_eq!(client.remote_port(), 30100);
+    let result = Client::new("localhost", 5000, "localhost", 30101, Some("tenant")).await;
+    assert!(result.is_err());
+    let client = Client::new("localhost", 5000, "localhost", 30101, Some("admin")).await?;
+    assert_eq!(client.remote_port(), 30101);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let first = TcpListener::bind("localhost:0").await?;
+    let second = TcpListener::bind("localhost:0").await?;
+    let mut client = Client::configure(
+        "localhost",
+        first.local_addr()?.port(),
+        "localhost",
+        0,
+        None,
+    );
+    client.add_tunnel("localhost", second.local_addr()?.port(), 0);
+    client.connect().await?;
+    let remote_ports = client.remote_ports().to_vec();
+    assert_eq!(remote_ports.len(), 2);
+    tokio::spawn(client.listen());
+
+    for (listener, remote_port) in [first, second].into_iter().zip(remote_ports) {
+        let mut stream = TcpStream::connect(("localhost", remote_port)).await?;
+        let (mut local, _) = listener.accept().await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        local.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn later_tunnel_refused() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server_with(|server| server.set_max_tunnels(1)).await;
+
+    let first = TcpListener::bind("localhost:0").await?;
+    let mut client = Client::configure(
+        "localhost",
+        first.local_addr()?.port(),
+        "localhost",
+        0,
+        None,
+    );
+    client.add_tunnel("localhost", 1, 0);
+    client.connect().await?;
+    let remote_ports = client.remote_ports().to_vec();
+    assert_eq!(remote_ports.len(), 2);
+    assert_ne!(remote_ports[0], 0);
+    assert_eq!(remote_ports[1], 0);
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("localhost", remote_ports[0])).await?;
+    let (mut local, _) = first.accept().await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn event_sink() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (tx, mut events) = mpsc::unbounded_channel();
+    spawn_server_with(|server| server.set_event_sink(tx)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let event = events.recv().await.unwrap();
+    let json = serde_json::to_value(&event)?;
+    assert_eq!(json["event"], "listener_created");
+    assert_eq!(json["port"], addr.port());
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+    let event = events.recv().await.unwrap();
+    let EventKind::ConnectionAccepted { id, .. } = event.kind else {
+        panic!("expected connection_accepted, got {event:?}");
+    };
+    let event = events.recv().await.unwrap();
+    assert!(
+        matches!(event.kind, EventKind::ConnectionForwarded { id: forwarded } if forwarded == id)
+    );
+
+    stream.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    local.write_all(b"hi").await?;
+    drop(local);
+    stream.read_to_end(&mut Vec::new()).await?;
+    drop(stream);
+    let event = events.recv().await.unwrap();
+    let EventKind::ConnectionClosed {
+        id: closed,
+        bytes_to_peer,
+        bytes_from_peer,
+    } = event.kind
+    else {
+        panic!("expected connection_closed, got {event:?}");
+    };
+    assert_eq!((closed, bytes_to_peer, bytes_from_peer), (id, 2, 5));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn event_subscribers() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(1024..=65535, None);
+    let mut first = server.subscribe();
+    // A subscriber that never receives does not hold up the others.
+    let _second = server.subscribe();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (_listener, addr) = spawn_client(None).await?;
+    let event = first.recv().await?;
+    assert!(
+        matches!(event.kind, EventKind::ListenerCreated { port, udp: false, .. } if port == addr.port())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn named_http_tunnel() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_http_port(30200)).await;
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_name("myapp");
+    assert_eq!(client.connect().await?, 30200);
+    tokio::spawn(client.listen());
+
+    let request = b"GET / HTTP/1.1\r\nHost: MyApp.localhost:30200\r\n\r\n";
+    let mut stream = TcpStream::connect("127.0.0.1:30200").await?;
+    stream.write_all(request).await?;
+    let (mut local, _) = time::timeout(NETWORK_TIMEOUT, listener.accept()).await??;
+    let mut buf = [0u8; 47];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, request);
+
+    let mut stream = TcpStream::connect("127.0.0.1:30200").await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: other.localhost\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn webhook() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let endpoint = TcpListener::bind("127.0.0.1:0").await?;
+    let mut server = Server::new(1024..=65535, None);
+    assert!(server.set_webhook("https://example.com/hook").is_err());
+    server.set_webhook(&format!("http://{}/hook", endpoint.local_addr()?))?;
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let (_listener, addr) = spawn_client(None).await?;
+
+    let (mut stream, _) = time::timeout(NETWORK_TIMEOUT, endpoint.accept()).await??;
+    let mut request = Vec::new();
+    while !request.ends_with(b"}") {
+        time::timeout(NETWORK_TIMEOUT, stream.read_buf(&mut request)).await??;
+    }
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+    let request = String::from_utf8(request)?;
+    assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+    let body = request.split("\r\n\r\n").nth(1).unwrap();
+    let event: Event = serde_json::from_str(body)?;
+    assert!(matches!(event.kind, EventKind::ListenerCreated { port, .. } if port == addr.port()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_idle_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_connection_idle_timeout(Duration::from_millis(300)))
+        .await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    let start = time::Instant::now();
+    let n = time::timeout(Duration::from_secs(3), stream.read(&mut buf)).await??;
+    assert_eq!(n, 0);
+    assert!(start.elapsed() >= Duration::from_millis(250));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_forward_closes_connection() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let (tx, mut events) = mpsc::unbounded_channel();
+    spawn_server_with(|server| {
+        server.set_connection_idle_timeout(Duration::from_millis(200));
+        server.set_event_sink(tx);
+    })
+    .await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    local.write_all(b"hi").await?;
+    stream.read_exact(&mut buf[..2]).await?;
+
+    // The idle timeout fails the forward, which still reports what was copied.
+    let closed = time::timeout(Duration::from_secs(3), async {
+        loop {
+            let event = events.recv().await.unwrap();
+            if let EventKind::ConnectionClosed {
+                bytes_to_peer,
+                bytes_from_peer,
+                ..
+            } = event.kind
+            {
+                return (bytes_to_peer, bytes_from_peer);
+            }
+        }
+    })
+    .await?;
+    assert_eq!(closed, (2, 5));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bandwidth_limit() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_bandwidth_limit(100_000, 10_000)).await;
+    let (listener, addr) = spawn_client(None).await?;
+
+    let start = time::Instant::now();
+    let mut stream = TcpStream::connect(addr).await?;
+    let data = vec![42u8; 60_000];
+    tokio::spawn(async move { stream.write_all(&data).await.map(|_| stream) });
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = vec![0u8; 60_000];
+    time::timeout(Duration::from_secs(3), local.read_exact(&mut buf)).await??;
+    assert!(buf.iter().all(|&b| b == 42));
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn idle_tunnel_timeout() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_idle_tunnel_timeout(Duration::from_millis(200))).await;
+
+    let mut stream = Delimited::new(TcpStream::connect(("localhost", CONTROL_PORT)).await?);
+    stream.send(ClientMessage::Hello(0)).await?;
+    let Some(ServerMessage::Hello(port)) = stream.recv_timeout().await? else {
+        panic!("expected hello");
+    };
+    loop {
+        match stream.recv_timeout().await? {
+            Some(ServerMessage::Heartbeat) => continue,
+            Some(ServerMessage::Error(message)) => {
+                assert_eq!(message, "idle timeout");
+                break;
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+    // The port is free again.
+    TcpListener::bind(("0.0.0.0", port)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_tunnel_lifetime() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_max_tunnel_lifetime(Duration::from_millis(300))).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    let (mut local, _) = listener.accept().await?;
+
+    // The tunnel closes at its lifetime, but the forwarded connection lives on.
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+    stream.write_all(b"still open").await?;
+    let mut buf = [0; 10];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"still open");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_protocol_header() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_proxy_protocol(ProxyProtocol::V1)).await;
+
+    let (listener, addr) = spawn_client(None).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    let peer = stream.local_addr()?;
+    stream.write_all(b"hello").await?;
+
+    let (mut local, _) = listener.accept().await?;
+    let expected = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+        peer.port(),
+        addr.port()
+    );
+    let mut buf = vec![0u8; expected.len()];
+    time::timeout(Duration::from_secs(3), local.read_exact(&mut buf)).await??;
+    assert_eq!(String::from_utf8(buf)?, expected);
+
+    Ok(())
+}
+
+#[rstest]
+#[case(None, Some("my secret"))]
+#[case(Some("my secret"), None)]
+#[tokio::test]
+async fn mismatched_secret(
+    #[case] server_secret: Option<&str>,
+    #[case] client_secret: Option<&str>,
+) {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(server_secret).await;
+    assert!(spawn_client(client_secret).await.is_err());
+}
+
+#[tokio::test]
+async fn client_error_kinds() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    tokio::spawn(Server::new(2000..=3000, Some("secret")).listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let error = |result: Result<Client>| result.err().unwrap();
+
+    let err = error(Client::new("localhost", 5000, "localhost", 0, None).await);
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ClientError::AuthRequired)
+    ));
+    let err = error(Client::new("localhost", 5000, "localhost", 0, Some("wrong")).await);
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ClientError::AuthRejected)
+    ));
+    assert_eq!(err.to_string(), "authentication failed: check your secret");
+    let err = error(Client::new("localhost", 5000, "localhost", 4000, Some("secret")).await);
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ClientError::PortNotInRange)
+    ));
+
+    // A server without a secret never challenges the client, which looks like a slow one.
+    let mut server = Server::new(2000..=3000, None);
+    server.set_control_port(30300);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, Some("secret"));
+    client.set_control_port(30300);
+    let err = client.connect().await.err().unwrap();
+    assert!(matches!(err.downcast_ref(), Some(ClientError::AuthTimeout)));
+
+    // Another message in place of the challenge shows that no secret is needed.
+    let listener = TcpListener::bind("localhost:30301").await?;
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = Delimited::new(stream);
+        stream.send(ServerMessage::Heartbeat).await?;
+        time::sleep(Duration::from_secs(1)).await;
+        anyhow::Ok(())
+    });
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, Some("secret"));
+    client.set_control_port(30301);
+    let err = client.connect().await.err().unwrap();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ClientError::AuthNotRequired)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_service_restart() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let local_addr = TcpListener::bind("localhost:0").await?.local_addr()?;
+    let client = Client::new("localhost", local_addr.port(), "localhost", 0, None).await?;
+    let addr: SocketAddr = ([127, 0, 0, 1], client.remote_port()).into();
+    tokio::spawn(client.listen());
+
+    // The local service is down, so the connection is closed but the tunnel stays up.
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut buf = [0u8; 5];
+    let n = time::timeout(Duration::from_secs(3), stream.read(&mut buf)).await??;
+    assert_eq!(n, 0);
+
+    let listener = TcpListener::bind(local_addr).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_stats() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let server = Server::new(1024..=65535, None);
+    assert_eq!(server.stats().uptime, Duration::ZERO);
+    let stats = server.stats_handle();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (_listener, addr) = spawn_client(None).await?;
+    let _stream = TcpStream::connect(addr).await?;
+    time::sleep(Duration::from_millis(50)).await;
+
+    let snapshot = stats.stats();
+    let ports: Vec<_> = snapshot.listeners.iter().map(|(port, _)| *port).collect();
+    assert_eq!(ports, [addr.port()]);
+    assert!(snapshot.uptime >= Duration::from_millis(50));
+
+    // Listeners are removed once their client disconnects.
+    let client = Client::new("localhost", 0, "localhost", 0, None).await?;
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(stats.stats().listeners.len(), 2);
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(stats.stats().listeners.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn port_utilization() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(30650..=30653, None);
+    server.set_port_utilization_warning(0.5);
+    assert_eq!(server.port_utilization(), (0, 4));
+    let stats = server.stats_handle();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let _first = Client::new("localhost", 0, "localhost", 30650, None).await?;
+    let _second = Client::new("localhost", 0, "localhost", 0, None).await?;
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(stats.port_utilization(), (2, 4));
+    assert_eq!(stats.stats().port_utilization, (2, 4));
+
+    // A UDP tunnel from another address shares the port of the first one.
+    let mut udp = Client::configure("127.0.0.1", 0, "127.0.0.1", 30650, None);
+    udp.set_udp(true);
+    udp.set_local_bind_addr(([127, 0, 0, 2], 0).into());
+    udp.connect().await?;
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(stats.stats().listeners.len(), 3);
+    assert_eq!(stats.port_utilization(), (2, 4));
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_port_utilization_warning(1.5);
+    let err = server.validate().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "port utilization warning must be above 0 and at most 1"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_target() -> Result<()> {
+    use tokio::net::UnixListener;
+
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    let path = std::env::temp_dir().join(format!("bore-target-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let mut client = Client::configure("localhost", 0, "localhost", 0, None);
+    client.set_local_socket(&path);
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+    local.write_all(b"world").await?;
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"world");
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn forward_stream() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // Each connection reaches an in-memory echo service.
+    let mut client = Client::configure("localhost", 0, "localhost", 0, None);
+    client.forward_stream(|| async {
+        let (stream, mut service) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(&mut service);
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        Ok(stream)
+    });
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_transport() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // The client reaches the server without a control port, and an echo service
+    // without a local port, so only the public side of the tunnel is a socket.
+    let (connector, listener) = memory::channel();
+    let mut server = Server::new(1024..=65535, Some("secret"));
+    server.set_memory_listener(listener);
+    tokio::spawn(server.listen());
+    let mut client = Client::configure("localhost", 0, "unreachable.invalid", 0, Some("secret"));
+    client.set_memory_connector(connector);
+    client.forward_stream(|| async {
+        let (stream, mut service) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(&mut service);
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        Ok(stream)
+    });
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socks_target() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let mut client = Client::configure("localhost", 0, "localhost", 0, None);
+    client.set_socks(true);
+    let addr: SocketAddr = ([127, 0, 0, 1], client.connect().await?).into();
+    tokio::spawn(client.listen());
+
+    // Ask for a connection to a local address, returning the reply code.
+    async fn request(addr: SocketAddr, target: SocketAddr) -> Result<(TcpStream, u8)> {
+        let SocketAddr::V4(target) = target else {
+            unreachable!("listeners are bound to IPv4");
+        };
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&[5, 1, 0]).await?;
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await?;
+        assert_eq!(method, [5, 0]);
+        let mut message = vec![5, 1, 0, 1];
+        message.extend(target.ip().octets());
+        message.extend(target.port().to_be_bytes());
+        stream.write_all(&message).await?;
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await?;
+        Ok((stream, reply[1]))
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let (mut stream, code) = request(addr, listener.local_addr()?).await?;
+    assert_eq!(code, 0);
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let (_, code) = request(addr, closed).await?;
+    assert_eq!(code, 5, "expected connection refused");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn custom_control_port() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_control_port(30100)).await;
+    assert!(TcpStream::connect(("localhost", CONTROL_PORT))
+        .await
+        .is_err());
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_control_port(30100);
+    let addr: SocketAddr = ([127, 0, 0, 1], client.connect().await?).into();
     tokio::spawn(client.listen());
-    Ok((listener, remote_addr))
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
 }
 
-#[rstest]
 #[tokio::test]
-async fn basic_proxy(#[values(None, Some(""), Some("abc"))] secret: Option<&str>) -> Result<()> {
+async fn secrets_file_reload() -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
 
-    spawn_server(secret).await;
-    let (listener, addr) = spawn_client(secret).await?;
+    let path = std::env::temp_dir().join(format!("bore-secrets-{}", std::process::id()));
+    std::fs::write(&path, "1024-65535 first\n")?;
+    let mut server = Server::new(1024..=65535, None);
+    server.set_secrets_file(&path)?;
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let first = Client::new("localhost", 0, "localhost", 0, Some("first")).await?;
+    // Timestamps may be coarse, so also change the size of the file.
+    std::fs::write(&path, "1024-65535 second-secret\n")?;
+    time::sleep(Duration::from_millis(1500)).await;
+
+    let err = Client::new("localhost", 0, "localhost", 0, Some("first"))
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ClientError::AuthRejected)
+    ));
+    Client::new("localhost", 0, "localhost", 0, Some("second-secret")).await?;
+
+    // Tunnels of clients that authenticated before the reload stay up.
+    TcpStream::connect(("localhost", first.remote_port())).await?;
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn authentications_by_secret() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let metrics_addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let mut server = Server::new(1024..=65535, Some("main"));
+    server.add_secret("team", 1024..=65535);
+    server.set_metrics_addr(metrics_addr);
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let _main = Client::new("localhost", 0, "localhost", 0, Some("main")).await?;
+    let _team = Client::new("localhost", 0, "localhost", 0, Some("team")).await?;
+    let _team2 = Client::new("localhost", 0, "localhost", 0, Some("team")).await?;
+
+    let mut http = TcpStream::connect(metrics_addr).await?;
+    http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    http.read_to_string(&mut response).await?;
+    for (secret, count) in [("main", 1), ("team", 2)] {
+        let key_id = Authenticator::new(secret).key_id().to_string();
+        let line = format!("\nbore_authentications_total{{key=\"{key_id}\"}} {count}\n");
+        assert!(response.contains(&line), "missing {line:?}");
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn local_bind_addr() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server(None).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("127.0.0.1", local_port, "127.0.0.1", 0, None);
+    // Linux routes all of 127.0.0.0/8 to the loopback interface.
+    client.set_local_bind_addr("127.0.0.2:0".parse()?);
+    let addr: SocketAddr = ([127, 0, 0, 1], client.connect().await?).into();
+    tokio::spawn(client.listen());
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, peer) = listener.accept().await?;
+    assert_eq!(peer.ip(), IpAddr::from([127, 0, 0, 2]));
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_tunnels() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| server.set_max_tunnels(1)).await;
+
+    let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    let err = Client::new("localhost", 5000, "localhost", 0, None)
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err.downcast_ref(), Some(ClientError::AtCapacity)));
+
+    // Once the first tunnel closes, its slot is free for another client.
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    Client::new("localhost", 5000, "localhost", 0, None).await?;
 
+    Ok(())
+}
+
+#[tokio::test]
+async fn wait_for_local() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // Find a free port for the local service, which is not up yet.
+    let local_port = TcpListener::bind("localhost:0").await?.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_wait_for_local(Duration::from_millis(300));
+    let err = client.connect().await.unwrap_err();
+    assert!(err.to_string().contains("did not come up within 0.3s"));
+
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_wait_for_local(Duration::from_secs(5));
+    let service = tokio::spawn(async move {
+        time::sleep(Duration::from_millis(700)).await;
+        TcpListener::bind(("localhost", local_port)).await
+    });
+    let start = time::Instant::now();
+    client.connect().await?;
+    assert!(start.elapsed() >= Duration::from_millis(700));
+    service.await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_proxy() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // A proxy that only tunnels requests with the right credentials.
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy.local_addr()?;
+    let (tx, mut requests) = mpsc::unbounded_channel();
     tokio::spawn(async move {
-        let (mut stream, _) = listener.accept().await?;
-        let mut buf = [0u8; 11];
-        stream.read_exact(&mut buf).await?;
-        assert_eq!(&buf, b"hello world");
+        loop {
+            let (mut stream, _) = proxy.accept().await?;
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await?);
+                }
+                let head = String::from_utf8(head)?;
+                tx.send(head.lines().next().unwrap_or_default().to_string())?;
+                if !head.contains("\r\nProxy-Authorization: Basic dXNlcjpwQHNz\r\n") {
+                    stream
+                        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                        .await?;
+                    return anyhow::Ok(());
+                }
+                let mut target = TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?;
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+                anyhow::Ok(())
+            });
+        }
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    });
 
-        stream.write_all(b"I can send a message too!").await?;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_http_proxy(&format!("http://{proxy_addr}"))?;
+    let err = client.connect().await.unwrap_err();
+    assert!(format!("{err:#}").contains("proxy requires authentication"));
+
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_http_proxy(&format!("http://user:p%40ss@{proxy_addr}/"))?;
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    // Connections to accept forwarded traffic go through the proxy too.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
+
+    let expected = format!("CONNECT localhost:{CONTROL_PORT} HTTP/1.1");
+    for _ in 0..3 {
+        assert_eq!(requests.recv().await.unwrap(), expected);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socks5_proxy() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+    spawn_server(None).await;
+
+    // A proxy that requires a user and password, and records the hosts it connects to.
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy.local_addr()?;
+    let (tx, mut requests) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = proxy.accept().await?;
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0; 3];
+                stream.read_exact(&mut greeting).await?;
+                if greeting != [5, 1, 2] {
+                    stream.write_all(&[5, 0xff]).await?;
+                    return anyhow::Ok(());
+                }
+                stream.write_all(&[5, 2]).await?;
+                let mut auth = [0; 13];
+                stream.read_exact(&mut auth).await?;
+                assert_eq!(&auth, b"\x01\x04user\x06p@ss:1");
+                stream.write_all(&[1, 0]).await?;
+
+                let mut request = [0; 5];
+                stream.read_exact(&mut request).await?;
+                assert_eq!(request[..4], [5, 1, 0, 3]);
+                let mut host = vec![0; request[4].into()];
+                stream.read_exact(&mut host).await?;
+                let port = stream.read_u16().await?;
+                tx.send((String::from_utf8(host)?, port))?;
+                let mut target = TcpStream::connect(("127.0.0.1", CONTROL_PORT)).await?;
+                stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+                anyhow::Ok(())
+            });
+        }
+        #[allow(unreachable_code)]
         anyhow::Ok(())
     });
 
-    let mut stream = TcpStream::connect(addr).await?;
-    stream.write_all(b"hello world").await?;
+    let mut client = Client::configure("localhost", 5000, "localhost", 0, None);
+    client.set_socks5_proxy(&proxy_addr.to_string(), None)?;
+    let err = client.connect().await.unwrap_err();
+    assert!(format!("{err:#}").contains("proxy requires authentication"));
 
-    let mut buf = [0u8; 25];
-    stream.read_exact(&mut buf).await?;
-    assert_eq!(&buf, b"I can send a message too!");
+    let listener = TcpListener::bind("localhost:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let mut client = Client::configure("localhost", local_port, "localhost", 0, None);
+    client.set_socks5_proxy(&proxy_addr.to_string(), Some(("user", "p@ss:1")))?;
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
 
-    // Ensure that the client end of the stream is closed now.
-    assert_eq!(stream.read(&mut buf).await?, 0);
+    // Connections to accept forwarded traffic go through the proxy too.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hello");
 
-    // Also ensure that additional connections do not produce any data.
-    let mut stream = TcpStream::connect(addr).await?;
-    assert_eq!(stream.read(&mut buf).await?, 0);
+    for _ in 0..2 {
+        let expected = ("localhost".to_string(), CONTROL_PORT);
+        assert_eq!(requests.recv().await.unwrap(), expected);
+    }
 
     Ok(())
 }
 
-#[rstest]
-#[case(None, Some("my secret"))]
-#[case(Some("my secret"), None)]
 #[tokio::test]
-async fn mismatched_secret(
-    #[case] server_secret: Option<&str>,
-    #[case] client_secret: Option<&str>,
-) {
+async fn release_tunnel() -> Result<()> {
     let _guard = SERIAL_GUARD.lock().await;
 
-    spawn_server(server_secret).await;
-    assert!(spawn_client(client_secret).await.is_err());
+    let server = Server::new(1024..=65535, None);
+    let stats = server.stats_handle();
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let client = Client::new("localhost", 5000, "localhost", 0, None).await?;
+    let port = client.remote_port();
+    assert_eq!(stats.stats().listeners.len(), 1);
+
+    // The port is free as soon as the release is acknowledged.
+    client.release().await?;
+    assert!(stats.stats().listeners.is_empty());
+    TcpListener::bind(("0.0.0.0", port)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn secret_quota() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    let mut server = Server::new(1024..=65535, Some("limited"));
+    server.set_secret_quota(
+        "limited",
+        Quota {
+            max_tunnels: Some(1),
+            max_connections: Some(1),
+        },
+    );
+    tokio::spawn(server.listen());
+    time::sleep(Duration::from_millis(50)).await;
+
+    let (listener, addr) = spawn_client(Some("limited")).await?;
+    let err = Client::new("localhost", 5000, "localhost", 0, Some("limited"))
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ClientError::QuotaExceeded)
+    ));
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"hello").await?;
+    let (mut local, _) = listener.accept().await?;
+    let mut buf = [0u8; 5];
+    local.read_exact(&mut buf).await?;
+
+    // A second connection is over the quota and is closed right away.
+    let mut stream2 = TcpStream::connect(addr).await?;
+    let result = time::timeout(Duration::from_secs(3), stream2.read(&mut buf)).await?;
+    assert!(matches!(result, Ok(0) | Err(_)));
+
+    Ok(())
 }
 
 #[tokio::test]
@@ -118,10 +2897,195 @@ async fn very_long_frame() -> Result<()> {
     panic!("did not exit after a 1 MB frame");
 }
 
-#[test]
-#[should_panic]
-fn empty_port_range() {
+#[tokio::test]
+async fn empty_port_range() {
     let min_port = 5000;
     let max_port = 3000;
-    let _ = Server::new(min_port..=max_port, None);
+    let server = Server::new(min_port..=max_port, None);
+    let err = server.validate().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ServerError::EmptyPortRange)
+    ));
+    assert!(server.listen().await.is_err());
+
+    let mut server = Server::new(1024..=65535, None);
+    server.add_secret("tenant", min_port..=max_port);
+    let err = server.validate().unwrap_err();
+    assert_eq!(err.to_string(), "port range of a secret is empty");
+}
+
+#[test]
+fn try_new_empty_port_range() {
+    let (min_port, max_port) = (5000, 3000);
+    let result = Server::try_new(min_port..=max_port, None);
+    assert!(matches!(result, Err(ServerError::EmptyPortRange)));
+}
+
+#[test]
+fn validate_config() {
+    let mut server = Server::new(1024..=65535, None);
+    assert!(server.validate().is_ok());
+
+    server.set_bind_tunnels([192, 0, 2, 1].into());
+    let err = server.validate().unwrap_err();
+    assert_eq!(err.to_string(), "cannot bind tunnels to 192.0.2.1");
+
+    let mut server = Server::new(1024..=65535, None);
+    server.set_tunnel_rate_limit(0, 10);
+    let err = server.validate().unwrap_err();
+    assert_eq!(err.to_string(), "tunnel rate limit must be positive");
+
+    #[cfg(unix)]
+    {
+        let mut server = Server::new(1024..=65535, None);
+        server.set_control_socket("/nonexistent/bore.sock");
+        let err = server.validate().unwrap_err();
+        assert_eq!(err.to_string(), "directory /nonexistent does not exist");
+    }
+}
+
+#[test]
+fn secure_defaults() {
+    let mut server = Server::new(1024..=65535, None);
+    server.secure_defaults();
+    let err = server.validate().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "a secret is required, but none is configured"
+    );
+
+    let server = Server::builder(1024..=65535, Some("secret"))
+        .secure_defaults()
+        .build();
+    assert!(server.is_ok());
+}
+
+#[test]
+fn server_builder() {
+    let server = Server::builder(1024..=65535, None)
+        .max_tunnels(10)
+        .accept_timeout(Duration::from_secs(5))
+        .build();
+    assert!(server.is_ok());
+
+    let (min_port, max_port) = (5000, 3000);
+    let err = Server::builder(min_port..=max_port, None)
+        .build()
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(ServerError::EmptyPortRange)
+    ));
+
+    // The first error is reported, whether from a setter or from validation.
+    let err = Server::builder(1024..=65535, None)
+        .webhook("ftp://example.com")
+        .bind_tunnels([192, 0, 2, 1].into())
+        .build()
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("ftp://example.com"), "{err}");
+    let err = Server::builder(1024..=65535, None)
+        .bind_tunnels([192, 0, 2, 1].into())
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "cannot bind tunnels to 192.0.2.1");
+
+    // Invalid values are reported by validation when the server is built.
+    let builders = [
+        Server::builder(1024..=65535, None).add_secret("secret", min_port..=max_port),
+        Server::builder(1024..=65535, None).heartbeat_interval(Duration::ZERO),
+        Server::builder(1024..=65535, None).bandwidth_limit(0, 1024),
+        Server::builder(1024..=65535, None).preferred_port_range(min_port..=max_port),
+    ];
+    for builder in builders {
+        assert!(builder.build().is_err());
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn compressed_tunnel(#[values(true, false)] compression: bool) -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    // Without compression on the server, the client falls back to plain connections.
+    spawn_server_with(|server| server.set_compression(compression)).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let mut client = Client::configure(
+        "127.0.0.1",
+        listener.local_addr()?.port(),
+        "localhost",
+        0,
+        None,
+    );
+    client.set_compression(true);
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    let request = b"GET / HTTP/1.1\r\n".repeat(64 * 1024);
+    let response: Vec<u8> = (0..256 * 1024).map(|_| fastrand::u8(..)).collect();
+    let (mut remote, mut local) = connect_through(port, &listener).await?;
+    let serve = {
+        let (request, response) = (request.clone(), response.clone());
+        tokio::spawn(async move {
+            let mut received = Vec::new();
+            local.read_to_end(&mut received).await?;
+            assert_eq!(received, request);
+            local.write_all(&response).await?;
+            anyhow::Ok(())
+        })
+    };
+
+    remote.write_all(&request).await?;
+    remote.shutdown().await?;
+    let mut received = Vec::new();
+    time::timeout(Duration::from_secs(10), remote.read_to_end(&mut received)).await??;
+    assert_eq!(received, response);
+    serve.await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn compressed_proxy_protocol() -> Result<()> {
+    let _guard = SERIAL_GUARD.lock().await;
+
+    spawn_server_with(|server| {
+        server.set_compression(true);
+        server.set_proxy_protocol(ProxyProtocol::V1);
+    })
+    .await;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let mut client = Client::configure(
+        "127.0.0.1",
+        listener.local_addr()?.port(),
+        "localhost",
+        0,
+        None,
+    );
+    client.set_compression(true);
+    let port = client.connect().await?;
+    tokio::spawn(client.listen());
+
+    // The header and the first bytes are sent before the client gets any data.
+    let (mut remote, mut local) = connect_through(port, &listener).await?;
+    let peer = remote.local_addr()?;
+    remote.write_all(b"hello").await?;
+    let expected = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} {port}\r\nhello",
+        peer.port()
+    );
+    let mut buf = vec![0u8; expected.len()];
+    time::timeout(Duration::from_secs(3), local.read_exact(&mut buf)).await??;
+    assert_eq!(String::from_utf8(buf)?, expected);
+
+    local.write_all(b"world").await?;
+    let mut buf = [0u8; 5];
+    time::timeout(Duration::from_secs(3), remote.read_exact(&mut buf)).await??;
+    assert_eq!(&buf, b"world");
+
+    Ok(())
 }
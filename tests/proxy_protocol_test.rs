@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+
+use bore_cli::proxy_protocol::ProxyProtocol;
+
+#[test]
+fn v1_header() {
+    let source: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+    let destination: SocketAddr = "198.51.100.7:443".parse().unwrap();
+    assert_eq!(
+        ProxyProtocol::V1.header(source, destination),
+        b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n",
+    );
+
+    let source: SocketAddr = "[2001:db8::1]:1000".parse().unwrap();
+    let destination: SocketAddr = "[2001:db8::2]:2000".parse().unwrap();
+    assert_eq!(
+        ProxyProtocol::V1.header(source, destination),
+        b"PROXY TCP6 2001:db8::1 2001:db8::2 1000 2000\r\n",
+    );
+
+    let mapped: SocketAddr = "[::ffff:192.0.2.1]:56324".parse().unwrap();
+    let destination: SocketAddr = "198.51.100.7:443".parse().unwrap();
+    assert_eq!(
+        ProxyProtocol::V1.header(mapped, destination),
+        b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n",
+    );
+}
+
+#[test]
+fn v2_header() {
+    let source: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+    let destination: SocketAddr = "198.51.100.7:443".parse().unwrap();
+    let header = ProxyProtocol::V2.header(source, destination);
+    assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+    assert_eq!(&header[12..16], &[0x21, 0x11, 0, 12]);
+    assert_eq!(&header[16..20], &[192, 0, 2, 1]);
+    assert_eq!(&header[20..24], &[198, 51, 100, 7]);
+    assert_eq!(&header[24..], &[0xdc, 0x04, 0x01, 0xbb]);
+
+    let source: SocketAddr = "[2001:db8::1]:1000".parse().unwrap();
+    let header = ProxyProtocol::V2.header(source, destination);
+    assert_eq!(&header[12..], &[0x20, 0, 0, 0]);
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use bore_cli::shared::{set_stream_options, ClientMessage, Delimited, Keepalive};
+use socket2::SockRef;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[tokio::test]
+async fn frame_too_long() -> Result<()> {
+    let (mut client, server) = io::duplex(64);
+    let mut server = Delimited::with_max_length(server, 16);
+
+    let write = async {
+        client.write_all(&[b'a'; 100]).await?;
+        client.write_all(&[0]).await
+    };
+    let (_, result) = tokio::join!(write, server.recv::<ClientMessage>());
+    let err = result.unwrap_err();
+    assert_eq!(err.to_string(), "frame exceeds maximum length of 16 bytes");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn frame_within_limit() -> Result<()> {
+    let (client, server) = io::duplex(64);
+    let mut client = Delimited::new(client);
+    let mut server = Delimited::with_max_length(server, 16);
+
+    client.send(ClientMessage::Hello(80)).await?;
+    let msg: Option<ClientMessage> = server.recv().await?;
+    assert!(matches!(msg, Some(ClientMessage::Hello(80))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stream_options_are_applied() -> Result<()> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let stream = TcpStream::connect(listener.local_addr()?).await?;
+    let keepalive = Keepalive {
+        idle: Duration::from_secs(60),
+        interval: Some(Duration::from_secs(10)),
+        count: Some(3),
+    };
+    set_stream_options(&stream, true, Some(keepalive))?;
+
+    let socket = SockRef::from(&stream);
+    assert!(socket.nodelay()?);
+    assert!(socket.keepalive()?);
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(socket.keepalive_time()?, Duration::from_secs(60));
+        assert_eq!(socket.keepalive_interval()?, Duration::from_secs(10));
+        assert_eq!(socket.keepalive_retries()?, 3);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stream_options_default_to_unset() -> Result<()> {
+    let listener = TcpListener::bind("localhost:0").await?;
+    let stream = TcpStream::connect(listener.local_addr()?).await?;
+    set_stream_options(&stream, false, None)?;
+
+    let socket = SockRef::from(&stream);
+    assert!(!socket.nodelay()?);
+    assert!(!socket.keepalive()?);
+
+    Ok(())
+}